@@ -1,11 +1,16 @@
 use std::fs::File;
-use std::io::{Result, Write};
-use std::path::PathBuf;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
 use std::env;
 use sysnum::gen_syscalls;
 use std::process::Command;
 
-use cc;
+// the instruction decoder of the tracer, to check hook patterns.
+#[allow(dead_code)]
+#[path = "src/x86.rs"]
+mod x86;
+
+const SYSCALL_HOOKS_DEF: &str = "trampoline/syscall_hooks.def";
 
 fn gen_syscall_nrs(dest: PathBuf) -> Result<()> {
     let mut f = File::create(dest)?;
@@ -28,6 +33,7 @@ fn gen_syscall_nrs(dest: PathBuf) -> Result<()> {
     }
     writeln!(f, "}}")?;
 
+    writeln!(f, "pub const NR_SYSCALLS: usize = {};", syscalls.len())?;
     writeln!(f, "static SYSCALL_NAMES: [&str; {}] = [", syscalls.len())?;
     for (name, _) in &syscalls {
         writeln!(
@@ -63,13 +69,142 @@ fn gen_syscall_nrs(dest: PathBuf) -> Result<()> {
     writeln!(f, "    }}")?;
     writeln!(f, "}}")?;
 
+    writeln!(f, "impl std::str::FromStr for SyscallNo {{")?;
+    writeln!(f, "    type Err = String;")?;
+    writeln!(f, "    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {{")?;
+    writeln!(f, "        SYSCALL_NAMES.iter().position(|name| *name == s)")?;
+    writeln!(f, "            .map(|i| SYSCALL_IDS[i])")?;
+    writeln!(f, "            .ok_or(format!(\"unknown syscall: {{}}\", s))")?;
+    writeln!(f, "    }}")?;
+    writeln!(f, "}}")?;
+
+    Ok(())
+}
+
+// a pattern of `trampoline/syscall_hooks.def`
+struct HookPattern {
+    instructions: Vec<u8>,
+    is_multi: bool,
+    replay: Vec<String>,
+    comment: String,
+}
+
+impl HookPattern {
+    fn symbol(&self) -> String {
+        let bytes: Vec<String> = self.instructions.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("_syscall_hook_trampoline_{}", bytes.join("_"))
+    }
+}
+
+fn parse_hook_pattern(line: &str) -> std::result::Result<HookPattern, String> {
+    let fields: Vec<&str> = line.split('|').map(|field| field.trim()).collect();
+    if fields.len() != 3 {
+        return Err(String::from("expected `<bytes> | <replayed instructions> | <comment>`"));
+    }
+    let instructions = fields[0]
+        .split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16).map_err(|_| format!("invalid byte `{}`", b)))
+        .collect::<std::result::Result<Vec<u8>, String>>()?;
+    // a `callq` (5 bytes) replaces the `syscall` (2 bytes) and the
+    // pattern, the rest is padded with at most 9 bytes of nops.
+    if instructions.len() < 3 || instructions.len() > 12 {
+        return Err(format!("pattern must be 3..=12 bytes, got {}", instructions.len()));
+    }
+    let mut count = 0;
+    let mut k = 0;
+    while k < instructions.len() {
+        let insn = x86::decode(&instructions[k..])
+            .ok_or_else(|| format!("cannot decode whole instructions at byte {}", k))?;
+        match insn.kind {
+            x86::InsnKind::Normal | x86::InsnKind::Terminator if insn.rip_disp.is_none() => (),
+            _ => return Err(format!("instruction at byte {} cannot be replayed", k)),
+        }
+        k += insn.len;
+        count += 1;
+    }
+    let replay = fields[1]
+        .split(';')
+        .map(|insn| insn.trim())
+        .filter(|insn| !insn.is_empty())
+        .map(String::from)
+        .collect();
+    Ok(HookPattern {
+        instructions,
+        is_multi: count > 1,
+        replay,
+        comment: String::from(fields[2]),
+    })
+}
+
+fn read_hook_patterns(def: &Path) -> Result<Vec<HookPattern>> {
+    let mut patterns: Vec<HookPattern> = Vec::new();
+    for (k, line) in std::fs::read_to_string(def)?.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |e: String| Error::new(ErrorKind::Other, format!("{}:{}: {}", def.display(), k + 1, e));
+        let pattern = parse_hook_pattern(line).map_err(error)?;
+        if let Some(prev) = patterns.iter().find(|p| pattern.instructions.starts_with(&p.instructions)) {
+            return Err(error(format!("pattern is shadowed by {}", prev.symbol())));
+        }
+        patterns.push(pattern);
+    }
+    Ok(patterns)
+}
+
+// generate syscall hooks for the tracer (`syscall_hooks.rs`), `route.c`
+// (`syscall_hooks.h`) and `trampoline.S` (`syscall_hooks.inc`).
+fn gen_syscall_hooks(out_dir: &Path) -> Result<()> {
+    let patterns = read_hook_patterns(Path::new(SYSCALL_HOOKS_DEF))?;
+
+    let mut f = File::create(out_dir.join("syscall_hooks.rs"))?;
+    writeln!(f, "// generated by build.rs from {}, do not edit.", SYSCALL_HOOKS_DEF)?;
+    writeln!(f, "pub const SYSCALL_HOOKS: &[SyscallPatchHook] = &[")?;
+    for p in &patterns {
+        let bytes: Vec<String> = p.instructions.iter().map(|b| format!("0x{:02x}", b)).collect();
+        writeln!(f, "    // {}", p.comment)?;
+        writeln!(f, "    SyscallPatchHook {{")?;
+        writeln!(f, "        is_multi: {},", p.is_multi)?;
+        writeln!(f, "        instructions: &[{}],", bytes.join(", "))?;
+        writeln!(f, "        symbol: \"{}\",", p.symbol())?;
+        writeln!(f, "    }},")?;
+    }
+    writeln!(f, "];")?;
+
+    let mut f = File::create(out_dir.join("syscall_hooks.h"))?;
+    writeln!(f, "/* generated by build.rs from {}, do not edit. */", SYSCALL_HOOKS_DEF)?;
+    for p in &patterns {
+        writeln!(f, "extern __attribute__((visibility(\"hidden\"))) void {}(void);", p.symbol())?;
+    }
+    writeln!(f, "\nstatic struct syscall_patch_hook syscall_patch_hooks[] = {{")?;
+    for p in &patterns {
+        let bytes: Vec<String> = p.instructions.iter().map(|b| format!("0x{:02x}", b)).collect();
+        writeln!(f, "    /* {} */", p.comment)?;
+        writeln!(f, "    {{ {},", p.is_multi as u8)?;
+        writeln!(f, "      {},", p.instructions.len())?;
+        writeln!(f, "      {{ {} }},", bytes.join(", "))?;
+        writeln!(f, "      (uintptr_t){} }},", p.symbol())?;
+    }
+    writeln!(f, "}};")?;
+
+    let mut f = File::create(out_dir.join("syscall_hooks.inc"))?;
+    writeln!(f, "/* generated by build.rs from {}, do not edit. */", SYSCALL_HOOKS_DEF)?;
+    for p in &patterns {
+        writeln!(f, "\n/* {} */", p.comment)?;
+        writeln!(f, "SYSCALLHOOK_START({})", p.symbol())?;
+        writeln!(f, "        callq __morestack")?;
+        for insn in &p.replay {
+            writeln!(f, "        {}", insn)?;
+        }
+        writeln!(f, "SYSCALLHOOK_END({})", p.symbol())?;
+    }
     Ok(())
 }
 
 // build `libtrampoline.so`
 // not using GNU make because this is a lot easier
 // than figureing out how to use variable rules (LHS) in makefile
-fn build_trampoline(){
+fn build_trampoline(out_dir: &Path){
     let mut cc = Command::new(env::var("CC").unwrap_or(String::from("cc")));
     let srcs = &[ "trampoline.S", "raw_syscall.S", "route.c" ];
     let output = PathBuf::from("target")
@@ -82,16 +217,16 @@ fn build_trampoline(){
     cc.arg("-o").arg(output);
     cc.args(&[ "-g", "-Wall", "-fPIC", "-O2", "-D_POSIX_C_SOURCE=20180920",
                  "-D_GNU_SOURCE=1", "-Iinclude", "-Itrampoline" ]);
+    cc.arg("-I").arg(out_dir);
     cc.args(&[ "-nostdlib", "-shared", "-Wl,--no-as-needed"]);
     println!("[build.rs] invoking: {:?}", cc);
-    cc.status().expect("failed to build libtrampoline.so");
+    let status = cc.status().expect("failed to build libtrampoline.so");
+    assert!(status.success(), "failed to build libtrampoline.so");
 }
 
 fn main() {
-    build_trampoline();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    gen_syscall_hooks(&out_dir).unwrap();
+    build_trampoline(&out_dir);
     gen_syscall_nrs(PathBuf::from("src").join("nr.rs")).unwrap();
-    cc::Build::new()
-        .file("src/bpf.c")
-        .file("src/bpf-helper.c")
-        .compile("my-asm-lib");
 }
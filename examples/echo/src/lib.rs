@@ -1,37 +1,36 @@
 #![feature(format_args_nl)]
 
 use tools_helper::*;
-use syscalls::*;
 use log::*;
 
-#[allow(unused_imports)]
-use std::ffi::CStr;
+/// echo tool only observes syscalls: `on_syscall` is not overridden,
+/// so the syscall runs as is, then the result is reported in
+/// `on_syscall_exit`. `--tool-arg echo.errors-only=1` only reports
+/// syscalls which failed.
+struct Echo;
 
-#[link_section = ".init_array"]
-#[used]
-static ECHO_DSO_CTORS: extern fn() = {
-    extern "C" fn echo_ctor() {
-	let _ = logger::init();
-    };
-    echo_ctor
-};
-
-#[no_mangle]
-pub extern "C" fn captured_syscall(
-    _no: i32,
-    _a0: i64,
-    _a1: i64,
-    _a2: i64,
-    _a3: i64,
-    _a4: i64,
-    _a5: i64,
-) -> i64 {
-    note_syscall(_no, NoteInfo::SyscallEntry);
-    let ret = unsafe { untraced_syscall(_no, _a0, _a1, _a2, _a3, _a4, _a5) };
-    if ret as u64 >= -4096i64 as u64 {
-        warn!("{:?} = {}", syscalls::SyscallNo::from(_no), ret);
-    } else {
-        msg!("{:?} = {:x}", syscalls::SyscallNo::from(_no), ret);
+impl Tool for Echo {
+    fn on_syscall_exit(&self, ctx: &SyscallCtx, ret: i64) -> i64 {
+        note_syscall(ctx.no, NoteInfo::SyscallEntry);
+        if ret as u64 >= -4096i64 as u64 {
+            warn!("{:?} = {}", ctx.syscall_no(), ret);
+        } else if get_tool_arg("echo.errors-only") != Some("1") {
+            msg!("{:?} = {:x}", ctx.syscall_no(), ret);
+        }
+        ret
+    }
+    fn on_thread_start(&self) {
+        msg!("<thread started>");
+    }
+    fn on_fork_child(&self, parent: i32) {
+        msg!("<forked from {}>", parent);
+    }
+    fn on_exec(&self) {
+        msg!("<exec>");
+    }
+    fn on_exit(&self, status: i32) {
+        msg!("<exit {}>", status);
     }
-    ret
 }
+
+systrace_tool!(Echo);
@@ -0,0 +1,32 @@
+#![feature(format_args_nl)]
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+use tools_helper::*;
+use syscalls::SyscallNo;
+
+/// files tool reports files opened by the tracee. It declares interest in
+/// `open` and `openat` only, other syscalls are not traced at all.
+struct Files;
+
+impl Tool for Files {
+    fn on_syscall_exit(&self, ctx: &SyscallCtx, ret: i64) -> i64 {
+        let path = match ctx.syscall_no() {
+            SyscallNo::SYS_open => ctx.args[0],
+            SyscallNo::SYS_openat => ctx.args[1],
+            _ => return ret,
+        };
+        // the path of a failed open could be a bad pointer (`EFAULT`).
+        if ret < 0 {
+            msg!("open failed: {}", ret);
+            return ret;
+        }
+        let path = unsafe { CStr::from_ptr(path as *const c_char) };
+        msg!("{:?} = {}", path, ret);
+        ret
+    }
+}
+
+systrace_tool!(Files);
+systrace_interest!(SyscallNo::SYS_open, SyscallNo::SYS_openat);
@@ -6,10 +6,32 @@
 /* syscall dispatcher, weak symbol, so that others can overrides */
 extern long captured_syscall(int syscallno, long arg0, long arg1, long arg2, long arg3, long arg4, long arg5);
 
+/* chained syscall dispatcher, weak symbol, may rewrite `syscall`, returns
+ * 0 to pass it to the next tool, or non-zero to skip the syscall, with
+ * `*retval` as its result */
+extern int captured_syscall_enter(struct syscall_info* syscall, long* retval) __attribute__((weak));
+
+/* optional syscall exit callback, weak symbol, called with the result
+ * of `captured_syscall`, returns the value seen by the tracee */
+extern long captured_syscall_exit(int syscallno, const long* args, long retval) __attribute__((weak));
+
+/* lifecycle callbacks, optional, called by the tracer in tracee's context */
+extern void systrace_on_thread_start(void) __attribute__((weak));
+
+extern void systrace_on_fork_child(pid_t parent) __attribute__((weak));
+
+extern void systrace_on_exec(void) __attribute__((weak));
+
+extern void systrace_on_exit(int status) __attribute__((weak));
+
 /* initiate a syscall, traced by seccomp-bpf */
 extern long traced_syscall(int syscallno, long arg0, long arg1, long arg2, long arg3, long arg4, long arg5);
 /* initiate a syscall, untraced/allowed by seccomp-bpf */
 
 extern long untraced_syscall(int syscallno, long arg0, long arg1, long arg2, long arg3, long arg4, long arg5);
 
+/* run a syscall of the program on its behalf (i.e.: from `captured_syscall`),
+ * untraced, but subject to the policy rules, unlike `untraced_syscall` */
+extern long forwarded_syscall(int syscallno, long arg0, long arg1, long arg2, long arg3, long arg4, long arg5);
+
 #endif
@@ -0,0 +1,225 @@
+//! per process records of the tracer: the memory map, stub pages, patched
+//! and unpatchable syscall sites and the patch lock.
+//!
+//! threads of a process share the same `AddressSpace` (behind an `Rc`),
+//! while a forked process starts with a copy of its parent's. tables are
+//! ordered maps behind `Rc`s, copied on first write (`Rc::make_mut`), so
+//! that a fork does not copy anything until either side patches a site.
+//! patched sites and stub pages never overlap, hence are indexed by their
+//! start address, and found by address in O(log n).
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+use crate::consts::SYSCALL_INSN_SIZE;
+use crate::proc::*;
+use crate::remote::*;
+use crate::remote_rwlock::*;
+
+#[derive(Default)]
+pub struct AddressSpace {
+    /// sorted by address, as in `/proc/[pid]/maps`.
+    maps: Rc<Vec<ProcMapsEntry>>,
+    stub_pages: Rc<BTreeMap<u64, SyscallStubPage>>,
+    /// patched sites by `address`.
+    patched: Rc<BTreeMap<u64, PatchedSyscall>>,
+    /// `rip` of patched sites, to their `address`.
+    patched_rips: Rc<BTreeMap<u64, u64>>,
+    /// `rip` of sites which cannot be patched.
+    unpatchable: Rc<BTreeSet<u64>>,
+    /// not inherited by `fork`, threads of the parent are not in the child.
+    lock: RemoteRWLock,
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        AddressSpace::default()
+    }
+
+    /// the address space of a forked child, tables are shared until written.
+    pub fn fork(&self) -> Self {
+        AddressSpace {
+            maps: self.maps.clone(),
+            stub_pages: self.stub_pages.clone(),
+            patched: self.patched.clone(),
+            patched_rips: self.patched_rips.clone(),
+            unpatchable: self.unpatchable.clone(),
+            lock: RemoteRWLock::new(),
+        }
+    }
+
+    pub fn maps(&self) -> &[ProcMapsEntry] {
+        &self.maps
+    }
+
+    pub fn set_maps(&mut self, maps: Vec<ProcMapsEntry>) {
+        self.maps = Rc::new(maps);
+    }
+
+    /// the mapping `addr` is in, if known.
+    pub fn mapping_of(&self, addr: u64) -> Option<&ProcMapsEntry> {
+        let k = self.maps.partition_point(|e| e.base() <= addr);
+        self.maps.get(k.checked_sub(1)?).filter(|e| addr < e.end())
+    }
+
+    /// some known mapping within `start..end` is executable.
+    pub fn has_text_within(&self, start: u64, end: u64) -> bool {
+        let k = self.maps.partition_point(|e| e.end() <= start);
+        self.maps[k..]
+            .iter()
+            .take_while(|e| e.base() < end)
+            .any(|e| e.prot() & libc::PROT_EXEC != 0)
+    }
+
+    pub fn stub_pages(&self) -> impl Iterator<Item = &SyscallStubPage> {
+        self.stub_pages.values()
+    }
+
+    /// the stub page `addr` is in, if any.
+    pub fn stub_page_of(&self, addr: u64) -> Option<&SyscallStubPage> {
+        let (_, page) = self.stub_pages.range(..=addr).next_back()?;
+        Some(page).filter(|page| addr < page.address + page.size as u64)
+    }
+
+    /// the stub page starting @address, if any.
+    pub fn stub_page_mut(&mut self, address: u64) -> Option<&mut SyscallStubPage> {
+        Rc::make_mut(&mut self.stub_pages).get_mut(&address)
+    }
+
+    pub fn add_stub_page(&mut self, page: SyscallStubPage) {
+        Rc::make_mut(&mut self.stub_pages).insert(page.address, page);
+    }
+
+    pub fn clear_stub_pages(&mut self) {
+        self.stub_pages = Rc::new(BTreeMap::new());
+    }
+
+    pub fn patched_sites(&self) -> impl Iterator<Item = &PatchedSyscall> {
+        self.patched.values()
+    }
+
+    /// the site patched with `rip` right after its `syscall`, if any.
+    pub fn patched_at(&self, rip: u64) -> Option<&PatchedSyscall> {
+        self.patched_rips.get(&rip).and_then(|address| self.patched.get(address))
+    }
+
+    pub fn is_patched(&self, rip: u64) -> bool {
+        self.patched_rips.contains_key(&rip)
+    }
+
+    /// the patched site whose original bytes cover `addr`, if any.
+    pub fn patched_site_of(&self, addr: u64) -> Option<&PatchedSyscall> {
+        let (_, site) = self.patched.range(..=addr).next_back()?;
+        Some(site).filter(|site| addr < site.address + site.original_bytes.len() as u64)
+    }
+
+    pub fn add_patched(&mut self, site: PatchedSyscall) {
+        Rc::make_mut(&mut self.patched_rips).insert(site.rip, site.address);
+        Rc::make_mut(&mut self.patched).insert(site.address, site);
+    }
+
+    /// forget the site patched with `rip`, returns its record.
+    pub fn remove_patched(&mut self, rip: u64) -> Option<PatchedSyscall> {
+        let address = Rc::make_mut(&mut self.patched_rips).remove(&rip)?;
+        Rc::make_mut(&mut self.patched).remove(&address)
+    }
+
+    /// forget patched sites overlapping `start..end`, returns their records.
+    pub fn remove_patched_within(&mut self, start: u64, end: u64) -> Vec<PatchedSyscall> {
+        let sites: Vec<u64> = self
+            .patched
+            .range(..end)
+            .rev()
+            .take_while(|(_, site)| site.address + site.original_bytes.len() as u64 > start)
+            .map(|(_, site)| site.rip)
+            .collect();
+        sites.iter().filter_map(|rip| self.remove_patched(*rip)).collect()
+    }
+
+    pub fn clear_patched(&mut self) {
+        self.patched = Rc::new(BTreeMap::new());
+        self.patched_rips = Rc::new(BTreeMap::new());
+    }
+
+    pub fn is_unpatchable(&self, rip: u64) -> bool {
+        self.unpatchable.contains(&rip)
+    }
+
+    pub fn mark_unpatchable(&mut self, rip: u64) {
+        if !self.unpatchable.contains(&rip) {
+            Rc::make_mut(&mut self.unpatchable).insert(rip);
+        }
+    }
+
+    /// forget unpatchable sites whose `syscall` overlaps `start..end`,
+    /// returns how many.
+    pub fn forget_unpatchable_within(&mut self, start: u64, end: u64) -> usize {
+        let range = start.saturating_add(1)..end.saturating_add(SYSCALL_INSN_SIZE as u64);
+        let doomed: Vec<u64> = self.unpatchable.range(range).cloned().collect();
+        if !doomed.is_empty() {
+            let unpatchable = Rc::make_mut(&mut self.unpatchable);
+            doomed.iter().for_each(|rip| {
+                unpatchable.remove(rip);
+            });
+        }
+        doomed.len()
+    }
+
+    /// forget patched sites and stub pages overlapping `start..end`, as well
+    /// as unpatchable sites within, returns the number of patched and
+    /// unpatchable sites, and the stub pages forgotten.
+    pub fn invalidate_range(&mut self, start: u64, end: u64) -> (usize, usize, Vec<SyscallStubPage>) {
+        let patched = self.remove_patched_within(start, end).len();
+        let unpatchable = self.forget_unpatchable_within(start, end);
+        let pages: Vec<u64> = self
+            .stub_pages
+            .range(..end)
+            .rev()
+            .take_while(|(_, page)| page.address + page.size as u64 > start)
+            .map(|(address, _)| *address)
+            .collect();
+        let pages = if pages.is_empty() {
+            Vec::new()
+        } else {
+            let stub_pages = Rc::make_mut(&mut self.stub_pages);
+            pages.iter().filter_map(|address| stub_pages.remove(address)).collect()
+        };
+        (patched, unpatchable, pages)
+    }
+
+    /// lock of syscall sites, see `RemoteRWLock`.
+    pub fn patch_lock(&mut self) -> &mut RemoteRWLock {
+        &mut self.lock
+    }
+}
+
+#[test]
+fn address_space_sanity_check() {
+    let site = |address: u64, len: usize| PatchedSyscall {
+        rip: address + SYSCALL_INSN_SIZE as u64,
+        address,
+        original_bytes: vec![0x90; len],
+        resume_from: address,
+    };
+    let mut parent = AddressSpace::new();
+    parent.add_patched(site(0x1000, 8));
+    parent.add_patched(site(0x2000, 13));
+    parent.mark_unpatchable(0x3002);
+    parent.add_stub_page(SyscallStubPage { address: 0x10000, size: 0x2000, allocated: 0 });
+
+    let mut child = parent.fork();
+    assert!(Rc::ptr_eq(&parent.patched, &child.patched));
+    assert_eq!(child.patched_site_of(0x200c).map(|site| site.rip), Some(0x2002));
+    assert!(child.patched_site_of(0x200d).is_none());
+    assert_eq!(child.stub_page_of(0x11fff).map(|page| page.address), Some(0x10000));
+
+    let (patched, unpatchable, pages) = child.invalidate_range(0x1004, 0x3001);
+    assert_eq!((patched, unpatchable, pages.len()), (2, 1, 0));
+    assert_eq!(child.invalidate_range(0x11000, 0x12000).2.len(), 1);
+    assert!(!child.is_patched(0x1002) && !child.is_unpatchable(0x3002));
+    assert!(child.stub_page_of(0x10000).is_none());
+
+    assert!(parent.is_patched(0x1002) && parent.is_patched(0x2002));
+    assert!(parent.is_unpatchable(0x3002));
+    assert!(parent.stub_page_of(0x10000).is_some());
+}
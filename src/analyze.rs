@@ -0,0 +1,328 @@
+//! offline syscall site analysis (`systrace analyze`)
+//!
+//! a program and the libraries it needs (its interpreter and `DT_NEEDED`,
+//! recursively) are read from disk, `syscall` instructions are found by
+//! decoding the executable sections, function by function when there are
+//! symbols, then each site is classified as the tracer would do when it
+//! traps: patched with a hook, patched by relocating instructions, or
+//! left to ptrace. libraries are searched in `DT_RPATH`, `LD_LIBRARY_PATH`,
+//! `DT_RUNPATH` and the default directories, `ld.so.cache` is not read.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+use goblin::elf::dynamic::{DT_RPATH, DT_RUNPATH};
+use goblin::elf::section_header::SHT_NOBITS;
+use goblin::elf::Elf;
+
+use crate::consts::SYSCALL_INSN_SIZE;
+use crate::hooks::SYSCALL_HOOKS;
+use crate::symbols::{self, Function};
+use crate::traced_task::check_function_patch_window;
+use crate::x86;
+
+const DEFAULT_LIBRARY_PATHS: &[&str] = &[
+    "/lib/x86_64-linux-gnu",
+    "/usr/lib/x86_64-linux-gnu",
+    "/lib64",
+    "/usr/lib64",
+    "/lib",
+    "/usr/lib",
+];
+
+/// how a syscall site would be handled by the tracer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SiteStatus {
+    /// patched with the hook of the given symbol.
+    Hook(&'static str),
+    /// no hook matches, patched by relocating instructions.
+    Relocated,
+    /// cannot be patched, always ptraced, for the given reason.
+    Ptraced(String),
+}
+
+/// a syscall site, at its link time address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Site {
+    pub address: u64,
+    /// enclosing function and offset, if known.
+    pub function: Option<(String, u64)>,
+    pub status: SiteStatus,
+}
+
+/// syscall sites of an ELF file, `path` is `None` if it is not found.
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    pub name: String,
+    pub path: Option<PathBuf>,
+    pub sites: Vec<Site>,
+}
+
+// `program` as a path, or searched in `PATH` like `execvp`.
+fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Some(PathBuf::from(program));
+    }
+    std::env::var("PATH")
+        .unwrap_or_default()
+        .split(':')
+        .map(|dir| Path::new(dir).join(program))
+        .find(|path| path.is_file())
+}
+
+// `DT_RPATH` or `DT_RUNPATH` of `elf`, with `$ORIGIN` expanded.
+fn dynamic_paths(elf: &Elf, tag: u64, origin: &Path) -> Vec<PathBuf> {
+    let dynamic = match &elf.dynamic {
+        Some(dynamic) => dynamic,
+        None => return Vec::new(),
+    };
+    dynamic
+        .dyns
+        .iter()
+        .filter(|d| d.d_tag == tag)
+        .filter_map(|d| elf.dynstrtab.get(d.d_val as usize).and_then(|s| s.ok()))
+        .flat_map(|paths| paths.split(':'))
+        .map(|path| {
+            let origin = origin.to_string_lossy();
+            PathBuf::from(path.replace("${ORIGIN}", &origin).replace("$ORIGIN", &origin))
+        })
+        .collect()
+}
+
+// directories to search the `DT_NEEDED` of `elf` (at `path`) from.
+fn library_search_paths(elf: &Elf, path: &Path) -> Vec<PathBuf> {
+    let origin = path.parent().unwrap_or_else(|| Path::new("."));
+    let runpath = dynamic_paths(elf, DT_RUNPATH, origin);
+    let mut res = Vec::new();
+    if runpath.is_empty() {
+        res.extend(dynamic_paths(elf, DT_RPATH, origin));
+    }
+    if let Some(paths) = std::env::var_os("LD_LIBRARY_PATH") {
+        res.extend(std::env::split_paths(&paths).filter(|p| !p.as_os_str().is_empty()));
+    }
+    res.extend(runpath);
+    res.extend(DEFAULT_LIBRARY_PATHS.iter().map(PathBuf::from));
+    res
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// analyze `program` and the libraries it needs, the program itself is
+/// reported first, then libraries in load order.
+pub fn analyze(program: &str) -> Result<Vec<FileReport>> {
+    let path = find_program(program)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("cannot find program {}", program)))?;
+    let mut res: Vec<FileReport> = Vec::new();
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    let mut queue: Vec<(String, Option<PathBuf>)> = vec![(String::from(program), Some(path))];
+    let mut k = 0;
+    while k < queue.len() {
+        let (name, path) = queue[k].clone();
+        k += 1;
+        let path = match path.and_then(|p| p.canonicalize().ok()) {
+            Some(path) => path,
+            None => {
+                res.push(FileReport { name, path: None, sites: Vec::new() });
+                continue;
+            }
+        };
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let bytes = read_file(&path)?;
+        let elf = Elf::parse(&bytes)
+            .map_err(|e| Error::new(ErrorKind::Other, format!("{}: {}", path.display(), e)))?;
+        if let Some(interp) = elf.interpreter {
+            queue.push((String::from(interp), Some(PathBuf::from(interp))));
+        }
+        let search_paths = library_search_paths(&elf, &path);
+        for lib in &elf.libraries {
+            let found = search_paths.iter().map(|dir| dir.join(lib)).find(|p| p.is_file());
+            queue.push((String::from(*lib), found));
+        }
+        let sites = syscall_sites(&path, &bytes, &elf);
+        res.push(FileReport { name, path: Some(path), sites });
+    }
+    Ok(res)
+}
+
+// offsets of `syscall` instructions in `code`, decoded linearly; unlike
+// `x86::syscall_sites`, undecodable bytes are skipped.
+fn sweep_syscall_sites(code: &[u8]) -> Vec<usize> {
+    let mut res = Vec::new();
+    let mut k = 0;
+    while k < code.len() {
+        match x86::decode(&code[k..]) {
+            Some(insn) => {
+                if insn.kind == x86::InsnKind::Syscall {
+                    res.push(k);
+                }
+                k += insn.len;
+            }
+            None => k += 1,
+        }
+    }
+    res
+}
+
+fn syscall_sites(path: &Path, bytes: &[u8], elf: &Elf) -> Vec<Site> {
+    let functions = symbols::elf_functions(path).unwrap_or_default();
+    let mut res = Vec::new();
+    for sh in &elf.section_headers {
+        if !sh.is_executable() || sh.sh_type == SHT_NOBITS {
+            continue;
+        }
+        let code = match bytes.get(sh.file_range()) {
+            Some(code) => code,
+            None => continue,
+        };
+        let base = sh.sh_addr;
+        let end = base + code.len() as u64;
+        // functions, and the gaps in between, are decoded separately.
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        let mut at = base;
+        for f in functions.iter().filter(|f| f.start >= base && f.end <= end) {
+            if f.start > at {
+                ranges.push((at, f.start));
+            }
+            if f.end > at {
+                ranges.push((at.max(f.start), f.end));
+                at = f.end;
+            }
+        }
+        if end > at {
+            ranges.push((at, end));
+        }
+        for (start, stop) in ranges {
+            let range = (start - base) as usize..(stop - base) as usize;
+            for k in sweep_syscall_sites(&code[range]) {
+                let address = start + k as u64;
+                let function = enclosing_function(&functions, address);
+                let status = classify_site(code, base, address, function);
+                res.push(Site {
+                    address,
+                    function: function.map(|f| (f.name.clone(), address - f.start)),
+                    status,
+                });
+            }
+        }
+    }
+    res
+}
+
+fn enclosing_function(functions: &[Function], address: u64) -> Option<&Function> {
+    let k = functions.partition_point(|f| f.start <= address);
+    functions.get(k.checked_sub(1)?).filter(|f| address < f.end)
+}
+
+// classify the `syscall` at `address`, like `patch_syscall_with` and
+// `patch_syscall_relocated` do.
+fn classify_site(code: &[u8], base: u64, address: u64, function: Option<&Function>) -> SiteStatus {
+    let check_window = |start: u64, end: u64| -> Result<()> {
+        match function {
+            Some(f) => check_function_patch_window(f, start, end, |size| {
+                let k = f.start.wrapping_sub(base) as usize;
+                code.get(k..k.saturating_add(size))
+                    .map(|code| code.to_vec())
+                    .ok_or_else(|| Error::new(ErrorKind::Other, format!("function {} spans sections", f.name)))
+            }),
+            None => Err(Error::new(
+                ErrorKind::Other,
+                format!("no known function encloses {:x}..{:x}", start, end),
+            )),
+        }
+    };
+    let at = (address - base) as usize + SYSCALL_INSN_SIZE;
+    let next = &code[at..code.len().min(at + 2 * std::mem::size_of::<u64>())];
+    if let Some(hook) = SYSCALL_HOOKS.iter().find(|hook| next.starts_with(hook.instructions)) {
+        let end = (at + hook.instructions.len()) as u64 + base;
+        return match check_window(address, end) {
+            Err(e) if hook.is_multi => SiteStatus::Ptraced(e.to_string()),
+            _ => SiteStatus::Hook(hook.symbol),
+        };
+    }
+    match x86::cover_syscall_site(code, base, address) {
+        None => SiteStatus::Ptraced(String::from("no hook matches, cannot be relocated")),
+        Some(site) => match check_window(site.address, site.address + site.len as u64) {
+            Err(e) => SiteStatus::Ptraced(e.to_string()),
+            Ok(()) => SiteStatus::Relocated,
+        },
+    }
+}
+
+/// (hooked, relocated, ptraced) sites of `sites`.
+pub fn site_counts(sites: &[Site]) -> (usize, usize, usize) {
+    sites.iter().fold((0, 0, 0), |(hooked, relocated, ptraced), site| match site.status {
+        SiteStatus::Hook(_) => (hooked + 1, relocated, ptraced),
+        SiteStatus::Relocated => (hooked, relocated + 1, ptraced),
+        SiteStatus::Ptraced(_) => (hooked, relocated, ptraced + 1),
+    })
+}
+
+/// print `reports`, one section per file, then the totals.
+pub fn print_reports(reports: &[FileReport], out: &mut dyn Write) -> Result<()> {
+    let mut all: Vec<Site> = Vec::new();
+    for report in reports {
+        let path = match &report.path {
+            Some(path) => path,
+            None => {
+                writeln!(out, "{}: not found\n", report.name)?;
+                continue;
+            }
+        };
+        let (hooked, relocated, ptraced) = site_counts(&report.sites);
+        writeln!(
+            out,
+            "{}: {} syscall sites, {} hooked, {} relocated, {} ptraced",
+            path.display(),
+            report.sites.len(),
+            hooked,
+            relocated,
+            ptraced
+        )?;
+        for site in &report.sites {
+            let function = match &site.function {
+                Some((name, offset)) => format!("{}+{:#x}", name, offset),
+                None => String::from("?"),
+            };
+            let status = match &site.status {
+                SiteStatus::Hook(symbol) => format!("hook {}", symbol),
+                SiteStatus::Relocated => String::from("relocated"),
+                SiteStatus::Ptraced(reason) => format!("ptraced: {}", reason),
+            };
+            writeln!(out, "  {:>#10x}  {:<40} {}", site.address, function, status)?;
+        }
+        writeln!(out)?;
+        all.extend(report.sites.iter().cloned());
+    }
+    let (hooked, relocated, ptraced) = site_counts(&all);
+    writeln!(
+        out,
+        "total: {} syscall sites, {} hooked, {} relocated, {} ptraced",
+        all.len(),
+        hooked,
+        relocated,
+        ptraced
+    )
+}
+
+#[test]
+fn can_analyze_syscall_sites() -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let reports = analyze(exe.to_str().unwrap())?;
+    assert_eq!(reports[0].path, Some(exe.canonicalize()?));
+    let libc = reports
+        .iter()
+        .find(|report| report.name.starts_with("libc.so"))
+        .unwrap();
+    let (hooked, _, _) = site_counts(&libc.sites);
+    assert!(hooked > 0);
+    assert!(libc.sites.iter().any(|site| site.function.is_some()));
+    Ok(())
+}
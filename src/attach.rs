@@ -0,0 +1,230 @@
+//! attach to running processes (`--attach`)
+//!
+//! every thread of the target process (and of its descendants) is seized
+//! with `PTRACE_SEIZE`, then stopped with `PTRACE_INTERRUPT`. each process
+//! is then prepared by `attach_preinit`, libtrampoline and the tools are
+//! loaded with `dlopen`, and the seccomp filter is installed with
+//! `SECCOMP_FILTER_FLAG_TSYNC`.
+//!
+//! NB: `dlopen` is called while all threads are stopped, this could
+//! deadlock if a stopped thread holds the loader (or malloc) lock.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::PathBuf;
+
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::Elf;
+use log::{debug, info, warn};
+use nix::sys::wait::{self, WaitStatus};
+use nix::sys::{ptrace, signal};
+use nix::unistd::Pid;
+
+use crate::proc::*;
+use crate::task::Task;
+use crate::traced_task::*;
+
+// symbols which can be used as `dlopen(path, flags)`, by preference.
+const DLOPEN_SYMBOLS: &[&str] = &["dlopen", "__libc_dlopen_mode"];
+
+fn from_nix_error(err: nix::Error) -> Error {
+    Error::new(ErrorKind::Other, err)
+}
+
+/// threads of process `pid`.
+pub fn process_threads(pid: Pid) -> Result<Vec<Pid>> {
+    let mut res = Vec::new();
+    for entry in std::fs::read_dir(format!("/proc/{}/task", pid))? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|s| s.parse::<i32>().ok()) {
+            res.push(Pid::from_raw(tid));
+        }
+    }
+    res.sort_by_key(|tid| tid.as_raw());
+    Ok(res)
+}
+
+/// process `pid` followed by its descendants, parents first.
+pub fn process_tree(pid: Pid) -> Vec<Pid> {
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for p in procfs::all_processes() {
+        children.entry(p.stat.ppid).or_default().push(p.stat.pid);
+    }
+    let mut res = vec![pid];
+    let mut k = 0;
+    while k < res.len() {
+        if let Some(v) = children.get(&res[k].as_raw()) {
+            res.extend(v.iter().map(|p| Pid::from_raw(*p)));
+        }
+        k += 1;
+    }
+    res
+}
+
+/// runtime address of `dlopen` in process `pid`, looked up in the
+/// dynamic symbol table of libc (or libdl).
+pub fn resolve_dlopen(pid: Pid) -> Result<u64> {
+    let maps = decode_proc_maps(pid)?;
+    for e in maps.iter().filter(|e| e.offset() == 0) {
+        let path = match e.filename() {
+            Some(path) => path,
+            None => continue,
+        };
+        let name = path.file_name().and_then(|s| s.to_str()).unwrap_or("");
+        if !(name.starts_with("libc.so") || name.starts_with("libc-") || name.starts_with("libdl")) {
+            continue;
+        }
+        let mut bytes: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        let elf = Elf::parse(bytes.as_slice()).map_err(|e| Error::new(ErrorKind::Other, e))?;
+        let load_vaddr = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .map(|ph| ph.p_vaddr & !0xfff)
+            .min()
+            .unwrap_or(0);
+        for symbol in DLOPEN_SYMBOLS {
+            let found = elf.dynsyms.iter().find(|sym| {
+                sym.st_value != 0 && sym.st_shndx != 0 && &elf.dynstrtab[sym.st_name] == *symbol
+            });
+            if let Some(sym) = found {
+                debug!("[attach] {} {} @{:?}+{:x}", pid, symbol, path, sym.st_value);
+                return Ok(e.base() - load_vaddr + sym.st_value);
+            }
+        }
+    }
+    Err(Error::new(ErrorKind::Other, format!("{}: cannot find dlopen", pid)))
+}
+
+fn seize(tid: Pid, options: ptrace::Options) -> Result<()> {
+    let ret = unsafe {
+        libc::ptrace(libc::PTRACE_SEIZE, tid.as_raw(), 0, options.bits() as libc::c_long)
+    };
+    if ret != 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn interrupt(tid: Pid) -> Result<()> {
+    let ret = unsafe { libc::ptrace(libc::PTRACE_INTERRUPT, tid.as_raw(), 0, 0) };
+    if ret != 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// wait until `tid` is in `PTRACE_EVENT_STOP`, returns the signal received
+// meanwhile (if any), which is to be delivered once the task resumes.
+fn wait_interrupted(tid: Pid) -> Result<Option<signal::Signal>> {
+    let mut pending = None;
+    loop {
+        match wait::waitpid(tid, Some(wait::WaitPidFlag::__WALL)).map_err(from_nix_error)? {
+            WaitStatus::PtraceEvent(_, _, event) if event as i64 == PTRACE_EVENT_STOP => {
+                return Ok(pending);
+            }
+            // i.e.: `PTRACE_EVENT_CLONE`, the new thread is seized already.
+            WaitStatus::PtraceEvent(_, _, _) => ptrace::cont(tid, None).map_err(from_nix_error)?,
+            WaitStatus::Stopped(_, sig) => {
+                pending = Some(sig);
+                ptrace::cont(tid, None).map_err(from_nix_error)?;
+            }
+            otherwise => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("{}: unexpected status {:?} while attaching", tid, otherwise),
+                ));
+            }
+        }
+    }
+}
+
+// seize and stop all threads of `pid`, until no new thread shows up.
+fn seize_threads(pid: Pid, options: ptrace::Options) -> Result<Vec<(Pid, Option<signal::Signal>)>> {
+    let mut seized: HashSet<Pid> = HashSet::new();
+    let mut res = Vec::new();
+    loop {
+        let threads: Vec<Pid> = process_threads(pid)?
+            .into_iter()
+            .filter(|tid| !seized.contains(tid))
+            .collect();
+        if threads.is_empty() {
+            break;
+        }
+        for tid in threads {
+            // threads cloned after `seize` are auto-attached, `EPERM`.
+            if let Err(e) = seize(tid, options) {
+                if e.raw_os_error() != Some(libc::EPERM) || seized.is_empty() {
+                    return Err(Error::new(e.kind(), format!("cannot seize {}: {}", tid, e)));
+                }
+            }
+            seized.insert(tid);
+            interrupt(tid)?;
+            let sig = wait_interrupted(tid)?;
+            res.push((tid, sig));
+        }
+    }
+    Ok(res)
+}
+
+/// attach to process `pid` and its descendants, returns tasks of all
+/// threads, ready to be scheduled.
+pub fn attach(pid: Pid, libs: &[PathBuf], prog: &[libc::sock_filter]) -> Result<Vec<TracedTask>> {
+    // NB: no `PTRACE_O_EXITKILL`, attached processes should outlive
+    // the tracer.
+    let options = ptrace::Options::PTRACE_O_TRACEEXEC
+        | ptrace::Options::PTRACE_O_TRACECLONE
+        | ptrace::Options::PTRACE_O_TRACEFORK
+        | ptrace::Options::PTRACE_O_TRACEVFORK
+        | ptrace::Options::PTRACE_O_TRACEVFORKDONE
+        | ptrace::Options::PTRACE_O_TRACEEXIT
+        | ptrace::Options::PTRACE_O_TRACESECCOMP
+        | ptrace::Options::PTRACE_O_TRACESYSGOOD;
+    let mut res = Vec::new();
+    for pid in process_tree(pid) {
+        let threads = match seize_threads(pid, options) {
+            Ok(threads) => threads,
+            // a descendant could be gone already, the root process cannot.
+            Err(e) if !res.is_empty() => {
+                warn!("[attach] skipping {}: {}", pid, e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        info!("[attach] {} attached, threads: {:?}", pid, threads.iter().map(|t| t.0).collect::<Vec<_>>());
+        let mut main: TracedTask = Task::new(pid);
+        let prepared = resolve_dlopen(pid).and_then(|dlopen| attach_preinit(&mut main, dlopen, libs, prog));
+        match prepared {
+            Ok(_) => (),
+            // like above, the descendant is left alone (hopefully untouched).
+            Err(e) if !res.is_empty() => {
+                warn!("[attach] skipping {}: {}", pid, e);
+                for (tid, sig) in threads {
+                    if let Err(e) = ptrace::detach(tid) {
+                        warn!("[attach] {} failed to detach: {}", tid, e);
+                    } else if let Some(sig) = sig {
+                        // pending once detached.
+                        unsafe { libc::syscall(libc::SYS_tgkill, pid.as_raw(), tid.as_raw(), sig as libc::c_int) };
+                    }
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        res.append(&mut main.spawned);
+        let mut tasks: Vec<TracedTask> = Vec::new();
+        for (tid, sig) in threads {
+            let mut task = main.thread(tid);
+            task.signal_to_deliver = sig;
+            tasks.push(task);
+        }
+        for task in tasks {
+            ptrace::cont(task.gettid(), task.signal_to_deliver).map_err(from_nix_error)?;
+            res.push(task);
+        }
+    }
+    Ok(res)
+}
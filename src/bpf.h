@@ -1,6 +0,0 @@
-#ifndef _MY_BPF_H
-#define _MY_BPF_H
-
-extern void bpf_patch_syscall(int syscall);
-
-#endif
@@ -0,0 +1,793 @@
+//! seccomp-bpf program builder
+//!
+//! a `SeccompFilter` is an ordered list of rules, the first matching rule
+//! decides the action, `default_action` is used when no rule matches. a
+//! rule matches either a syscall (range) with optional argument
+//! comparisons, or the instruction pointer of the syscall.
+//!
+//! rules can also be parsed from text, one rule per line (the format used
+//! by `--seccomp-rule` and `--seccomp-policy`):
+//!
+//! ```text
+//! # comment
+//! default trace
+//! allow rt_sigreturn
+//! errno(1) ptrace
+//! kill reboot
+//! log openat(arg2 & 0x3 == 0x1)
+//! allow ioctl(arg0 == 1, arg1 == 0x5401)
+//! allow ip(0x70000002)
+//! ```
+//!
+//! NB: the program does not check `seccomp_data.arch`, same as before.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::Path;
+
+use crate::consts;
+use crate::nr::*;
+use crate::options::get_systrace_options;
+
+const BPF_LD: u16 = 0x00;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_W: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_AND: u16 = 0x50;
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_K: u16 = 0x00;
+
+const BPF_MAXINSNS: usize = 4096;
+
+// offsets in `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_IP: u32 = 8;
+const SECCOMP_DATA_ARGS: u32 = 16;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_DATA: u32 = 0x0000_ffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Trace,
+    Errno(u16),
+    Kill,
+    Log,
+}
+
+impl Action {
+    pub fn to_seccomp_ret(&self) -> u32 {
+        match self {
+            Action::Allow => SECCOMP_RET_ALLOW,
+            Action::Trace => SECCOMP_RET_TRACE,
+            Action::Errno(errno) => SECCOMP_RET_ERRNO | (*errno as u32 & SECCOMP_RET_DATA),
+            Action::Kill => SECCOMP_RET_KILL_PROCESS,
+            Action::Log => SECCOMP_RET_LOG,
+        }
+    }
+    pub fn from_seccomp_ret(ret: u32) -> Option<Self> {
+        match ret & !SECCOMP_RET_DATA {
+            SECCOMP_RET_ALLOW => Some(Action::Allow),
+            SECCOMP_RET_TRACE => Some(Action::Trace),
+            SECCOMP_RET_ERRNO => Some(Action::Errno((ret & SECCOMP_RET_DATA) as u16)),
+            SECCOMP_RET_KILL_PROCESS => Some(Action::Kill),
+            SECCOMP_RET_LOG => Some(Action::Log),
+            _ => None,
+        }
+    }
+}
+
+/// comparison of a (64-bit) syscall argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cmp {
+    Eq(u64),
+    Ne(u64),
+    Lt(u64),
+    Le(u64),
+    Gt(u64),
+    Ge(u64),
+    /// `arg & mask == value`
+    MaskedEq(u64, u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgCmp {
+    pub arg: usize,
+    pub cmp: Cmp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Match {
+    /// syscalls within `[lo, hi]`, all argument comparisons must hold.
+    Syscalls(u32, u32, Vec<ArgCmp>),
+    /// syscall from the given instruction pointer (after `syscall`).
+    InstructionPointer(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub matcher: Match,
+    pub action: Action,
+}
+
+impl Rule {
+    pub fn syscall(nr: SyscallNo, action: Action) -> Self {
+        Rule {
+            matcher: Match::Syscalls(nr as u32, nr as u32, Vec::new()),
+            action,
+        }
+    }
+    pub fn syscall_with_args(nr: SyscallNo, args: Vec<ArgCmp>, action: Action) -> Self {
+        Rule {
+            matcher: Match::Syscalls(nr as u32, nr as u32, args),
+            action,
+        }
+    }
+    pub fn syscall_range(lo: u32, hi: u32, action: Action) -> Self {
+        Rule {
+            matcher: Match::Syscalls(lo, hi, Vec::new()),
+            action,
+        }
+    }
+    pub fn instruction_pointer(ip: u64, action: Action) -> Self {
+        Rule {
+            matcher: Match::InstructionPointer(ip),
+            action,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeccompFilter {
+    pub rules: Vec<Rule>,
+    pub default_action: Action,
+}
+
+// jump target while generating a rule
+#[derive(Debug, Clone, Copy)]
+enum Jmp {
+    // next instruction
+    Next,
+    // skip the given number of instructions
+    Skip(u8),
+    // rule does not match, jump to the next rule
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Insn {
+    code: u16,
+    jt: Jmp,
+    jf: Jmp,
+    k: u32,
+}
+
+fn stmt(code: u16, k: u32) -> Insn {
+    Insn { code, jt: Jmp::Next, jf: Jmp::Next, k }
+}
+
+fn jump(code: u16, k: u32, jt: Jmp, jf: Jmp) -> Insn {
+    Insn { code: BPF_JMP | code | BPF_K, jt, jf, k }
+}
+
+fn load(offset: u32) -> Insn {
+    stmt(BPF_LD | BPF_W | BPF_ABS, offset)
+}
+
+fn lo32(x: u64) -> u32 {
+    x as u32
+}
+
+fn hi32(x: u64) -> u32 {
+    (x >> 32) as u32
+}
+
+// compare 64-bit value at `offset` (little endian), falls through if
+// the comparison holds, jumps to `Fail` otherwise.
+fn compile_cmp(offset: u32, cmp: Cmp) -> Vec<Insn> {
+    let (lo, hi) = (offset, offset + 4);
+    match cmp {
+        Cmp::Eq(v) => vec![
+            load(hi),
+            jump(BPF_JEQ, hi32(v), Jmp::Next, Jmp::Fail),
+            load(lo),
+            jump(BPF_JEQ, lo32(v), Jmp::Next, Jmp::Fail),
+        ],
+        Cmp::Ne(v) => vec![
+            load(hi),
+            jump(BPF_JEQ, hi32(v), Jmp::Next, Jmp::Skip(2)),
+            load(lo),
+            jump(BPF_JEQ, lo32(v), Jmp::Fail, Jmp::Next),
+        ],
+        Cmp::Gt(v) => vec![
+            load(hi),
+            jump(BPF_JGT, hi32(v), Jmp::Skip(3), Jmp::Next),
+            jump(BPF_JEQ, hi32(v), Jmp::Next, Jmp::Fail),
+            load(lo),
+            jump(BPF_JGT, lo32(v), Jmp::Next, Jmp::Fail),
+        ],
+        Cmp::Ge(v) => vec![
+            load(hi),
+            jump(BPF_JGT, hi32(v), Jmp::Skip(3), Jmp::Next),
+            jump(BPF_JEQ, hi32(v), Jmp::Next, Jmp::Fail),
+            load(lo),
+            jump(BPF_JGE, lo32(v), Jmp::Next, Jmp::Fail),
+        ],
+        Cmp::Lt(v) => vec![
+            load(hi),
+            jump(BPF_JGT, hi32(v), Jmp::Fail, Jmp::Next),
+            jump(BPF_JEQ, hi32(v), Jmp::Next, Jmp::Skip(2)),
+            load(lo),
+            jump(BPF_JGE, lo32(v), Jmp::Fail, Jmp::Next),
+        ],
+        Cmp::Le(v) => vec![
+            load(hi),
+            jump(BPF_JGT, hi32(v), Jmp::Fail, Jmp::Next),
+            jump(BPF_JEQ, hi32(v), Jmp::Next, Jmp::Skip(2)),
+            load(lo),
+            jump(BPF_JGT, lo32(v), Jmp::Fail, Jmp::Next),
+        ],
+        Cmp::MaskedEq(mask, v) => vec![
+            load(hi),
+            stmt(BPF_ALU | BPF_AND | BPF_K, hi32(mask)),
+            jump(BPF_JEQ, hi32(v), Jmp::Next, Jmp::Fail),
+            load(lo),
+            stmt(BPF_ALU | BPF_AND | BPF_K, lo32(mask)),
+            jump(BPF_JEQ, lo32(v), Jmp::Next, Jmp::Fail),
+        ],
+    }
+}
+
+fn compile_rule(rule: &Rule) -> Result<Vec<libc::sock_filter>> {
+    let mut insns: Vec<Insn> = Vec::new();
+    match &rule.matcher {
+        Match::Syscalls(lo, hi, args) => {
+            insns.push(load(SECCOMP_DATA_NR));
+            if lo == hi {
+                insns.push(jump(BPF_JEQ, *lo, Jmp::Next, Jmp::Fail));
+            } else {
+                insns.push(jump(BPF_JGE, *lo, Jmp::Next, Jmp::Fail));
+                insns.push(jump(BPF_JGT, *hi, Jmp::Fail, Jmp::Next));
+            }
+            for arg in args {
+                if arg.arg >= 6 {
+                    return Err(Error::new(ErrorKind::Other, format!("invalid argument: arg{}", arg.arg)));
+                }
+                let offset = SECCOMP_DATA_ARGS + 8 * arg.arg as u32;
+                insns.extend(compile_cmp(offset, arg.cmp));
+            }
+        }
+        Match::InstructionPointer(ip) => {
+            insns.extend(compile_cmp(SECCOMP_DATA_IP, Cmp::Eq(*ip)));
+        }
+    }
+    insns.push(stmt(BPF_RET | BPF_K, rule.action.to_seccomp_ret()));
+
+    // `Fail` jumps right after the final `ret`.
+    let size = insns.len();
+    if size > 256 {
+        return Err(Error::new(ErrorKind::Other, format!("rule too long: {:?}", rule)));
+    }
+    let resolve = |k: usize, j: Jmp| -> u8 {
+        match j {
+            Jmp::Next => 0,
+            Jmp::Skip(n) => n,
+            Jmp::Fail => (size - k - 1) as u8,
+        }
+    };
+    Ok(insns
+        .iter()
+        .enumerate()
+        .map(|(k, insn)| libc::sock_filter {
+            code: insn.code,
+            jt: resolve(k, insn.jt),
+            jf: resolve(k, insn.jf),
+            k: insn.k,
+        })
+        .collect())
+}
+
+impl SeccompFilter {
+    pub fn new(default_action: Action) -> Self {
+        SeccompFilter {
+            rules: Vec::new(),
+            default_action,
+        }
+    }
+
+    pub fn add_rule(&mut self, rule: Rule) -> &mut Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn add_rules<I: IntoIterator<Item = Rule>>(&mut self, rules: I) -> &mut Self {
+        self.rules.extend(rules);
+        self
+    }
+
+    /// compile the filter into a BPF program.
+    pub fn compile(&self) -> Result<Vec<libc::sock_filter>> {
+        let mut prog = Vec::new();
+        for rule in &self.rules {
+            prog.extend(compile_rule(rule)?);
+        }
+        prog.push(libc::sock_filter {
+            code: BPF_RET | BPF_K,
+            jt: 0,
+            jf: 0,
+            k: self.default_action.to_seccomp_ret(),
+        });
+        if prog.len() > BPF_MAXINSNS {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("seccomp filter too long: {} instructions", prog.len()),
+            ));
+        }
+        Ok(prog)
+    }
+
+    /// install the filter for the calling thread, `PR_SET_NO_NEW_PRIVS`
+    /// must have been set.
+    pub fn install(&self) -> Result<()> {
+        let mut prog = self.compile()?;
+        let fprog = libc::sock_fprog {
+            len: prog.len() as libc::c_ushort,
+            filter: prog.as_mut_ptr(),
+        };
+        let ret = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &fprog as *const libc::sock_fprog,
+            )
+        };
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// parse rules from `text`, see module document for the format.
+    /// `default` overrides `default_action`.
+    pub fn parse_rules(&mut self, text: &str) -> Result<&mut Self> {
+        for line in text.lines() {
+            let line = match line.find('#') {
+                Some(k) => &line[..k],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (first, rest) = split_word(line);
+            if first == "default" {
+                self.default_action = parse_action(rest.trim())?;
+            } else {
+                let action = parse_action(first)?;
+                let matcher = parse_match(rest.trim())?;
+                self.rules.push(Rule { matcher, action });
+            }
+        }
+        Ok(self)
+    }
+
+    /// parse rules from a policy file.
+    pub fn parse_rules_from<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        self.parse_rules(&text)
+    }
+}
+
+fn parse_error(what: &str, s: &str) -> Error {
+    Error::new(ErrorKind::Other, format!("invalid {}: {:?}", what, s))
+}
+
+// split at the first whitespace, or `(` (kept in the rest).
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    // `errno(N)` is a single word.
+    if s.starts_with("errno(") {
+        return match s.find(')') {
+            Some(k) => (&s[..k + 1], &s[k + 1..]),
+            None => (s, ""),
+        };
+    }
+    match s.find(|c: char| c.is_whitespace() || c == '(') {
+        Some(k) => (&s[..k], &s[k..]),
+        None => (s, ""),
+    }
+}
+
+fn parse_number(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let res = if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else if s.starts_with('-') {
+        s.parse::<i64>().map(|x| x as u64)
+    } else {
+        s.parse::<u64>()
+    };
+    res.map_err(|_| parse_error("number", s))
+}
+
+fn parse_action(s: &str) -> Result<Action> {
+    match s {
+        "allow" => Ok(Action::Allow),
+        "trace" => Ok(Action::Trace),
+        "kill" => Ok(Action::Kill),
+        "log" => Ok(Action::Log),
+        _ if s.starts_with("errno(") && s.ends_with(')') => {
+            let errno = parse_number(&s[6..s.len() - 1])?;
+            if errno > SECCOMP_RET_DATA as u64 {
+                return Err(parse_error("errno", s));
+            }
+            Ok(Action::Errno(errno as u16))
+        }
+        _ => Err(parse_error("action", s)),
+    }
+}
+
+fn parse_syscall(s: &str) -> Result<u32> {
+    let s = s.trim();
+    if s.chars().all(|c| c.is_ascii_digit()) {
+        parse_number(s).map(|nr| nr as u32)
+    } else {
+        s.parse::<SyscallNo>()
+            .map(|nr| nr as u32)
+            .map_err(|e| Error::new(ErrorKind::Other, e))
+    }
+}
+
+// `argN op value`, or `argN & mask == value`
+fn parse_arg_cmp(s: &str) -> Result<ArgCmp> {
+    let s = s.trim();
+    let mut chars = s.strip_prefix("arg").ok_or(parse_error("argument comparison", s))?.chars();
+    let arg = chars
+        .next()
+        .and_then(|c| c.to_digit(10))
+        .ok_or(parse_error("argument comparison", s))? as usize;
+    let rest = chars.as_str().trim();
+    let ops = &["==", "!=", "<=", ">=", "<", ">", "&"];
+    let op = ops
+        .iter()
+        .find(|op| rest.starts_with(*op))
+        .ok_or(parse_error("argument comparison", s))?;
+    let operand = rest[op.len()..].trim();
+    let cmp = match *op {
+        "==" => Cmp::Eq(parse_number(operand)?),
+        "!=" => Cmp::Ne(parse_number(operand)?),
+        "<=" => Cmp::Le(parse_number(operand)?),
+        ">=" => Cmp::Ge(parse_number(operand)?),
+        "<" => Cmp::Lt(parse_number(operand)?),
+        ">" => Cmp::Gt(parse_number(operand)?),
+        _ => {
+            let k = operand.find("==").ok_or(parse_error("argument comparison", s))?;
+            Cmp::MaskedEq(parse_number(&operand[..k])?, parse_number(&operand[k + 2..])?)
+        }
+    };
+    Ok(ArgCmp { arg, cmp })
+}
+
+// `syscall`, `syscall(cond, ..)` or `ip(addr)`
+fn parse_match(s: &str) -> Result<Match> {
+    let (name, conds) = match s.find('(') {
+        Some(k) if s.ends_with(')') => (s[..k].trim(), Some(&s[k + 1..s.len() - 1])),
+        Some(_) => return Err(parse_error("rule", s)),
+        None => (s.trim(), None),
+    };
+    if name == "ip" {
+        let ip = parse_number(conds.unwrap_or(""))?;
+        return Ok(Match::InstructionPointer(ip));
+    }
+    let nr = parse_syscall(name)?;
+    let mut args = Vec::new();
+    if let Some(conds) = conds {
+        for cond in conds.split(',').filter(|c| !c.trim().is_empty()) {
+            args.push(parse_arg_cmp(cond)?);
+        }
+    }
+    Ok(Match::Syscalls(nr, nr, args))
+}
+
+// syscalls the tracer must see, after the trampoline's untraced syscall:
+// notifications from the trampoline (see `SYSTRACE_SYSCALL_NOTIFY`), of
+// patched syscalls changing the address space. when syscalls are traced
+// by default, syscalls changing the address space (`munmap`, `mremap`,
+// `mprotect` and `MAP_FIXED` `mmap`s, so that patch records are
+// invalidated) and, when mapped text is patched (see
+// `patches_mapped_text`), executable `mmap`s and `exit_group`, are traced
+// as well, so that the tracer sees them from unpatched sites even when no
+// tool is interested. none when patching is disabled.
+fn tracer_rules(default_action: Action) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    if get_systrace_options().disable_patching {
+        return rules;
+    }
+    let notify = consts::SYSTRACE_SYSCALL_NOTIFY as u32;
+    rules.push(Rule::syscall_range(notify, notify, Action::Trace));
+    if default_action != Action::Trace {
+        return rules;
+    }
+    rules.push(Rule::syscall(SYS_munmap, Action::Trace));
+    rules.push(Rule::syscall(SYS_mremap, Action::Trace));
+    rules.push(Rule::syscall(SYS_mprotect, Action::Trace));
+    rules.push(Rule::syscall_with_args(
+        SYS_mmap,
+        vec![ArgCmp { arg: 3, cmp: Cmp::MaskedEq(libc::MAP_FIXED as u64, libc::MAP_FIXED as u64) }],
+        Action::Trace,
+    ));
+    if get_systrace_options().patches_mapped_text() {
+        let prot_exec = libc::PROT_EXEC as u64;
+        rules.push(Rule::syscall_with_args(
+            SYS_mmap,
+            vec![ArgCmp { arg: 2, cmp: Cmp::MaskedEq(prot_exec, prot_exec) }],
+            Action::Trace,
+        ));
+        rules.push(Rule::syscall(SYS_exit_group, Action::Trace));
+    }
+    rules
+}
+
+/// reject `policy` rules which would keep the tracer from seeing the
+/// syscalls it must trace (i.e.: `allow mprotect`), these rules would
+/// be shadowed by `systrace_seccomp_filter` anyway.
+pub fn check_policy(policy: &SeccompFilter) -> Result<()> {
+    let required = tracer_rules(policy.default_action);
+    for rule in policy.rules.iter().filter(|rule| rule.action != Action::Trace) {
+        let (lo, hi) = match rule.matcher {
+            Match::Syscalls(lo, hi, _) => (lo, hi),
+            Match::InstructionPointer(_) => continue,
+        };
+        let conflict = required.iter().find_map(|r| match r.matcher {
+            Match::Syscalls(nr, _, _) if (lo..=hi).contains(&nr) => Some(nr),
+            _ => None,
+        });
+        if let Some(nr) = conflict {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("{:?} rule matches {}, which systrace must trace",
+                        rule.action,
+                        SyscallNo::from(nr as i32).to_string()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// what `filter` (installed in tracees) still traces once the tracer
+/// detached, if anything: these syscalls would fail with `ENOSYS`, hence
+/// tracees cannot be left running untraced. notifications from the
+/// trampoline (see `tracer_rules`) and syscalls from systrace's private
+/// page are not issued once detached.
+pub fn traced_once_detached(filter: &SeccompFilter) -> Option<String> {
+    if filter.default_action == Action::Trace {
+        return Some(String::from("syscalls matching no rule"));
+    }
+    let private_page = consts::SYSTRACE_PRIVATE_PAGE_OFFSET
+        ..consts::SYSTRACE_PRIVATE_PAGE_OFFSET + consts::SYSTRACE_PRIVATE_PAGE_SIZE;
+    let notify = consts::SYSTRACE_SYSCALL_NOTIFY as u32;
+    let name = |nr: u32| if (nr as usize) < NR_SYSCALLS {
+        SyscallNo::from(nr as i32).to_string()
+    } else {
+        nr.to_string()
+    };
+    filter.rules.iter().filter(|rule| rule.action == Action::Trace).find_map(|rule| match rule.matcher {
+        Match::Syscalls(lo, hi, _) if lo == notify && hi == notify => None,
+        Match::Syscalls(lo, hi, _) if lo == hi => Some(name(lo)),
+        Match::Syscalls(lo, hi, _) => Some(format!("{}..{}", name(lo), name(hi))),
+        Match::InstructionPointer(ip) if private_page.contains(&ip) => None,
+        Match::InstructionPointer(ip) => Some(format!("syscalls at {:x}", ip)),
+    })
+}
+
+/// the filter installed in tracees: tools' own (untraced) syscalls,
+/// `policy` rules, syscalls the trampoline forwards on behalf of the
+/// program, syscalls the tracer must trace (see `tracer_rules`),
+/// syscalls which must not be patched, the `untraced` syscall ranges
+/// (which no tool is interested in), then `policy.default_action`
+/// (`TRACE` unless overridden by the policy).
+pub fn systrace_seccomp_filter(policy: &SeccompFilter, untraced: &[(u32, u32)]) -> SeccompFilter {
+    let mut filter = SeccompFilter::new(policy.default_action);
+    filter
+        .add_rule(Rule::instruction_pointer(
+            consts::SYSTRACE_PRIVATE_PAGE_OFFSET + 2,
+            Action::Allow,
+        ))
+        .add_rules(policy.rules.iter().cloned())
+        .add_rule(Rule::instruction_pointer(
+            consts::SYSTRACE_PRIVATE_FORWARDED_SYSCALL + consts::SYSCALL_INSN_SIZE as u64,
+            Action::Allow,
+        ))
+        .add_rules(tracer_rules(policy.default_action))
+        .add_rule(Rule::syscall(SYS_clone, Action::Allow))
+        .add_rule(Rule::syscall(SYS_fork, Action::Allow))
+        .add_rule(Rule::syscall(SYS_vfork, Action::Allow))
+        .add_rule(Rule::syscall(SYS_rt_sigreturn, Action::Allow));
+    filter.add_rules(untraced.iter().map(|(lo, hi)| Rule::syscall_range(*lo, *hi, Action::Allow)));
+    filter
+}
+
+/// run BPF program `prog` against a syscall, returns the `SECCOMP_RET_*`
+/// value, as the kernel would do. only instructions generated by
+/// `SeccompFilter` are supported.
+pub fn simulate(prog: &[libc::sock_filter], nr: u32, ip: u64, args: &[u64; 6]) -> Result<u32> {
+    let read = |offset: u32| -> Result<u32> {
+        let word = |x: u64, off: u32| if off & 4 == 0 { lo32(x) } else { hi32(x) };
+        match offset {
+            SECCOMP_DATA_NR => Ok(nr),
+            SECCOMP_DATA_IP | 12 => Ok(word(ip, offset)),
+            k if (SECCOMP_DATA_ARGS..SECCOMP_DATA_ARGS + 48).contains(&k) => {
+                Ok(word(args[(k - SECCOMP_DATA_ARGS) as usize / 8], k))
+            }
+            _ => Err(Error::new(ErrorKind::Other, format!("invalid load @{}", offset))),
+        }
+    };
+    let mut acc: u32 = 0;
+    let mut pc = 0;
+    while pc < prog.len() {
+        let insn = &prog[pc];
+        pc += 1;
+        match insn.code {
+            code if code == BPF_LD | BPF_W | BPF_ABS => acc = read(insn.k)?,
+            code if code == BPF_ALU | BPF_AND | BPF_K => acc &= insn.k,
+            code if code == BPF_RET | BPF_K => return Ok(insn.k),
+            code if code == BPF_JMP | BPF_JA => pc += insn.k as usize,
+            code if code & 0x07 == BPF_JMP => {
+                let cond = match code & 0xf0 {
+                    BPF_JEQ => acc == insn.k,
+                    BPF_JGT => acc > insn.k,
+                    BPF_JGE => acc >= insn.k,
+                    _ => return Err(Error::new(ErrorKind::Other, format!("invalid jump {:x}", code))),
+                };
+                pc += if cond { insn.jt } else { insn.jf } as usize;
+            }
+            code => return Err(Error::new(ErrorKind::Other, format!("invalid instruction {:x}", code))),
+        }
+    }
+    Err(Error::new(ErrorKind::Other, "no return"))
+}
+
+#[cfg(test)]
+fn run(filter: &SeccompFilter, nr: SyscallNo, args: &[u64; 6]) -> Action {
+    let prog = filter.compile().unwrap();
+    let ret = simulate(&prog, nr as u32, 0x1000, args).unwrap();
+    Action::from_seccomp_ret(ret).unwrap()
+}
+
+#[test]
+fn seccomp_filter_syscall_rules() {
+    let mut filter = SeccompFilter::new(Action::Trace);
+    filter
+        .add_rule(Rule::syscall(SYS_clone, Action::Allow))
+        .add_rule(Rule::syscall(SYS_ptrace, Action::Errno(1)))
+        .add_rule(Rule::syscall_range(SYS_read as u32, SYS_close as u32, Action::Log))
+        .add_rule(Rule::instruction_pointer(0x7000_0002, Action::Allow));
+    let args = [0u64; 6];
+    assert_eq!(run(&filter, SYS_clone, &args), Action::Allow);
+    assert_eq!(run(&filter, SYS_ptrace, &args), Action::Errno(1));
+    assert_eq!(run(&filter, SYS_write, &args), Action::Log);
+    assert_eq!(run(&filter, SYS_getpid, &args), Action::Trace);
+    let prog = filter.compile().unwrap();
+    let ret = simulate(&prog, SYS_getpid as u32, 0x7000_0002, &args).unwrap();
+    assert_eq!(Action::from_seccomp_ret(ret), Some(Action::Allow));
+    let ret = simulate(&prog, SYS_getpid as u32, 0x1_7000_0002, &args).unwrap();
+    assert_eq!(Action::from_seccomp_ret(ret), Some(Action::Trace));
+}
+
+#[test]
+fn systrace_seccomp_filter_sanity_check() {
+    let mut policy = SeccompFilter::new(Action::Trace);
+    policy.add_rule(Rule::syscall(SYS_fork, Action::Errno(1)));
+    assert!(check_policy(&policy).is_ok());
+    let mut rejected = policy.clone();
+    rejected.add_rule(Rule::syscall(SYS_mprotect, Action::Allow));
+    assert!(check_policy(&rejected).is_err());
+    let filter = systrace_seccomp_filter(&policy, &[(SYS_getpid as u32, SYS_getpid as u32)]);
+    let args = [0u64; 6];
+    assert_eq!(run(&filter, SYS_fork, &args), Action::Errno(1));
+    assert_eq!(run(&filter, SYS_mprotect, &args), Action::Trace);
+    assert_eq!(run(&filter, SYS_vfork, &args), Action::Allow);
+    assert_eq!(run(&filter, SYS_getpid, &args), Action::Allow);
+    assert_eq!(run(&filter, SYS_openat, &args), Action::Trace);
+    let prog = filter.compile().unwrap();
+    let forwarded = consts::SYSTRACE_PRIVATE_FORWARDED_SYSCALL + consts::SYSCALL_INSN_SIZE as u64;
+    let ret = simulate(&prog, SYS_openat as u32, forwarded, &args).unwrap();
+    assert_eq!(Action::from_seccomp_ret(ret), Some(Action::Allow));
+    // policy rules apply to forwarded syscalls, not to tools' own.
+    let ret = simulate(&prog, SYS_fork as u32, forwarded, &args).unwrap();
+    assert_eq!(Action::from_seccomp_ret(ret), Some(Action::Errno(1)));
+    let ret = simulate(&prog, SYS_fork as u32, consts::SYSTRACE_PRIVATE_PAGE_OFFSET + 2, &args).unwrap();
+    assert_eq!(Action::from_seccomp_ret(ret), Some(Action::Allow));
+    // address space changes are notified by the trampoline instead.
+    let ret = simulate(&prog, SYS_munmap as u32, forwarded, &args).unwrap();
+    assert_eq!(Action::from_seccomp_ret(ret), Some(Action::Allow));
+    let notify = consts::SYSTRACE_SYSCALL_NOTIFY as u32;
+    let ret = simulate(&prog, notify, consts::SYSTRACE_PRIVATE_PAGE_OFFSET + 4, &args).unwrap();
+    assert_eq!(Action::from_seccomp_ret(ret), Some(Action::Trace));
+    let mut fixed = args;
+    fixed[3] = (libc::MAP_PRIVATE | libc::MAP_FIXED) as u64;
+    assert_eq!(run(&filter, SYS_mmap, &fixed), Action::Trace);
+    // only notifications are traced when syscalls are allowed by default.
+    let filter = systrace_seccomp_filter(&SeccompFilter::new(Action::Allow), &[]);
+    assert_eq!(run(&filter, SYS_munmap, &args), Action::Allow);
+    assert_eq!(run(&filter, SYS_mmap, &fixed), Action::Allow);
+    let prog = filter.compile().unwrap();
+    let ret = simulate(&prog, notify, consts::SYSTRACE_PRIVATE_PAGE_OFFSET + 4, &args).unwrap();
+    assert_eq!(Action::from_seccomp_ret(ret), Some(Action::Trace));
+}
+
+#[test]
+fn traced_once_detached_sanity_check() {
+    let filter = systrace_seccomp_filter(&SeccompFilter::new(Action::Trace), &[]);
+    assert!(traced_once_detached(&filter).is_some());
+    let mut policy = SeccompFilter::new(Action::Allow);
+    policy.add_rule(Rule::syscall(SYS_ptrace, Action::Errno(1)));
+    let filter = systrace_seccomp_filter(&policy, &[]);
+    assert_eq!(traced_once_detached(&filter), None);
+    policy.add_rule(Rule::syscall(SYS_getppid, Action::Trace));
+    let filter = systrace_seccomp_filter(&policy, &[]);
+    assert_eq!(traced_once_detached(&filter), Some(String::from("getppid")));
+}
+
+#[test]
+fn seccomp_filter_arg_cmps() {
+    let big = 0x1_0000_0000u64;
+    let cmps = &[
+        (Cmp::Eq(big + 1), &[big + 1][..], &[1, big][..]),
+        (Cmp::Ne(big + 1), &[1, big, 0][..], &[big + 1][..]),
+        (Cmp::Lt(big + 1), &[0, big, 1][..], &[big + 1, big + 2, 2 * big][..]),
+        (Cmp::Le(big + 1), &[0, big, big + 1][..], &[big + 2, 2 * big][..]),
+        (Cmp::Gt(big + 1), &[big + 2, 2 * big][..], &[0, big, big + 1][..]),
+        (Cmp::Ge(big + 1), &[big + 1, 2 * big][..], &[0, 2, big][..]),
+        (Cmp::MaskedEq(big | 0x3, big | 0x1), &[big | 0x5, big | 0x1][..], &[0x1, big | 0x3][..]),
+    ];
+    for (cmp, matched, unmatched) in cmps {
+        let mut filter = SeccompFilter::new(Action::Allow);
+        filter.add_rule(Rule::syscall_with_args(SYS_openat, vec![ArgCmp { arg: 2, cmp: *cmp }], Action::Kill));
+        for x in matched.iter() {
+            assert_eq!(run(&filter, SYS_openat, &[0, 0, *x, 0, 0, 0]), Action::Kill, "{:?} {:x}", cmp, x);
+        }
+        for x in unmatched.iter() {
+            assert_eq!(run(&filter, SYS_openat, &[0, 0, *x, 0, 0, 0]), Action::Allow, "{:?} {:x}", cmp, x);
+        }
+    }
+}
+
+#[test]
+fn seccomp_filter_parse_rules() -> Result<()> {
+    let mut filter = SeccompFilter::new(Action::Allow);
+    filter.parse_rules(
+        "# sandbox\n\
+         default trace\n\
+         errno(1) ptrace\n\
+         kill openat(arg2 & 0x3 == 0x1, arg3 != 0)\n\
+         log 39\n\
+         allow ip(0x70000002)\n",
+    )?;
+    assert_eq!(filter.default_action, Action::Trace);
+    assert_eq!(filter.rules.len(), 4);
+    assert_eq!(filter.rules[0], Rule::syscall(SYS_ptrace, Action::Errno(1)));
+    assert_eq!(filter.rules[1], Rule::syscall_with_args(SYS_openat, vec![
+        ArgCmp { arg: 2, cmp: Cmp::MaskedEq(3, 1) },
+        ArgCmp { arg: 3, cmp: Cmp::Ne(0) },
+    ], Action::Kill));
+    assert_eq!(filter.rules[2], Rule::syscall(SYS_getpid, Action::Log));
+    assert_eq!(filter.rules[3], Rule::instruction_pointer(0x7000_0002, Action::Allow));
+    assert!(SeccompFilter::new(Action::Allow).parse_rules("deny openat").is_err());
+    assert!(SeccompFilter::new(Action::Allow).parse_rules("allow no_such_syscall").is_err());
+    assert!(SeccompFilter::new(Action::Allow).parse_rules("allow openat(argé==1)").is_err());
+    assert!(SeccompFilter::new(Action::Allow).parse_rules("allow openat(arg)").is_err());
+    Ok(())
+}
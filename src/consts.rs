@@ -10,6 +10,20 @@ pub const SYSCALL_INSN: u64 = 0x050f;
 pub const SYSTRACE_PRIVATE_PAGE_OFFSET: u64 = 0x7000_0000;
 pub const SYSTRACE_PRIVATE_PAGE_SIZE: u64 = 0x4000;
 
+// `int3` in the syscall stubs (see `gen_syscall_sequences_at`), used as
+// return address of functions called by the tracer in tracee's context.
+pub const SYSTRACE_PRIVATE_BREAKPOINT: u64 = SYSTRACE_PRIVATE_PAGE_OFFSET + 0xd;
+
+// `syscall; ret` by which the trampoline runs the program's (patched)
+// syscalls, `forwarded_syscall` in `trampoline/route.c`: unlike tools'
+// own untraced syscalls (at offset 0), these are subject to policy rules.
+pub const SYSTRACE_PRIVATE_FORWARDED_SYSCALL: u64 = SYSTRACE_PRIVATE_PAGE_OFFSET + 0x38;
+
+// not a syscall: issued by the trampoline with `traced_syscall`, to notify
+// the tracer of a patched syscall just done (i.e.: `munmap`), as
+// `(nr, arg0, arg1, arg2, arg3, retval)`, see `trampoline/route.c`.
+pub const SYSTRACE_SYSCALL_NOTIFY: u64 = 0x1000;
+
 pub const SYSTRACE_GLOBAL_STATE_FILE: &'static str = "systrace";
 pub const SYSTRACE_GLOBAL_STATE_ADDR: u64 = 0x7020_0000;
 pub const SYSTRACE_GLOBAL_STATE_SIZE: u64 = 0x1000;
@@ -38,6 +52,36 @@ pub const SYSTRACE_LOCAL_SYSTOOL_LOG_LEVEL: u64 =
 pub const SYSTRACE_LOCAL_SYSTRACE_GLOBAL_STATE: u64 =
     SYSTRACE_LOCAL_SYSTOOL_LOG_LEVEL + std::mem::size_of::<u64>() as u64;
 
+// entry of `_syscall_hook_trampoline_ptraced`, written by libtrampoline
+pub const SYSTRACE_LOCAL_SYSCALL_TRAMPOLINE_PTRACED: u64 =
+    SYSTRACE_LOCAL_SYSTRACE_GLOBAL_STATE + std::mem::size_of::<u64>() as u64;
+
+// number of tools in the tool chain, written by the tracer.
+pub const SYSTRACE_LOCAL_TOOL_CHAIN_SIZE: u64 =
+    SYSTRACE_LOCAL_SYSCALL_TRAMPOLINE_PTRACED + std::mem::size_of::<u64>() as u64;
+
+// size (in bytes) of `--tool-arg` options, written by the tracer.
+pub const SYSTRACE_LOCAL_TOOL_ARGS_SIZE: u64 =
+    SYSTRACE_LOCAL_TOOL_CHAIN_SIZE + std::mem::size_of::<u64>() as u64;
+
+// tool chain table, one `[captured_syscall_enter, captured_syscall,
+// captured_syscall_exit]` entry per tool, in `--tool` order.
+pub const SYSTRACE_TOOL_CHAIN_ADDR: u64 = SYSTRACE_PRIVATE_PAGE_OFFSET + 0x2000;
+pub const SYSTRACE_TOOL_CHAIN_MAX: usize = 16;
+
+// `--tool-arg` options, as NUL terminated `key=value` strings.
+pub const SYSTRACE_TOOL_ARGS_ADDR: u64 = SYSTRACE_PRIVATE_PAGE_OFFSET + 0x3000;
+pub const SYSTRACE_TOOL_ARGS_MAX_SIZE: usize = 0x1000;
+
+// tools can export `SYSTRACE_INTEREST: [u64; 8]`, a bitmap of syscalls
+// the tool is interested in, other syscalls are not traced at all.
+pub const SYSTRACE_INTEREST_SYMBOL: &'static str = "SYSTRACE_INTEREST";
+pub const SYSTRACE_INTEREST_WORDS: usize = 8;
+
+// size of the x86_64 red zone, must be skipped when pushing frames on
+// tracee's stack.
+pub const SYSTRACE_RED_ZONE_SIZE: u64 = 0x80;
+
 #[test]
 fn det_tls_sanity_check() {
     assert_eq!(SYSTRACE_LOCAL_SYSCALL_HOOK_SIZE, SYSTRACE_LOCAL_BASE + 0);
@@ -49,4 +93,11 @@ fn det_tls_sanity_check() {
     assert_eq!(SYSTRACE_LOCAL_SYSCALL_PATCH_LOCK, SYSTRACE_LOCAL_BASE + 48);
     assert_eq!(SYSTRACE_LOCAL_SYSTOOL_LOG_LEVEL, SYSTRACE_LOCAL_BASE + 56);
     assert_eq!(SYSTRACE_LOCAL_SYSTRACE_GLOBAL_STATE, SYSTRACE_LOCAL_BASE + 64);
+    assert_eq!(SYSTRACE_LOCAL_SYSCALL_TRAMPOLINE_PTRACED, SYSTRACE_LOCAL_BASE + 72);
+    assert_eq!(SYSTRACE_LOCAL_TOOL_CHAIN_SIZE, SYSTRACE_LOCAL_BASE + 80);
+    assert_eq!(SYSTRACE_LOCAL_TOOL_ARGS_SIZE, SYSTRACE_LOCAL_BASE + 88);
+    assert!(SYSTRACE_TOOL_CHAIN_ADDR + (SYSTRACE_TOOL_CHAIN_MAX * 3 * 8) as u64
+            <= SYSTRACE_TOOL_ARGS_ADDR);
+    assert!(SYSTRACE_TOOL_ARGS_ADDR + SYSTRACE_TOOL_ARGS_MAX_SIZE as u64
+            <= SYSTRACE_PRIVATE_PAGE_OFFSET + SYSTRACE_PRIVATE_PAGE_SIZE);
 }
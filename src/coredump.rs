@@ -0,0 +1,398 @@
+//! ELF core dumps of crashing tracees (`--core-dir`)
+//!
+//! when a tracee is about to be killed by a core dumping signal, the tracer
+//! writes the core itself, before the signal is delivered: cores dumped by
+//! the kernel are often unavailable from within our user namespace (i.e.:
+//! `core_pattern` pipes to a host program). the core has a `PT_NOTE`
+//! segment (`NT_PRSTATUS` and `NT_PRFPREG` for each thread, the faulting
+//! thread first, `NT_PRPSINFO`, `NT_SIGINFO`, `NT_AUXV` and `NT_FILE`),
+//! and a `PT_LOAD` segment per mapping, with the contents of readable
+//! mappings (read with `Remote::peek_bytes`), so that `gdb PROGRAM CORE`
+//! works as usual.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use nix::sys::wait::{self, WaitPidFlag, WaitStatus};
+use nix::sys::{ptrace, signal};
+use nix::unistd::Pid;
+
+use crate::attach;
+use crate::proc::*;
+use crate::remote::*;
+use crate::task::Task;
+use crate::traced_task::TracedTask;
+
+const PAGE_SIZE: u64 = 0x1000;
+// memory is read by chunks of this size, unreadable chunks are zeroed.
+const CHUNK_SIZE: u64 = 0x10_0000;
+// how long to wait for another thread to stop.
+const THREAD_STOP_TIMEOUT: Duration = Duration::from_millis(100);
+
+const ELF_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_PRFPREG: u32 = 2;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+const NT_SIGINFO: u32 = 0x5349_4749;
+const NT_FILE: u32 = 0x4649_4c45;
+
+/// signals whose default action is to dump core, which the tracer sees
+/// as signal-delivery-stops (`SIGTRAP` is left out, ptrace uses it).
+pub const CORE_SIGNALS: &[signal::Signal] = &[
+    signal::SIGQUIT,
+    signal::SIGILL,
+    signal::SIGABRT,
+    signal::SIGBUS,
+    signal::SIGFPE,
+    signal::SIGSEGV,
+];
+
+// a hex mask (i.e.: `SigCgt`) from `/proc/<tid>/status`.
+fn status_mask(tid: Pid, field: &str) -> u64 {
+    proc_status_field(tid, field)
+        .and_then(|mask| u64::from_str_radix(&mask, 16).ok())
+        .unwrap_or(0)
+}
+
+// first word of `field` in `/proc/<tid>/status`.
+fn proc_status_field(tid: Pid, field: &str) -> Option<String> {
+    let status = fs::read_to_string(format!("/proc/{}/status", tid)).ok()?;
+    status
+        .lines()
+        .find(|line| line.starts_with(field) && line[field.len()..].starts_with(':'))
+        .and_then(|line| line[field.len() + 1..].split_whitespace().next())
+        .map(String::from)
+}
+
+/// `sig` (with `si_code`) kills thread `tid` once delivered: it is not
+/// caught nor ignored, or it is a fault (sent by the kernel) which is
+/// blocked or ignored, the kernel then resets it to its default action.
+pub fn is_fatal(tid: Pid, sig: signal::Signal, si_code: i32) -> bool {
+    let bit = 1u64 << (sig as u64 - 1);
+    let fault = si_code > 0;
+    if status_mask(tid, "SigIgn") & bit != 0 {
+        fault
+    } else {
+        status_mask(tid, "SigCgt") & bit == 0 || (fault && status_mask(tid, "SigBlk") & bit != 0)
+    }
+}
+
+// registers of thread `tid`.
+struct ThreadState {
+    tid: Pid,
+    signal: Option<signal::Signal>,
+    regs: libc::user_regs_struct,
+    fpregs: Option<libc::user_fpregs_struct>,
+}
+
+fn getfpregs(tid: Pid) -> Option<libc::user_fpregs_struct> {
+    let mut fpregs: libc::user_fpregs_struct = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::ptrace(libc::PTRACE_GETFPREGS, tid.as_raw(), 0, &mut fpregs as *mut libc::user_fpregs_struct)
+    };
+    if ret == 0 {
+        Some(fpregs)
+    } else {
+        None
+    }
+}
+
+// registers of another thread of the crashing process. a thread which
+// is not in a ptrace stop is stopped with `SIGSTOP`; its stop is consumed
+// here, which is fine as the whole process is about to be killed.
+fn other_thread_state(pid: Pid, tid: Pid) -> Option<ThreadState> {
+    let state = |tid| {
+        let regs = ptrace::getregs(tid).ok()?;
+        Some(ThreadState { tid, signal: None, regs, fpregs: getfpregs(tid) })
+    };
+    if let Some(state) = state(tid) {
+        return Some(state);
+    }
+    let ret = unsafe { libc::syscall(libc::SYS_tgkill, pid.as_raw(), tid.as_raw(), libc::SIGSTOP) };
+    if ret != 0 {
+        return None;
+    }
+    let deadline = Instant::now() + THREAD_STOP_TIMEOUT;
+    while Instant::now() < deadline {
+        match wait::waitpid(tid, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL)) {
+            Ok(WaitStatus::StillAlive) => std::thread::sleep(Duration::from_millis(1)),
+            Ok(WaitStatus::Stopped(_, _))
+            | Ok(WaitStatus::PtraceEvent(_, _, _))
+            | Ok(WaitStatus::PtraceSyscall(_)) => return state(tid),
+            _ => return None,
+        }
+    }
+    debug!("[core] thread {} did not stop", tid);
+    None
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+// raw bytes of a C struct.
+fn push_struct<T: Copy>(buf: &mut Vec<u8>, value: &T) {
+    let bytes = unsafe { std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>()) };
+    buf.extend_from_slice(bytes);
+}
+
+fn pad4(buf: &mut Vec<u8>) {
+    while buf.len() & 3 != 0 {
+        buf.push(0);
+    }
+}
+
+fn push_note(buf: &mut Vec<u8>, note_type: u32, desc: &[u8]) {
+    const NAME: &[u8] = b"CORE\0";
+    push_u32(buf, NAME.len() as u32);
+    push_u32(buf, desc.len() as u32);
+    push_u32(buf, note_type);
+    buf.extend_from_slice(NAME);
+    pad4(buf);
+    buf.extend_from_slice(desc);
+    pad4(buf);
+}
+
+// `struct elf_prstatus`.
+fn prstatus(task: &TracedTask, sid: i32, thread: &ThreadState) -> Vec<u8> {
+    let mut desc = Vec::new();
+    let signo = thread.signal.map_or(0, |sig| sig as i32);
+    // pr_info (si_signo, si_code, si_errno), pr_cursig
+    push_u32(&mut desc, signo as u32);
+    push_u32(&mut desc, 0);
+    push_u32(&mut desc, 0);
+    push_u16(&mut desc, signo as u16);
+    push_u16(&mut desc, 0);
+    // pr_sigpend, pr_sighold
+    push_u64(&mut desc, status_mask(thread.tid, "SigPnd"));
+    push_u64(&mut desc, status_mask(thread.tid, "SigBlk"));
+    for id in &[thread.tid.as_raw(), task.getppid().as_raw(), task.getpgid().as_raw(), sid] {
+        push_u32(&mut desc, *id as u32);
+    }
+    // pr_utime, pr_stime, pr_cutime, pr_cstime
+    desc.extend_from_slice(&[0u8; 4 * 16]);
+    push_struct(&mut desc, &thread.regs);
+    // pr_fpvalid
+    push_u32(&mut desc, thread.fpregs.is_some() as u32);
+    push_u32(&mut desc, 0);
+    desc
+}
+
+// `struct elf_prpsinfo`.
+fn prpsinfo(task: &TracedTask, sid: i32) -> Vec<u8> {
+    let pid = task.getpid();
+    let id = |field: &str| proc_status_field(pid, field).and_then(|id| id.parse::<u32>().ok()).unwrap_or(0);
+    let comm = fs::read(format!("/proc/{}/comm", pid)).unwrap_or_default();
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let mut desc = Vec::new();
+    // pr_state, pr_sname, pr_zomb, pr_nice, pr_flag
+    desc.extend_from_slice(&[0, b'R', 0, 0, 0, 0, 0, 0]);
+    push_u64(&mut desc, 0);
+    push_u32(&mut desc, id("Uid"));
+    push_u32(&mut desc, id("Gid"));
+    for id in &[pid.as_raw(), task.getppid().as_raw(), task.getpgid().as_raw(), sid] {
+        push_u32(&mut desc, *id as u32);
+    }
+    let mut fname = [0u8; 16];
+    comm.iter()
+        .take_while(|c| **c != b'\n')
+        .take(15)
+        .enumerate()
+        .for_each(|(k, c)| fname[k] = *c);
+    desc.extend_from_slice(&fname);
+    let mut psargs = [0u8; 80];
+    let args = cmdline.strip_suffix(&[0]).unwrap_or(&cmdline);
+    args.iter()
+        .take(79)
+        .enumerate()
+        .for_each(|(k, c)| psargs[k] = if *c == 0 { b' ' } else { *c });
+    desc.extend_from_slice(&psargs);
+    desc
+}
+
+// `NT_FILE`: file backed mappings of `maps`.
+fn mapped_files(maps: &[ProcMapsEntry]) -> Vec<u8> {
+    let files: Vec<(&ProcMapsEntry, &PathBuf)> = maps
+        .iter()
+        .filter_map(|e| e.filename().filter(|file| file.is_absolute()).map(|file| (e, file)))
+        .collect();
+    let mut desc = Vec::new();
+    push_u64(&mut desc, files.len() as u64);
+    push_u64(&mut desc, PAGE_SIZE);
+    for (e, _) in &files {
+        push_u64(&mut desc, e.base());
+        push_u64(&mut desc, e.end());
+        push_u64(&mut desc, e.offset() / PAGE_SIZE);
+    }
+    for (_, file) in &files {
+        desc.extend_from_slice(file.to_string_lossy().as_bytes());
+        desc.push(0);
+    }
+    desc
+}
+
+// mappings whose contents are dumped, `[vvar]` cannot be read, nor can
+// `[vsyscall]`.
+fn is_dumped(e: &ProcMapsEntry) -> bool {
+    let special = e.filename().is_some_and(|file| {
+        let file = file.to_string_lossy();
+        file.starts_with("[vvar") || file == "[vsyscall]"
+    });
+    e.prot() & libc::PROT_READ != 0 && !special
+}
+
+fn write_program_header(f: &mut dyn Write, p_type: u32, flags: u32, offset: u64, vaddr: u64, filesz: u64, memsz: u64) -> Result<()> {
+    let align = if p_type == PT_NOTE { 4 } else { PAGE_SIZE };
+    let mut buf = Vec::new();
+    push_u32(&mut buf, p_type);
+    push_u32(&mut buf, flags);
+    push_u64(&mut buf, offset);
+    push_u64(&mut buf, vaddr);
+    push_u64(&mut buf, 0);
+    push_u64(&mut buf, filesz);
+    push_u64(&mut buf, memsz);
+    push_u64(&mut buf, align);
+    f.write_all(&buf)
+}
+
+fn write_elf_header(f: &mut dyn Write, phnum: u16) -> Result<()> {
+    let mut buf = Vec::new();
+    // ELFCLASS64, ELFDATA2LSB, EV_CURRENT, ELFOSABI_NONE
+    buf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    buf.extend_from_slice(&[0u8; 8]);
+    push_u16(&mut buf, ET_CORE);
+    push_u16(&mut buf, EM_X86_64);
+    push_u32(&mut buf, 1);
+    // e_entry, e_phoff, e_shoff, e_flags
+    push_u64(&mut buf, 0);
+    push_u64(&mut buf, ELF_HEADER_SIZE);
+    push_u64(&mut buf, 0);
+    push_u32(&mut buf, 0);
+    push_u16(&mut buf, ELF_HEADER_SIZE as u16);
+    push_u16(&mut buf, PROGRAM_HEADER_SIZE as u16);
+    push_u16(&mut buf, phnum);
+    // e_shentsize, e_shnum, e_shstrndx
+    push_u16(&mut buf, 64);
+    push_u16(&mut buf, 0);
+    push_u16(&mut buf, 0);
+    f.write_all(&buf)
+}
+
+// `size` bytes of memory @addr, pages which cannot be read (i.e.: file
+// mappings beyond the end of the file) are zeroed.
+fn read_memory(task: &TracedTask, addr: u64, size: usize) -> Vec<u8> {
+    if let Ok(bytes) = task.peek_bytes(RemotePtr::new(addr as *mut u8), size) {
+        return bytes;
+    }
+    let mut res = Vec::with_capacity(size);
+    for page in (addr..addr + size as u64).step_by(PAGE_SIZE as usize) {
+        let bytes = task
+            .peek_bytes(RemotePtr::new(page as *mut u8), PAGE_SIZE as usize)
+            .unwrap_or_else(|_| vec![0u8; PAGE_SIZE as usize]);
+        res.extend_from_slice(&bytes);
+    }
+    res
+}
+
+/// write the core of the process of `task`, which got `sig`, under `dir`
+/// as `core.<comm>.<pid>`; the path of the core is returned.
+pub fn write_core(task: &TracedTask, sig: signal::Signal, dir: &Path) -> Result<PathBuf> {
+    let pid = task.getpid();
+    let maps = decode_proc_maps(pid)?;
+    let regs = task.getregs()?;
+    let mut threads = vec![ThreadState {
+        tid: task.gettid(),
+        signal: Some(sig),
+        regs,
+        fpregs: getfpregs(task.gettid()),
+    }];
+    for tid in attach::process_threads(pid)? {
+        if tid != task.gettid() {
+            threads.extend(other_thread_state(pid, tid));
+        }
+    }
+    let sid = unsafe { libc::getsid(pid.as_raw()) };
+
+    let mut notes = Vec::new();
+    for (k, thread) in threads.iter().enumerate() {
+        push_note(&mut notes, NT_PRSTATUS, &prstatus(task, sid, thread));
+        if let Some(fpregs) = &thread.fpregs {
+            let mut desc = Vec::new();
+            push_struct(&mut desc, fpregs);
+            push_note(&mut notes, NT_PRFPREG, &desc);
+        }
+        // process wide notes follow the first thread, like the kernel does.
+        if k == 0 {
+            push_note(&mut notes, NT_PRPSINFO, &prpsinfo(task, sid));
+            if let Ok(siginfo) = task.getsiginfo() {
+                let mut desc = Vec::new();
+                push_struct(&mut desc, &siginfo);
+                push_note(&mut notes, NT_SIGINFO, &desc);
+            }
+            if let Ok(auxv) = fs::read(format!("/proc/{}/auxv", pid)) {
+                push_note(&mut notes, NT_AUXV, &auxv);
+            }
+            push_note(&mut notes, NT_FILE, &mapped_files(&maps));
+        }
+    }
+
+    let phnum = maps.len() + 1;
+    if phnum > u16::MAX as usize {
+        return Err(Error::new(ErrorKind::Other, format!("too many mappings for a core: {}", maps.len())));
+    }
+    let notes_offset = ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * phnum as u64;
+    let mut offset = (notes_offset + notes.len() as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    fs::create_dir_all(dir)?;
+    let comm = proc_status_field(pid, "Name").unwrap_or_else(|| String::from("unknown"));
+    let path = dir.join(format!("core.{}.{}", comm, pid));
+    let mut f = BufWriter::new(File::create(&path)?);
+    write_elf_header(&mut f, phnum as u16)?;
+    write_program_header(&mut f, PT_NOTE, 0, notes_offset, 0, notes.len() as u64, 0)?;
+    let mut loads = Vec::new();
+    for e in &maps {
+        let filesz = if is_dumped(e) { e.size() as u64 } else { 0 };
+        let flags = [(libc::PROT_READ, PF_R), (libc::PROT_WRITE, PF_W), (libc::PROT_EXEC, PF_X)]
+            .iter()
+            .filter(|(prot, _)| e.prot() & prot != 0)
+            .fold(0, |flags, (_, flag)| flags | flag);
+        write_program_header(&mut f, PT_LOAD, flags, offset, e.base(), filesz, e.size() as u64)?;
+        if filesz != 0 {
+            loads.push((e, offset));
+        }
+        offset += filesz;
+    }
+    f.write_all(&notes)?;
+    for (e, offset) in loads {
+        f.seek(SeekFrom::Start(offset))?;
+        let mut addr = e.base();
+        while addr < e.end() {
+            let size = CHUNK_SIZE.min(e.end() - addr) as usize;
+            f.write_all(&read_memory(task, addr, size))?;
+            addr += size as u64;
+        }
+    }
+    f.flush()?;
+    Ok(path)
+}
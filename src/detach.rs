@@ -0,0 +1,241 @@
+//! detach from tracees, leaving them running untraced
+//!
+//! detach is requested asynchronously (by a signal, see
+//! `install_detach_signals`), the scheduler then stops handing out tasks,
+//! and `detach` stops all threads, restores patched syscall sites (see
+//! `detach_restore`), and detaches with `PTRACE_DETACH`.
+//!
+//! NB: the seccomp filter cannot be removed, syscalls it traces fail with
+//! `ENOSYS` once there is no tracer. detach is hence refused unless the
+//! filter traces nothing but notifications from the trampoline (see
+//! `bpf::traced_once_detached`), i.e.: syscalls are allowed by default,
+//! tools see patched syscalls only.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use nix::sys::wait::{self, WaitPidFlag, WaitStatus};
+use nix::sys::{ptrace, signal};
+use nix::unistd::Pid;
+
+use crate::attach;
+use crate::task::{Task, TaskState};
+use crate::traced_task::*;
+
+static DETACH_REQUESTED: AtomicBool = AtomicBool::new(false);
+static DETACH_REFUSED: AtomicBool = AtomicBool::new(false);
+
+// how long to wait for all threads to stop.
+const DETACH_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+// how many times threads in patched syscalls are let run a bit, before
+// giving up restoring their process.
+const DETACH_RESTORE_ATTEMPTS: usize = 100;
+
+extern "C" fn detach_signal_handler(sig: libc::c_int) {
+    if !DETACH_REFUSED.load(Ordering::SeqCst) {
+        request_detach();
+    } else if sig == libc::SIGINT || sig == libc::SIGTERM {
+        // as if there was no handler, tracees are killed (`PTRACE_O_EXITKILL`).
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+    }
+}
+
+/// request to detach from all tracees, async-signal-safe. ignored when
+/// detach is refused (see `install_detach_signals`).
+pub fn request_detach() {
+    if !DETACH_REFUSED.load(Ordering::SeqCst) {
+        DETACH_REQUESTED.store(true, Ordering::SeqCst);
+    }
+}
+
+pub fn detach_requested() -> bool {
+    DETACH_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// request detach upon any of `signals` (received by the tracer), unless
+/// detach is `refused`: `SIGINT` and `SIGTERM` then terminate the tracer
+/// as usual, other signals are ignored. `SA_RESTART` is set, so that
+/// blocking `waitpid` are not interrupted.
+pub fn install_detach_signals(signals: &[signal::Signal], refused: bool) -> Result<()> {
+    DETACH_REFUSED.store(refused, Ordering::SeqCst);
+    let action = signal::SigAction::new(
+        signal::SigHandler::Handler(detach_signal_handler),
+        signal::SaFlags::SA_RESTART,
+        signal::SigSet::empty(),
+    );
+    for sig in signals {
+        unsafe { signal::sigaction(*sig, &action) }.map_err(|e| Error::new(ErrorKind::Other, e))?;
+    }
+    Ok(())
+}
+
+fn tgkill(task: &TracedTask, sig: signal::Signal) -> Result<()> {
+    let ret = unsafe {
+        libc::syscall(libc::SYS_tgkill, task.getpid().as_raw(), task.gettid().as_raw(), sig as libc::c_int)
+    };
+    if ret != 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// `SIGSTOP` is pending for thread `tid`.
+fn sigstop_pending(tid: Pid) -> bool {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", tid)).unwrap_or_default();
+    status
+        .lines()
+        .filter(|line| line.starts_with("SigPnd:") || line.starts_with("ShdPnd:"))
+        .filter_map(|line| u64::from_str_radix(line[7..].trim(), 16).ok())
+        .any(|mask| mask & (1u64 << (signal::SIGSTOP as u64 - 1)) != 0)
+}
+
+// stop all `tasks`: seized tasks with `PTRACE_INTERRUPT`, others with
+// `SIGSTOP`. events received meanwhile are let through, except new tasks
+// are added, and signals are kept in `signal_to_deliver`. tasks which did
+// not stop before the timeout are returned as the second item.
+fn stop_all(tasks: Vec<TracedTask>) -> (Vec<TracedTask>, Vec<TracedTask>) {
+    let mut running: HashMap<Pid, TracedTask> = HashMap::new();
+    let mut stopped: Vec<TracedTask> = Vec::new();
+    for task in tasks {
+        let tid = task.gettid();
+        if attach::interrupt(tid).is_err() {
+            let _ = tgkill(&task, signal::SIGSTOP);
+        }
+        running.insert(tid, task);
+    }
+    let deadline = Instant::now() + DETACH_STOP_TIMEOUT;
+    while !running.is_empty() && Instant::now() < deadline {
+        let mut idle = true;
+        let tids: Vec<Pid> = running.keys().cloned().collect();
+        for tid in tids {
+            let status = wait::waitpid(tid, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL));
+            if status != Ok(WaitStatus::StillAlive) {
+                idle = false;
+                debug!("[detach] {} {:?}", tid, status);
+            }
+            let mut resume = true;
+            match status {
+                Ok(WaitStatus::StillAlive) => resume = false,
+                Ok(WaitStatus::PtraceEvent(_, _, event)) if event as i64 == PTRACE_EVENT_STOP => {
+                    resume = false;
+                }
+                Ok(WaitStatus::Stopped(_, signal::SIGSTOP)) if !sigstop_pending(tid) => {
+                    resume = false;
+                }
+                Ok(WaitStatus::Stopped(_, signal::SIGSTOP)) => (),
+                Ok(WaitStatus::Stopped(_, sig)) => {
+                    if let Some(task) = running.get_mut(&tid) {
+                        task.signal_to_deliver = task.signal_to_deliver.or(Some(sig));
+                    }
+                }
+                Ok(WaitStatus::PtraceEvent(_, _, event)) if event == libc::PTRACE_EVENT_CLONE
+                    || event == libc::PTRACE_EVENT_FORK
+                    || event == libc::PTRACE_EVENT_VFORK => {
+                    let task = &running[&tid];
+                    let child = if event == libc::PTRACE_EVENT_CLONE {
+                        task.cloned()
+                    } else {
+                        task.forked()
+                    };
+                    // the new task starts with `SIGSTOP` or `PTRACE_EVENT_STOP`.
+                    running.insert(child.gettid(), child);
+                    // vfork parent cannot be resumed until the child exits.
+                    if event == libc::PTRACE_EVENT_VFORK {
+                        resume = false;
+                    }
+                }
+                Ok(WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_EXEC)) => {
+                    if let Some(task) = running.get_mut(&tid) {
+                        task.address_space.borrow_mut().clear_patched();
+                        task.address_space.borrow_mut().clear_stub_pages();
+                        task.injected_mmap_page = None;
+                    }
+                }
+                Ok(WaitStatus::PtraceEvent(_, _, _)) | Ok(WaitStatus::PtraceSyscall(_)) => (),
+                Ok(WaitStatus::Continued(_)) => (),
+                Ok(WaitStatus::Exited(_, _)) | Ok(WaitStatus::Signaled(_, _, _)) | Err(_) => {
+                    running.remove(&tid);
+                    continue;
+                }
+            }
+            if resume {
+                let _ = ptrace::cont(tid, None);
+            } else if let Some(mut task) = running.remove(&tid) {
+                task.state = match status {
+                    Ok(WaitStatus::PtraceEvent(_, _, event)) => TaskState::Event(event as u64),
+                    _ => TaskState::Stopped(signal::SIGSTOP),
+                };
+                stopped.push(task);
+            }
+        }
+        if idle {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+    (stopped, running.into_iter().map(|(_, task)| task).collect())
+}
+
+/// detach from all `tasks`, which are to be left running untraced.
+pub fn detach(tasks: Vec<TracedTask>) {
+    let (stopped, running) = stop_all(tasks);
+    if !running.is_empty() {
+        let tids: Vec<Pid> = running.iter().map(|task| task.gettid()).collect();
+        warn!("[detach] cannot stop {:?}, left attached", tids);
+    }
+    let mut processes: HashMap<Pid, Vec<TracedTask>> = HashMap::new();
+    for task in stopped {
+        processes.entry(task.getpid()).or_default().push(task);
+    }
+    let mut detached: HashSet<Pid> = HashSet::new();
+    while let Some(pid) = processes.keys().next().cloned() {
+        let mut threads = processes.remove(&pid).unwrap_or_default();
+        for _ in 0..DETACH_RESTORE_ATTEMPTS {
+            match threads_in_patched_syscalls(&threads) {
+                Ok(busy) if !busy.is_empty() => debug!("[detach] {} busy threads {:?}", pid, busy),
+                _ => break,
+            }
+            // let them leave the patched syscalls, then stop them again.
+            for task in &threads {
+                if task.state != TaskState::Event(libc::PTRACE_EVENT_VFORK as u64) {
+                    let _ = ptrace::cont(task.gettid(), None);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+            let (stopped, running) = stop_all(threads);
+            if !running.is_empty() {
+                let tids: Vec<Pid> = running.iter().map(|task| task.gettid()).collect();
+                warn!("[detach] cannot stop {:?}, left attached", tids);
+            }
+            let (ours, others): (Vec<TracedTask>, Vec<TracedTask>) =
+                stopped.into_iter().partition(|task| task.getpid() == pid);
+            for task in others {
+                processes.entry(task.getpid()).or_default().push(task);
+            }
+            threads = ours;
+        }
+        if let Err(e) = detach_restore(&mut threads) {
+            warn!("[detach] {} failed to restore: {}", pid, e);
+        }
+        for task in threads {
+            let tid = task.gettid();
+            // pending once detached.
+            if let Some(sig) = task.signal_to_deliver {
+                let _ = tgkill(&task, sig);
+            }
+            match ptrace::detach(tid) {
+                Ok(_) => {
+                    detached.insert(tid);
+                }
+                Err(e) => warn!("[detach] {} failed to detach: {}", tid, e),
+            }
+        }
+    }
+    info!("[detach] detached from {} threads", detached.len());
+}
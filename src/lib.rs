@@ -4,19 +4,34 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod address_space;
+pub mod analyze;
 pub mod consts;
+pub mod coredump;
 pub mod hooks;
 pub mod nr;
 pub mod ns;
+pub mod options;
+pub mod patch_cache;
+pub mod patch_report;
 pub mod proc;
 pub mod remote;
 pub mod remote_rwlock;
+pub mod sandbox;
 pub mod sched;
 pub mod sched_wait;
 pub mod stubs;
+pub mod symbols;
 pub mod vdso;
+pub mod vsyscall;
 pub mod task;
+pub mod tool;
 pub mod traced_task;
+pub mod unwind;
 pub mod state;
 pub mod state_tracer;
 pub mod block_events;
+pub mod bpf;
+pub mod attach;
+pub mod detach;
+pub mod x86;
@@ -4,7 +4,7 @@
 #[macro_use]
 extern crate lazy_static;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 use fern;
 use libc;
 use nix::sys::wait::WaitStatus;
@@ -21,17 +21,14 @@ use std::path::PathBuf;
 use std::sync::atomic::{Ordering, AtomicUsize};
 use std::env;
 
-use systrace::{ns, consts, task, hooks};
+use systrace::{analyze, attach, bpf, detach, ns, consts, patch_cache, patch_report, sandbox, task, hooks, tool};
 use systrace::sched::Scheduler;
 use systrace::sched_wait::SchedWait;
 use systrace::task::{RunTask, Task};
 use systrace::state::SystraceState;
 use systrace::state_tracer::*;
-
-// install seccomp-bpf filters
-extern "C" {
-    fn bpf_install();
-}
+use systrace::options::*;
+use systrace::vdso::VdsoMode;
 
 #[test]
 fn can_resolve_syscall_hooks() -> Result<()> {
@@ -53,19 +50,42 @@ fn libtrampoline_trampoline_within_first_page() -> Result<()> {
 struct Arguments<'a> {
     debug_level: i32,
     library_path: PathBuf,
-    tool_name: &'a str,
+    tools: Vec<PathBuf>,
+    tool_args: Vec<(String, String)>,
+    seccomp_policy: bpf::SeccompFilter,
     host_envs: bool,
     envs: HashMap<String, String>,
     namespaces: bool,
     output: Option<&'a str>,
     disable_monkey_patcher: bool,
+    route_ptraced_syscalls: bool,
+    eager_patching: bool,
+    patch_cache: bool,
+    patch_report: Option<&'a str>,
+    core_dir: Option<&'a str>,
+    vdso: VdsoMode,
     show_perf_stats: bool,
+    attach: Option<unistd::Pid>,
+    detach_after: Option<u32>,
     program: &'a str,
     program_args: Vec<&'a str>,
 }
 
-fn run_tracer_main(sched: &mut SchedWait) -> i32 {
-    sched.event_loop()
+fn run_tracer_main(sched: &mut SchedWait, argv: &Arguments) -> Result<i32> {
+    // detach instead of leaving (or killing) the tracees, unless syscalls
+    // traced by the filter would fail once detached.
+    let filter = bpf::systrace_seccomp_filter(&argv.seccomp_policy, &[]);
+    let traced = bpf::traced_once_detached(&filter);
+    if let Some(traced) = &traced {
+        let level = if argv.detach_after.is_some() { log::Level::Warn } else { log::Level::Debug };
+        log::log!(level, "[main] detach is refused, {} would fail with ENOSYS once detached", traced);
+    }
+    let signals = [signal::SIGINT, signal::SIGTERM, signal::SIGUSR1, signal::SIGALRM];
+    detach::install_detach_signals(&signals, traced.is_some())?;
+    if let Some(seconds) = argv.detach_after.filter(|seconds| *seconds > 0) {
+        unsafe { libc::alarm(seconds) };
+    }
+    Ok(sched.event_loop())
 }
 
 fn wait_sigstop(pid: unistd::Pid) -> Result<()> {
@@ -97,11 +117,25 @@ fn tracee_init_signals() {
     };
 }
 
+// seccomp filter for the tracee(s), and ranges of syscalls not traced.
+fn seccomp_filter(argv: &Arguments) -> Result<(bpf::SeccompFilter, Vec<(u32, u32)>)> {
+    // syscalls none of the tools is interested in are not traced.
+    let tools: Vec<tool::ToolSymbols> = argv.tools
+        .iter()
+        .map(|path| tool::resolve_tool_symbols_from(path.clone()))
+        .collect::<Result<_>>()?;
+    let allowed: Vec<(u32, u32)> = tool::syscall_interest(&tools)
+        .map(|interest| tool::uninteresting_syscall_ranges(&interest))
+        .unwrap_or_default();
+    let filter = bpf::systrace_seccomp_filter(&argv.seccomp_policy, &allowed);
+    Ok((filter, allowed))
+}
+
 fn run_tracee(argv: &Arguments) -> Result<i32> {
     let library_path = &argv.library_path;
-    let tool = PathBuf::from(argv.tool_name);
     let so = library_path.join(consts::LIBTRAMPOLINE_SO);
-    let libs: Vec<PathBuf> = vec![tool, so];
+    let mut libs: Vec<PathBuf> = argv.tools.clone();
+    libs.push(so);
     let ldpreload = String::from("LD_PRELOAD=")
         + &libs
             .iter()
@@ -109,6 +143,10 @@ fn run_tracee(argv: &Arguments) -> Result<i32> {
             .collect::<Vec<_>>()
             .join(":");
 
+    let (filter, allowed) = seccomp_filter(argv)?;
+    // compile early, so that bad policies fail before the tracee stops.
+    filter.compile()?;
+
     unsafe {
         assert!(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0);
         assert!(libc::personality(ADDR_NO_RANDOMIZE) != -1);
@@ -151,10 +189,14 @@ fn run_tracee(argv: &Arguments) -> Result<i32> {
         .collect();
 
     log::info!("[main] launching: {} {:?}", &argv.program, &argv.program_args);
+    if !allowed.is_empty() {
+        log::info!("[main] syscall ranges not traced: {:?}", allowed);
+    }
+    log::debug!("[main] seccomp filter: {:#?}", filter);
     // install seccomp-bpf filters
     // NB: the only syscall beyond this point should be
     // execvpe only.
-    unsafe { bpf_install() };
+    filter.install()?;
 
     unistd::execvpe(&program, args.as_slice(), envp.as_slice()).map_err(from_nix_error)?;
     panic!("exec failed: {} {:?}", &argv.program, &argv.program_args);
@@ -182,6 +224,10 @@ fn show_perf_stats(state: &SystraceState) {
     log::info!("syscalls captured(wo/ patching): {:.2}%",
                100.0 * (syscalls_captured - syscalls_patched) as f64
                / syscalls as f64);
+
+    let syscalls_routed = state.nr_syscalls_routed.load(Ordering::SeqCst);
+    log::info!("syscalls routed to tool (w/ ptrace): {:.2}%",
+               100.0 * syscalls_routed as f64 / syscalls as f64);
 }
 
 fn run_tracer(
@@ -219,17 +265,55 @@ fn run_tracer(
             let tracee = task::Task::new(child);
             let mut sched: SchedWait = Scheduler::new();
             sched.add(tracee);
-            let res = run_tracer_main(&mut sched);
-            if argv.show_perf_stats {
-                let state = get_systrace_state();
-                show_perf_stats(state);
-            }
-            Ok(res)
+            let res = run_tracer_main(&mut sched, argv)?;
+            run_tracer_exit(argv, res)
         }
     }
 }
 
+fn run_tracer_exit(argv: &Arguments, res: i32) -> Result<i32> {
+    if get_systrace_options().sandbox.is_some() {
+        sandbox::audit_summary();
+    }
+    if let Some(report) = &get_systrace_options().patch_report {
+        if let Err(e) = patch_report::write_report(report) {
+            log::error!("[main] cannot write patch report {:?}: {}", report, e);
+        }
+    }
+    if argv.show_perf_stats {
+        let state = get_systrace_state();
+        show_perf_stats(state);
+    }
+    Ok(res)
+}
+
+// trace an already running process (tree), see `attach::attach`.
+fn run_attach(pid: unistd::Pid, argv: &Arguments) -> Result<i32> {
+    let (filter, allowed) = seccomp_filter(argv)?;
+    let prog = filter.compile()?;
+    let so = argv.library_path.join(consts::LIBTRAMPOLINE_SO).canonicalize()?;
+    let mut libs = vec![so];
+    libs.extend(argv.tools.iter().cloned());
+
+    log::info!("[main] attaching to: {}", pid);
+    if !allowed.is_empty() {
+        log::info!("[main] syscall ranges not traced: {:?}", allowed);
+    }
+    log::debug!("[main] seccomp filter: {:#?}", filter);
+    let tasks = attach::attach(pid, &libs, &prog)?;
+    let mut sched: SchedWait = Scheduler::new();
+    for task in tasks {
+        sched.add(task);
+    }
+    let res = run_tracer_main(&mut sched, argv)?;
+    run_tracer_exit(argv, res)
+}
+
 fn run_app(argv: &Arguments) -> Result<i32> {
+    if let Some(pid) = argv.attach {
+        return run_attach(pid, argv);
+    }
+
     let (starting_pid, starting_uid, starting_gid) =
         (unistd::getpid(), unistd::getuid(), unistd::getgid());
 
@@ -282,6 +366,19 @@ fn populate_rpath(hint: Option<&str>, so: &str) -> Result<PathBuf> {
 fn main() {
     let matches = App::new("systrace - a fast syscall tracer and interceper")
         .version("0.0.1")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .setting(AppSettings::TrailingVarArg)
+        .subcommand(
+            SubCommand::with_name("analyze")
+                .about("report how syscall sites of PROGRAM and the libraries it needs would be patched, without running it")
+                .arg(
+                    Arg::with_name("program")
+                        .value_name("PROGRAM")
+                        .required(true)
+                        .help("PROGRAM"),
+                ),
+        )
         .arg(
             Arg::with_name("debug")
                 .long("debug")
@@ -300,7 +397,48 @@ fn main() {
             Arg::with_name("tool")
                 .long("tool")
                 .value_name("TOOL")
-                .help("choose which tool (/path/to/lib<TOOL>.so) to run, default to none (libnone.so) if not specified. ")
+                .help("choose which tool (/path/to/lib<TOOL>.so) to run, can be used multiple times to chain tools, in the given order. default to none if not specified. ")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tool-arg")
+                .long("tool-arg")
+                .value_name("KEY=VALUE")
+                .multiple(true)
+                .number_of_values(1)
+                .help("pass an option to the tool(s), can be used multiple times")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seccomp-rule")
+                .long("seccomp-rule")
+                .value_name("RULE")
+                .multiple(true)
+                .number_of_values(1)
+                .help("add a seccomp rule, i.e.: 'errno(1) ptrace', 'kill openat(arg2 & 0x3 == 1)' or 'default trace', can be used multiple times. rules are checked in order, before --seccomp-policy and systrace's own rules")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seccomp-policy")
+                .long("seccomp-policy")
+                .value_name("FILE")
+                .help("read seccomp rules from FILE, one rule per line (same syntax as --seccomp-rule)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("policy")
+                .long("policy")
+                .value_name("FILE")
+                .help("run as a sandbox: syscalls not allowed by the policy FILE fail with an errno, and are reported to the audit log")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audit-log")
+                .long("audit-log")
+                .value_name("FILE")
+                .help("write syscalls denied by --policy to FILE, default is the policy's `audit` or stderr")
                 .takes_value(true),
         )
         .arg(
@@ -335,15 +473,72 @@ fn main() {
              .help("do not patch any syscalls, handle all syscalls by seccomp")
              .takes_value(false)
         )
+        .arg(Arg::with_name("route-ptraced-syscalls")
+             .long("route-ptraced-syscalls")
+             .help("route syscalls which cannot be patched through the tool's captured_syscall, instead of running them natively")
+             .takes_value(false)
+        )
+        .arg(Arg::with_name("eager-patching")
+             .long("eager-patching")
+             .help("patch all syscall sites matching a hook when the program or a library is mapped, instead of on first use")
+             .takes_value(false)
+        )
+        .arg(Arg::with_name("patch-cache")
+             .long("patch-cache")
+             .help("remember patched and unpatchable syscall sites across runs, under $XDG_CACHE_HOME/systrace")
+             .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("patch-report")
+                .long("patch-report")
+                .value_name("FILE")
+                .help("write syscall sites seen by the tracer to FILE at exit, with their hits, syscalls and patch status")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("core-dir")
+                .long("core-dir")
+                .value_name("DIR")
+                .help("write an ELF core to DIR when a tracee is killed by a core dumping signal (i.e.: SIGSEGV)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("vdso")
+                .long("vdso")
+                .value_name("MODE")
+                .possible_values(&["keep", "syscall", "trampoline"])
+                .help("keep: leave vdso functions alone, syscall (default): rewrite them into syscalls, trampoline: route them to the tool without seccomp stops")
+                .takes_value(true),
+        )
         .arg(Arg::with_name("show-perf-stats")
              .long("show-perf-stats")
              .help("show systrace softare performance counter statistics, --debug must be >= 3")
              .takes_value(false)
         )
+        .arg(
+            Arg::with_name("attach")
+                .long("attach")
+                .value_name("PID")
+                .help("trace the running process PID and its descendants, instead of launching PROGRAM")
+                .validator(|pid| match pid.parse::<i32>() {
+                    Ok(k) if k > 0 => Ok(()),
+                    Ok(_) => Err(format!("{}: not a valid pid", pid)),
+                    Err(e) => Err(format!("{}: {}", pid, e)),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("detach-after")
+                .long("detach-after")
+                .value_name("SECONDS")
+                .help("detach after SECONDS (0 for never), leaving the tracees running untraced. detach is also done upon SIGINT, SIGTERM or SIGUSR1, it is refused unless the filter traces no syscall, i.e.: --seccomp-rule 'default allow'")
+                .validator(|seconds| seconds.parse::<u32>().map(|_| ()).map_err(|e| format!("{}: {}", seconds, e)))
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("program")
                 .value_name("PROGRAM")
-                .required(true)
+                .required_unless("attach")
                 .help("PROGRAM")
                 .takes_value(true),
         )
@@ -363,15 +558,72 @@ fn main() {
     let log_output = matches.value_of("with-log");
     setup_logger(log_level, log_output).expect("set log level");
 
-    let tool = matches.value_of("tool").unwrap_or_else(||{
+    if let Some(matches) = matches.subcommand_matches("analyze") {
+        let program = matches.value_of("program").unwrap();
+        let res = analyze::analyze(program)
+            .and_then(|reports| analyze::print_reports(&reports, &mut std::io::stdout()));
+        match res {
+            Ok(()) => std::process::exit(0),
+            err => panic!("analyze {} failed with error: {:?}", program, err),
+        }
+    }
+
+    let tools: Vec<PathBuf> = matches
+        .values_of("tool")
+        .unwrap_or_default()
+        .map(|tool| std::fs::canonicalize(tool)
+             .unwrap_or_else(|e| panic!("cannot find tool {}: {}", tool, e)))
+        .collect();
+    if tools.is_empty() {
         log::info!("[main] tool not specified, default to none");
-        "none"
-    });
+    }
+    assert!(tools.len() <= consts::SYSTRACE_TOOL_CHAIN_MAX,
+            "too many tools, at most {} can be chained", consts::SYSTRACE_TOOL_CHAIN_MAX);
     let rpath = matches.value_of("library-path").map(|p| PathBuf::from(p));
+    let mut seccomp_policy = bpf::SeccompFilter::new(bpf::Action::Trace);
+    for rule in matches.values_of("seccomp-rule").unwrap_or_default() {
+        seccomp_policy
+            .parse_rules(rule)
+            .unwrap_or_else(|e| panic!("bad --seccomp-rule {:?}: {}", rule, e));
+    }
+    if let Some(policy) = matches.value_of("seccomp-policy") {
+        seccomp_policy
+            .parse_rules_from(policy)
+            .unwrap_or_else(|e| panic!("bad --seccomp-policy {}: {}", policy, e));
+    }
+    let mut sandbox = matches.value_of("policy").map(|policy| {
+        let mut res = sandbox::SandboxPolicy::parse_from(policy)
+            .unwrap_or_else(|e| panic!("bad --policy {}: {}", policy, e));
+        if let Some(audit_log) = matches.value_of("audit-log") {
+            res.audit_log = Some(PathBuf::from(audit_log));
+        }
+        res
+    });
+    if let Some(policy) = &mut sandbox {
+        // opened by the loader, before any tool is loaded.
+        let mut preloads = tools.clone();
+        if let Some(so) = rpath.as_ref().and_then(|p| p.join(consts::LIBTRAMPOLINE_SO).canonicalize().ok()) {
+            preloads.push(so);
+        }
+        policy.allow_preloads(&preloads);
+        sandbox::open_audit_log(policy.audit_log.as_deref())
+            .unwrap_or_else(|e| panic!("cannot open audit log {:?}: {}", policy.audit_log, e));
+        // trapped ahead of the trampoline's untraced syscall.
+        seccomp_policy.add_rules(policy.seccomp_rules());
+    }
 
     let argv = Arguments {
         debug_level: log_level,
-        tool_name: &tool,
+        tools,
+        tool_args: matches
+            .values_of("tool-arg")
+            .unwrap_or_default()
+            .map(|s| match s.find('=') {
+                Some(i) => (s[..i].to_string(), s[i+1..].to_string()),
+                None => (s.to_string(), String::new()),
+            })
+            .collect(),
+        seccomp_policy,
         library_path: rpath.expect("cannot find shared libraries under library_path"),
         host_envs: !matches.is_present("-no-host-envs"),
         envs: matches
@@ -386,7 +638,19 @@ fn main() {
         namespaces: matches.is_present("with-namespace"),
         output: log_output,
         disable_monkey_patcher: matches.is_present("disable-monkey-patcher"),
+        route_ptraced_syscalls: matches.is_present("route-ptraced-syscalls"),
+        eager_patching: matches.is_present("eager-patching"),
+        patch_cache: matches.is_present("patch-cache"),
+        patch_report: matches.value_of("patch-report"),
+        core_dir: matches.value_of("core-dir"),
+        vdso: matches.value_of("vdso").map_or(VdsoMode::default(), |mode| {
+            mode.parse::<VdsoMode>().unwrap_or_else(|e| panic!("bad --vdso: {}", e))
+        }),
         show_perf_stats: matches.is_present("show-perf-stats"),
+        attach: matches.value_of("attach")
+            .and_then(|pid| pid.parse::<i32>().ok())
+            .map(unistd::Pid::from_raw),
+        detach_after: matches.value_of("detach-after").and_then(|seconds| seconds.parse::<u32>().ok()),
         program: matches.value_of("program").unwrap_or(""),
         program_args: matches
             .values_of("program_args")
@@ -395,6 +659,26 @@ fn main() {
     };
 
     std::env::set_var(consts::LIBTRAMPOLINE_LIBRARY_PATH, &argv.library_path);
+    set_systrace_options(SystraceOptions {
+        route_ptraced_syscalls: argv.route_ptraced_syscalls,
+        eager_patching: argv.eager_patching,
+        patch_cache: if argv.patch_cache {
+            Some(patch_cache::default_cache_dir().expect("cannot find cache directory, HOME is not set"))
+        } else {
+            None
+        },
+        patch_report: argv.patch_report.map(PathBuf::from),
+        core_dir: argv.core_dir.map(PathBuf::from),
+        vdso: argv.vdso,
+        disable_patching: argv.disable_monkey_patcher,
+        tools: argv.tools.clone(),
+        tool_args: argv.tool_args.clone(),
+        sandbox,
+    });
+    assert!(get_systrace_options().tool_args_bytes().len() <= consts::SYSTRACE_TOOL_ARGS_MAX_SIZE,
+            "--tool-arg options exceed {} bytes", consts::SYSTRACE_TOOL_ARGS_MAX_SIZE);
+    bpf::check_policy(&argv.seccomp_policy)
+        .unwrap_or_else(|e| panic!("bad --seccomp-rule or --seccomp-policy: {}", e));
     match run_app(&argv) {
         Ok(exit_code) => std::process::exit(exit_code),
         err => panic!("run app failed with error: {:?}", err),
@@ -0,0 +1,84 @@
+//! tracer options, derived from command line arguments
+//!
+//! options are set once by `main` before the first tracee is launched,
+//! and are read-only afterwards, so that tasks can consult them without
+//! carrying a copy around.
+
+use std::path::PathBuf;
+
+use crate::sandbox::SandboxPolicy;
+use crate::vdso::VdsoMode;
+
+#[derive(Debug, Clone, Default)]
+pub struct SystraceOptions {
+    /// route syscalls which cannot be patched through the tool's
+    /// `captured_syscall`, instead of letting them run natively.
+    pub route_ptraced_syscalls: bool,
+    /// patch all syscall sites matching a hook when text is mapped, instead
+    /// of on first use.
+    pub eager_patching: bool,
+    /// `--patch-cache`, directory of the persistent patch cache.
+    pub patch_cache: Option<PathBuf>,
+    /// `--patch-report`, file the syscall sites seen are written to.
+    pub patch_report: Option<PathBuf>,
+    /// `--core-dir`, cores of crashing tracees are written to it.
+    pub core_dir: Option<PathBuf>,
+    /// `--vdso`, how vdso functions of tracees are handled.
+    pub vdso: VdsoMode,
+    /// `--disable-monkey-patcher`, syscall sites are never patched, trapped
+    /// syscalls are ptraced.
+    pub disable_patching: bool,
+    /// tools (canonical paths), in the order they are chained.
+    pub tools: Vec<PathBuf>,
+    /// `--tool-arg key=value` options, readable by tools.
+    pub tool_args: Vec<(String, String)>,
+    /// `--policy`, syscalls are checked against it when set.
+    pub sandbox: Option<SandboxPolicy>,
+}
+
+impl SystraceOptions {
+    /// syscall sites are patched as soon as text is mapped, either by
+    /// `--eager-patching` or from the patch cache.
+    pub fn patches_mapped_text(&self) -> bool {
+        !self.disable_patching && (self.eager_patching || self.patch_cache.is_some())
+    }
+
+    /// syscalls of vdso functions are patched to call the trampoline, see
+    /// `VdsoMode::Trampoline`.
+    pub fn patches_vdso_syscalls(&self) -> bool {
+        !self.disable_patching && self.vdso == VdsoMode::Trampoline
+    }
+
+    /// tool args as laid out in tracee's memory: NUL terminated
+    /// `key=value` strings, back to back.
+    pub fn tool_args_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        for (k, v) in &self.tool_args {
+            res.extend_from_slice(k.as_bytes());
+            res.push(b'=');
+            res.extend_from_slice(v.as_bytes());
+            res.push(0);
+        }
+        res
+    }
+}
+
+static mut SYSTRACE_OPTIONS: Option<&'static SystraceOptions> = None;
+
+lazy_static! {
+    static ref DEFAULT_OPTIONS: SystraceOptions = SystraceOptions::default();
+}
+
+/// set global options, must be called before any tracee is created.
+pub fn set_systrace_options(options: SystraceOptions) {
+    let leaked: &'static SystraceOptions = Box::leak(Box::new(options));
+    unsafe {
+        SYSTRACE_OPTIONS = Some(leaked);
+    }
+}
+
+/// get global options, default options are returned if
+/// `set_systrace_options` was never called.
+pub fn get_systrace_options() -> &'static SystraceOptions {
+    unsafe { SYSTRACE_OPTIONS.unwrap_or(&DEFAULT_OPTIONS) }
+}
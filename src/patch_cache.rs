@@ -0,0 +1,203 @@
+//! persistent cache of syscall site verdicts (`--patch-cache`)
+//!
+//! sites are keyed by the ELF file they are mapped from and their offset
+//! in the file. a file is identified by its build-id (`NT_GNU_BUILD_ID`),
+//! or by its path, size and mtime when it has none, hence a rebuilt file
+//! starts with an empty cache. the cache of a file is an append-only text
+//! file named after the key, one site per line, later lines win:
+//!
+//! ```text
+//! # <file offset> hook <hook index> | unpatchable
+//! f43fa hook 3
+//! f4407 unpatchable
+//! ```
+//!
+//! hook indices refer to `SYSCALL_HOOKS`, the site is checked against the
+//! hook before it is patched, a stale entry is merely ignored. cache files
+//! are kept in a directory named after the systrace version and a hash of
+//! `SYSCALL_HOOKS`, so that indices (and verdicts) of another build are
+//! never used.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use goblin::elf::note::NT_GNU_BUILD_ID;
+use goblin::elf::Elf;
+
+use crate::hooks::SYSCALL_HOOKS;
+use crate::proc::*;
+
+/// what is known about a syscall site.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// patched with the hook of the given index.
+    Hook(usize),
+    /// cannot be patched, i.e.: a branch lands inside the patch.
+    Unpatchable,
+}
+
+// cached sites of a file, as of `mtime`.
+struct CachedFile {
+    key: String,
+    mtime: SystemTime,
+    sites: HashMap<u64, Verdict>,
+}
+
+lazy_static! {
+    static ref PATCH_CACHE: Mutex<HashMap<PathBuf, CachedFile>> = Mutex::new(HashMap::new());
+}
+
+/// `$XDG_CACHE_HOME/systrace`, or `$HOME/.cache/systrace`.
+pub fn default_cache_dir() -> Option<PathBuf> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache_home.join("systrace"))
+}
+
+// the directory of cache files under `dir`, for this systrace build.
+fn hooks_dir(dir: &Path) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    for hook in SYSCALL_HOOKS {
+        (hook.symbol, hook.instructions, hook.is_multi).hash(&mut hasher);
+    }
+    dir.join(format!("{}-{:016x}", env!("CARGO_PKG_VERSION"), hasher.finish()))
+}
+
+fn build_id(bytes: &[u8]) -> Option<String> {
+    let elf = Elf::parse(bytes).ok()?;
+    let note = elf
+        .iter_note_headers(bytes)?
+        .filter_map(|note| note.ok())
+        .find(|note| note.n_type == NT_GNU_BUILD_ID && note.name.trim_end_matches('\0') == "GNU")?;
+    Some(note.desc.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// the key of `file`: its build-id, or a hash of its path, size and mtime.
+fn cache_key(file: &Path) -> Result<(String, SystemTime)> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut fp = File::open(file)?;
+    let metadata = fp.metadata()?;
+    let mtime = metadata.modified()?;
+    fp.read_to_end(&mut bytes)?;
+    let key = match build_id(&bytes) {
+        Some(id) => id,
+        None => {
+            let mut hasher = DefaultHasher::new();
+            (file, metadata.len(), mtime).hash(&mut hasher);
+            format!("{}-{:016x}", file.file_name().unwrap_or_default().to_string_lossy(), hasher.finish())
+        }
+    };
+    Ok((key, mtime))
+}
+
+fn parse_line(line: &str) -> Option<(u64, Verdict)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let offset = u64::from_str_radix(fields.first()?, 16).ok()?;
+    match fields[1..] {
+        ["hook", k] => Some((offset, Verdict::Hook(k.parse().ok()?))),
+        ["unpatchable"] => Some((offset, Verdict::Unpatchable)),
+        _ => None,
+    }
+}
+
+fn format_line(offset: u64, verdict: Verdict) -> String {
+    match verdict {
+        Verdict::Hook(k) => format!("{:x} hook {}\n", offset, k),
+        Verdict::Unpatchable => format!("{:x} unpatchable\n", offset),
+    }
+}
+
+fn read_sites(path: &Path) -> HashMap<u64, Verdict> {
+    let mut text = String::new();
+    if File::open(path).and_then(|mut fp| fp.read_to_string(&mut text)).is_err() {
+        return HashMap::new();
+    }
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(parse_line)
+        .collect()
+}
+
+// run `f` with the (up to date) cached sites of `file`.
+fn with_cached_file<T, F>(dir: &Path, file: &Path, f: F) -> Result<T>
+where
+    F: FnOnce(&mut CachedFile) -> Result<T>,
+{
+    let mut cache = PATCH_CACHE.lock().unwrap();
+    let mtime = std::fs::metadata(file)?.modified()?;
+    if cache.get(file).map(|cached| cached.mtime) != Some(mtime) {
+        let (key, mtime) = cache_key(file)?;
+        let sites = read_sites(&hooks_dir(dir).join(&key));
+        cache.insert(file.to_path_buf(), CachedFile { key, mtime, sites });
+    }
+    f(cache.get_mut(file).unwrap())
+}
+
+/// cached verdicts of sites of `file`, as `(file offset, verdict)`.
+pub fn lookup(dir: &Path, file: &Path) -> Result<Vec<(u64, Verdict)>> {
+    with_cached_file(dir, file, |cached| {
+        Ok(cached.sites.iter().map(|(offset, verdict)| (*offset, *verdict)).collect())
+    })
+}
+
+/// record the verdict of the site at `offset` of `file`, the cache file
+/// is appended only if the verdict is new.
+pub fn record(dir: &Path, file: &Path, offset: u64, verdict: Verdict) -> Result<()> {
+    with_cached_file(dir, file, |cached| {
+        if cached.sites.get(&offset) == Some(&verdict) {
+            return Ok(());
+        }
+        let dir = hooks_dir(dir);
+        std::fs::create_dir_all(&dir)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(&cached.key))?
+            .write_all(format_line(offset, verdict).as_bytes())?;
+        cached.sites.insert(offset, verdict);
+        Ok(())
+    })
+}
+
+/// the file mapped at `addr` and the offset of `addr` in the file.
+pub fn file_offset(maps: &[ProcMapsEntry], addr: u64) -> Option<(PathBuf, u64)> {
+    let mapping = maps.iter().find(|e| addr >= e.base() && addr < e.end())?;
+    let file = mapping.filename().filter(|file| file.is_absolute())?;
+    Some((file.clone(), addr - mapping.base() + mapping.offset()))
+}
+
+/// the runtime address of `offset` of the file mapped by `mapping`, if
+/// mapped.
+pub fn mapped_address(mapping: &ProcMapsEntry, offset: u64) -> Option<u64> {
+    if offset >= mapping.offset() && offset - mapping.offset() < mapping.size() as u64 {
+        Some(mapping.base() + offset - mapping.offset())
+    } else {
+        None
+    }
+}
+
+#[test]
+fn patch_cache_sanity_check() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("systrace-patch-cache-{}", std::process::id()));
+    let exe = std::env::current_exe()?;
+    record(&dir, &exe, 0x1000, Verdict::Hook(3))?;
+    record(&dir, &exe, 0x2000, Verdict::Unpatchable)?;
+    record(&dir, &exe, 0x1000, Verdict::Unpatchable)?;
+    let (key, _) = cache_key(&exe)?;
+    let sites = read_sites(&hooks_dir(&dir).join(key));
+    assert_eq!(sites.len(), 2);
+    assert_eq!(sites.get(&0x1000), Some(&Verdict::Unpatchable));
+    assert_eq!(sites.get(&0x2000), Some(&Verdict::Unpatchable));
+    assert_eq!(parse_line("f43fa hook 3"), Some((0xf43fa, Verdict::Hook(3))));
+    assert_eq!(parse_line("f43fa hook"), None);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,243 @@
+//! syscall sites seen by the tracer (`--patch-report`)
+//!
+//! every syscall trapped by seccomp is recorded by its site: the file it
+//! is mapped from and the offset in the file (or the address, for
+//! anonymous memory), so that sites are shared by processes. a site has
+//! a hit count, the syscalls seen and the outcome of the last patch
+//! attempt, sites patched ahead of time (i.e.: `--eager-patching`) have
+//! no hits. the report is written when the tracer exits, most hit sites
+//! first, along with the bytes following the `syscall`, which is what a
+//! new hook pattern (see `trampoline/syscall_hooks.def`) has to match.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::nr::SyscallNo;
+use crate::proc::*;
+use crate::symbols;
+
+/// why a syscall site is not patched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Failure {
+    /// no hook matches, and instructions cannot be relocated.
+    NoHook,
+    /// a branch could land inside the patch.
+    BranchInto,
+    /// the task is in `vfork`.
+    Vfork,
+    /// other threads are in the syscall, the write lock cannot be taken.
+    LockContention,
+    /// no stub page can be allocated (or has room) within +/- 2GB.
+    StubAllocation,
+    /// `libsystrace-trampoline.so` is not loaded (yet).
+    NotLoaded,
+    /// never patched by design, i.e.: `exit_group`.
+    Never,
+    /// the code can be rewritten without the tracer noticing: it is
+    /// writable, or shared (i.e.: a JIT writing through another mapping).
+    WritableCode,
+    /// found unpatchable before, possibly by another process.
+    Unpatchable,
+    /// a call into the vsyscall page, there is no code to patch.
+    Vsyscall,
+    Other,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Failure::NoHook => "no matching hook",
+            Failure::BranchInto => "branch into patch",
+            Failure::Vfork => "vfork",
+            Failure::LockContention => "lock contention",
+            Failure::StubAllocation => "stub page allocation",
+            Failure::NotLoaded => "libtrampoline not loaded",
+            Failure::Never => "never patched",
+            Failure::WritableCode => "writable code",
+            Failure::Unpatchable => "known unpatchable",
+            Failure::Vsyscall => "vsyscall",
+            Failure::Other => "other",
+        };
+        f.write_str(s)
+    }
+}
+
+/// a patch failure, carried by `std::io::Error`.
+#[derive(Debug)]
+struct PatchError {
+    failure: Failure,
+    message: String,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// an error for a patch which failed because of `failure`.
+pub fn patch_error<S: Into<String>>(failure: Failure, message: S) -> Error {
+    Error::new(ErrorKind::Other, PatchError { failure, message: message.into() })
+}
+
+/// the failure of a patch `error`, `Other` unless built by `patch_error`.
+pub fn failure_of(error: &Error) -> Failure {
+    error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<PatchError>())
+        .map_or(Failure::Other, |e| e.failure)
+}
+
+/// outcome of the last patch attempt of a site.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// patched with the hook of the given name.
+    Hook(String),
+    /// patched by relocating instructions.
+    Relocated,
+    /// not patched, with the error.
+    Failed(Failure, String),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Hook(name) => write!(f, "patched: hook {}", name),
+            Status::Relocated => write!(f, "patched: relocated"),
+            Status::Failed(failure, message) => write!(f, "not patched: {}: {}", failure, message),
+        }
+    }
+}
+
+/// a site: the file it is mapped from (or `[anon]`), and its offset in
+/// the file (or its address).
+pub type SiteKey = (String, u64);
+
+#[derive(Debug, Clone)]
+struct Site {
+    symbol: Option<String>,
+    following: Vec<u8>,
+    syscalls: BTreeSet<i32>,
+    hits: u64,
+    status: Option<Status>,
+}
+
+lazy_static! {
+    static ref PATCH_REPORT: Mutex<HashMap<SiteKey, Site>> = Mutex::new(HashMap::new());
+}
+
+/// key of the site @address given the memory map `maps` of its process,
+/// and its enclosing function (`name+offset`) if known.
+pub fn site_of(maps: &[ProcMapsEntry], address: u64) -> (SiteKey, Option<String>) {
+    let symbol = symbols::enclosing_function(maps, address)
+        .map(|f| format!("{}+{:#x}", f.name, address - f.start));
+    let mapping = maps.iter().find(|e| address >= e.base() && address < e.end());
+    let key = match mapping.and_then(|e| e.filename().map(|file| (file, e))) {
+        Some((file, e)) if file.is_absolute() => {
+            (file.to_string_lossy().into_owned(), address - e.base() + e.offset())
+        }
+        Some((file, e)) => (file.to_string_lossy().into_owned(), address - e.base()),
+        None => (String::from("[anon]"), address),
+    };
+    (key, symbol)
+}
+
+/// record the site `key` with its `symbol`, a hit of `syscall` (if any),
+/// and the outcome of its patch attempt (if any). `following` gives the
+/// bytes after the `syscall`, read once. a known unpatchable site keeps
+/// the reason it was found unpatchable for.
+pub fn record<F>(key: SiteKey, symbol: Option<String>, syscall: Option<SyscallNo>, status: Option<Status>, following: F)
+where
+    F: FnOnce() -> Vec<u8>,
+{
+    let mut report = PATCH_REPORT.lock().unwrap();
+    let site = report.entry(key).or_insert_with(|| Site {
+        symbol,
+        following: following(),
+        syscalls: BTreeSet::new(),
+        hits: 0,
+        status: None,
+    });
+    if let Some(syscall) = syscall {
+        site.syscalls.insert(syscall as i32);
+        site.hits += 1;
+    }
+    match status {
+        Some(Status::Failed(Failure::Unpatchable, _)) if site.status.is_some() => (),
+        Some(status) => site.status = Some(status),
+        None => (),
+    }
+}
+
+fn format_site(key: &SiteKey, site: &Site) -> String {
+    let syscalls: Vec<String> = site
+        .syscalls
+        .iter()
+        .map(|nr| format!("{}({})", SyscallNo::from(*nr).to_string(), nr))
+        .collect();
+    let following: Vec<String> = site.following.iter().map(|b| format!("{:02x}", b)).collect();
+    let status = site.status.as_ref().map_or(String::from("unknown"), |status| status.to_string());
+    format!(
+        "{}\t{}\t{:#x}\t{}\t{}\t{}\t{}",
+        site.hits,
+        key.0,
+        key.1,
+        site.symbol.as_deref().unwrap_or("?"),
+        if syscalls.is_empty() { String::from("-") } else { syscalls.join(",") },
+        following.join(" "),
+        status
+    )
+}
+
+/// write the report to `path`, sites with most hits first.
+pub fn write_report(path: &Path) -> Result<()> {
+    let report = PATCH_REPORT.lock().unwrap();
+    let mut sites: Vec<(&SiteKey, &Site)> = report.iter().collect();
+    sites.sort_by(|a, b| b.1.hits.cmp(&a.1.hits).then_with(|| a.0.cmp(b.0)));
+    let (patched, failed): (Vec<_>, Vec<_>) = sites
+        .iter()
+        .partition(|(_, site)| !matches!(site.status, Some(Status::Failed(_, _))));
+    let hits = |sites: &[&(&SiteKey, &Site)]| sites.iter().map(|(_, site)| site.hits).sum::<u64>();
+    let mut f = BufWriter::new(File::create(path)?);
+    writeln!(
+        f,
+        "# {} syscall sites: {} patched ({} ptraced hits), {} not patched ({} ptraced hits)",
+        sites.len(),
+        patched.len(),
+        hits(&patched),
+        failed.len(),
+        hits(&failed)
+    )?;
+    writeln!(f, "# hits\tlibrary\toffset\tsymbol\tsyscalls\tbytes after syscall\tstatus")?;
+    for (key, site) in &sites {
+        writeln!(f, "{}", format_site(key, site))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn patch_report_sanity_check() {
+    let key = (String::from("/lib/libc.so.6"), 0x1234);
+    let following = || vec![0x48, 0x3d];
+    record(key.clone(), Some(String::from("read+0xd")), Some(SyscallNo::SYS_read), None, following);
+    let failed = Status::Failed(Failure::NoHook, String::from("cannot be relocated"));
+    record(key.clone(), None, Some(SyscallNo::SYS_read), Some(failed.clone()), Vec::new);
+    let unpatchable = Status::Failed(Failure::Unpatchable, String::new());
+    record(key.clone(), None, Some(SyscallNo::SYS_pread64), Some(unpatchable), Vec::new);
+    let report = PATCH_REPORT.lock().unwrap();
+    let site = report.get(&key).unwrap();
+    assert_eq!(site.hits, 3);
+    assert_eq!(site.status, Some(failed));
+    assert_eq!(
+        format_site(&key, site),
+        "3\t/lib/libc.so.6\t0x1234\tread+0xd\tread(0),pread64(17)\t48 3d\tnot patched: no matching hook: cannot be relocated"
+    );
+    assert_eq!(failure_of(&patch_error(Failure::Vfork, "vfork")), Failure::Vfork);
+    assert_eq!(failure_of(&Error::new(ErrorKind::Other, "vfork")), Failure::Other);
+}
@@ -44,6 +44,15 @@ impl ProcMapsEntry {
     pub fn end(&self) -> u64 {
         self.base + self.size
     }
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+    pub fn prot(&self) -> i32 {
+        self.prot
+    }
+    pub fn flags(&self) -> i32 {
+        self.flags
+    }
     pub fn filename(&self) -> Option<&PathBuf> {
         self.file.iter().next()
     }
@@ -26,6 +26,20 @@ pub struct SyscallStubPage {
     pub allocated: usize,
 }
 
+/// a patched syscall site, `rip` is the address right after the `syscall`
+/// instruction (as reported by seccomp), `original_bytes` are the bytes
+/// overwritten by the patch, starting from `address`: the `syscall`
+/// instruction, or the first relocated instruction (see `x86`).
+/// a thread which did the syscall before it was patched is resumed from
+/// `resume_from` (with the syscall number in `rax`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchedSyscall {
+    pub rip: u64,
+    pub address: u64,
+    pub original_bytes: Vec<u8>,
+    pub resume_from: u64,
+}
+
 #[derive(Debug)]
 pub struct RemotePtr<T> {
     ptr: NonNull<T>,
@@ -134,16 +148,51 @@ pub fn synchronize_from(task: &TracedTask, rip: u64) {
     task.setregs(regs).unwrap();
 }
 
+/// patch syscall site of the current syscall (task must be stopped right
+/// after the `syscall` instruction) to `callq target`, returns the
+/// original bytes.
 pub fn patch_syscall_at(
     task: &mut TracedTask,
     syscall: SyscallNo,
     hook: &hooks::SyscallHook,
     target: u64,
-) {
-    let jmp_insn_size = 5;
+) -> Vec<u8> {
     let regs = task.getregs().unwrap();
-    let resume_from = regs.rip - SYSCALL_INSN_SIZE as u64;
-    let ip = resume_from;
+    let ip = regs.rip - SYSCALL_INSN_SIZE as u64;
+    let (original_bytes, patch_bytes) = patch_syscall_site(task, ip, hook, target);
+    debug!(
+        "{} patched {:?}@{:x} {:02x?} => {:02x?} (callq {:x})",
+        task.gettid(),
+        syscall,
+        ip,
+        original_bytes,
+        patch_bytes,
+        target
+    );
+    let mut new_regs = regs.clone();
+    new_regs.rax = regs.orig_rax; // for our patch, we use rax as syscall no.
+    new_regs.rip = ip; // rewind pc back (-2).
+    task.setregs(new_regs).unwrap();
+    // because we modified tracee's code
+    // we need some kind of synchronization to make sure
+    // the CPU (especially i-cache) noticed the change
+    // hence we set a breakponit at ip (original rip - 2)
+    // to force synchronization.
+    synchronize_from(task, ip);
+    original_bytes
+}
+
+/// patch the syscall site @ip (the `syscall` instruction followed by
+/// `hook`) to `callq target`, registers are left untouched. returns the
+/// original bytes and the patch.
+/// NB: the caller must make sure no thread is running the site.
+pub fn patch_syscall_site(
+    task: &TracedTask,
+    ip: u64,
+    hook: &hooks::SyscallHook,
+    target: u64,
+) -> (Vec<u8>, Vec<u8>) {
+    let jmp_insn_size = 5;
     let rela: i64 = target as i64 - ip as i64 - jmp_insn_size as i64;
     assert!(rela >= -1i64.wrapping_shl(31) && rela < 1i64.wrapping_shl(31));
 
@@ -252,25 +301,7 @@ pub fn patch_syscall_at(
         task.poke_bytes(rptr, chunk).unwrap();
     }
     task.poke_bytes(remote_rip, patch_head.as_slice()).unwrap();
-    debug!(
-        "{} patched {:?}@{:x} {:02x?} => {:02x?} (callq {:x})",
-        task.gettid(),
-        syscall,
-        ip,
-        original_bytes,
-        patch_bytes,
-        target
-    );
-    let mut new_regs = regs.clone();
-    new_regs.rax = regs.orig_rax; // for our patch, we use rax as syscall no.
-    new_regs.rip = ip; // rewind pc back (-2).
-    task.setregs(new_regs).unwrap();
-    // because we modified tracee's code
-    // we need some kind of synchronization to make sure
-    // the CPU (especially i-cache) noticed the change
-    // hence we set a breakponit at ip (original rip - 2)
-    // to force synchronization.
-    synchronize_from(task, ip)
+    (original_bytes, patch_bytes)
 }
 
 // search for spare page(s) which can be allocated (mmap) within the
@@ -400,6 +431,9 @@ pub fn gen_syscall_sequences_at(pid: Pid, page_address: u64) -> nix::Result<()>
      * 35:  5b                   	pop    %rbx
      * 36:  58                   	pop    %rax
      * 37:  cc                   	int3
+     * 38:  0f 05                   syscall                  // forwarded syscall (of the program)
+     * 3a:  c3                      retq
+     * 3b:  90                      nop
      */
     let syscall_stub: &[u64] = &[
         0x90c3050f90c3050f,
@@ -409,6 +443,7 @@ pub fn gen_syscall_sequences_at(pid: Pid, page_address: u64) -> nix::Result<()>
         0xc3585b595aa20f00,
         0x000000b852515350,
         0xcc585b595aa20f00,
+        0x9090909090c3050f,
     ];
     // please note we force each `ptrace::write` to be exactly ptrace_poke (8 bytes a time)
     // instead of using `process_vm_writev`, because this function can be called in
@@ -1,75 +1,52 @@
-use crate::remote::*;
+//! locks of syscall sites, per address space: a thread stopped in a
+//! syscall (not patched yet) holds the read lock of its site (by `rip`),
+//! a thread patching the site must take the write lock, which is not
+//! available while other threads are in the syscall.
+
 use nix::unistd::Pid;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Default)]
+struct SiteLock {
+    readers: BTreeSet<libc::pid_t>,
+    writer: Option<Pid>,
+}
 
+#[derive(Debug, Default)]
 pub struct RemoteRWLock {
-    reader: HashSet<Pid>,
-    writer: HashSet<Pid>,
-    reverse_loopup_table: HashMap<u64, HashSet<Pid>>,
+    sites: BTreeMap<u64, SiteLock>,
 }
 
 impl RemoteRWLock {
     pub fn new() -> Self {
-        RemoteRWLock {
-            reader: HashSet::new(),
-            writer: HashSet::new(),
-            reverse_loopup_table: HashMap::new(),
-        }
+        RemoteRWLock::default()
     }
+
     pub fn try_read_lock(&mut self, tid: Pid, at: u64) -> bool {
-        self.reader.insert(tid);
-        let r = self.reverse_loopup_table.get(&at);
-        if r.is_none() || r.unwrap().len() == 0 {
-            self.writer.remove(&tid);
-            self.reverse_loopup_table
-                .entry(at)
-                .and_modify(|s| {
-                    s.insert(tid);
-                })
-                .or_insert({
-                    let mut s = HashSet::new();
-                    s.insert(tid);
-                    s
-                });
-            true
-        } else {
-            for x in r.unwrap() {
-                if self.writer.contains(&x) {
-                    self.reader.remove(&tid);
-                    return false;
-                }
+        let site = self.sites.entry(at).or_default();
+        match site.writer {
+            Some(writer) if writer != tid => false,
+            _otherwise => {
+                site.writer = None;
+                site.readers.insert(tid.as_raw());
+                true
             }
-            true
         }
     }
+
     pub fn try_read_unlock(&mut self, tid: Pid, at: u64) -> bool {
-        if !self.reader.contains(&tid) {
-            return false;
-        }
-        if self.writer.contains(&tid) {
-            return false;
-        }
-        self.reverse_loopup_table.entry(at).and_modify(|s| {
-            let _ = s.remove(&tid);
-        });
-        true
+        let unlocked = match self.sites.get_mut(&at) {
+            Some(site) => site.readers.remove(&tid.as_raw()),
+            None => false,
+        };
+        self.forget_if_unlocked(at);
+        unlocked
     }
 
     pub fn try_write_lock(&mut self, tid: Pid, at: u64) -> bool {
-        let r = self.reverse_loopup_table.get(&at);
-        if r.is_none() || r.unwrap().len() == 0 {
-            self.writer.insert(tid);
-            self.reader.remove(&tid);
-            self.reverse_loopup_table
-                .entry(at)
-                .and_modify(|s| {
-                    s.insert(tid);
-                })
-                .or_insert({
-                    let mut s = HashSet::new();
-                    s.insert(tid);
-                    s
-                });
+        let site = self.sites.entry(at).or_default();
+        if site.readers.is_empty() && site.writer.is_none() {
+            site.writer = Some(tid);
             true
         } else {
             false
@@ -77,15 +54,37 @@ impl RemoteRWLock {
     }
 
     pub fn try_write_unlock(&mut self, tid: Pid, at: u64) -> bool {
-        if !self.writer.contains(&tid) {
-            return false;
-        }
-        if self.reader.contains(&tid) {
-            return false;
+        let unlocked = match self.sites.get_mut(&at) {
+            Some(site) if site.writer == Some(tid) => {
+                site.writer = None;
+                true
+            }
+            _otherwise => false,
+        };
+        self.forget_if_unlocked(at);
+        unlocked
+    }
+
+    fn forget_if_unlocked(&mut self, at: u64) {
+        if self.sites.get(&at).is_some_and(|site| site.readers.is_empty() && site.writer.is_none()) {
+            self.sites.remove(&at);
         }
-        self.reverse_loopup_table.entry(at).and_modify(|s| {
-            let _ = s.remove(&tid);
-        });
-        true
     }
 }
+
+#[test]
+fn remote_rwlock_sanity_check() {
+    let (a, b) = (Pid::from_raw(1), Pid::from_raw(2));
+    let mut lock = RemoteRWLock::new();
+    assert!(lock.try_read_lock(a, 0x1000));
+    assert!(lock.try_read_lock(b, 0x1000));
+    assert!(lock.try_read_unlock(a, 0x1000));
+    assert!(!lock.try_write_lock(a, 0x1000));
+    assert!(lock.try_read_unlock(b, 0x1000));
+    assert!(lock.try_write_lock(a, 0x1000));
+    assert!(!lock.try_read_lock(b, 0x1000));
+    assert!(lock.try_write_unlock(a, 0x1000));
+    assert!(lock.try_read_lock(b, 0x1000));
+    assert!(lock.try_read_unlock(b, 0x1000));
+    assert!(lock.sites.is_empty());
+}
@@ -0,0 +1,641 @@
+//! sandbox policy (`--policy`)
+//!
+//! a policy lists the syscalls a tracee is allowed to call, everything
+//! else fails with an errno, and is reported to the audit log. one
+//! directive per line, `#` starts a comment:
+//!
+//! ```text
+//! allow read write close fstat mmap brk exit_group
+//! # path prefixes for `open`/`openat`/`creat`
+//! allow openat path=/usr path=/lib path=/tmp/build
+//! # `connect` destinations: `ip:port`, `[ipv6]:port`, `ip` (any port),
+//! # `unix:/path/prefix`, `unix:@abstract`
+//! allow connect addr=127.0.0.1:8080 addr=unix:/run/
+//! # denied syscalls fail with this errno, default EPERM
+//! errno EACCES
+//! # per syscall errno
+//! deny ptrace errno=ENOSYS
+//! # audit log, default is stderr, overridden by `--audit-log`
+//! audit /tmp/audit.log
+//! ```
+//!
+//! denied and constrained syscalls are trapped by seccomp (ahead of the
+//! trampoline's forwarded syscall), so that they are checked by the tracer
+//! whether they are patched or not. syscalls tools run on their own (with
+//! `untraced_syscall`) are not checked. syscalls systrace relies on are
+//! always allowed, see `SANDBOX_REQUIRED_SYSCALLS`, denying them is an
+//! error.
+//!
+//! NB: paths (and allowed prefixes) are resolved as seen from the tracee's
+//! root, symlinks included. they are read from the tracee before the
+//! syscall runs though: another thread could change the path (or a
+//! symlink) meanwhile. the policy guards against mistakes, it is not a
+//! security boundary.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use nix::unistd::Pid;
+
+use crate::bpf;
+use crate::nr::*;
+use crate::remote::*;
+
+// syscalls used by systrace itself (i.e.: by the tracer, through the
+// trampoline's untraced syscall), never denied.
+const SANDBOX_REQUIRED_SYSCALLS: &[SyscallNo] = &[
+    SYS_mmap,
+    SYS_mprotect,
+    SYS_munmap,
+    SYS_rt_sigreturn,
+    SYS_exit,
+    SYS_exit_group,
+];
+
+// syscalls with a path at the given argument.
+const PATH_SYSCALLS: &[(SyscallNo, usize)] = &[(SYS_open, 0), (SYS_creat, 0), (SYS_openat, 1)];
+
+const AT_FDCWD: i32 = -100;
+const PATH_MAX: usize = 4096;
+
+const ERRNO_NAMES: &[(&str, i32)] = &[
+    ("EPERM", libc::EPERM),
+    ("ENOENT", libc::ENOENT),
+    ("EIO", libc::EIO),
+    ("EBADF", libc::EBADF),
+    ("EAGAIN", libc::EAGAIN),
+    ("ENOMEM", libc::ENOMEM),
+    ("EACCES", libc::EACCES),
+    ("EFAULT", libc::EFAULT),
+    ("EBUSY", libc::EBUSY),
+    ("EEXIST", libc::EEXIST),
+    ("EINVAL", libc::EINVAL),
+    ("ENOSPC", libc::ENOSPC),
+    ("EROFS", libc::EROFS),
+    ("ENOSYS", libc::ENOSYS),
+    ("ENOTSUP", libc::ENOTSUP),
+    ("EAFNOSUPPORT", libc::EAFNOSUPPORT),
+    ("ENETUNREACH", libc::ENETUNREACH),
+    ("ECONNREFUSED", libc::ECONNREFUSED),
+];
+
+fn errno_name(errno: i32) -> String {
+    ERRNO_NAMES
+        .iter()
+        .find(|(_, e)| *e == errno)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| errno.to_string())
+}
+
+fn parse_errno(s: &str) -> Result<i32> {
+    ERRNO_NAMES
+        .iter()
+        .find(|(name, _)| *name == s)
+        .map(|(_, e)| *e)
+        .or_else(|| s.parse::<i32>().ok().filter(|e| *e > 0 && *e < 4096))
+        .ok_or(Error::new(ErrorKind::Other, format!("invalid errno: {:?}", s)))
+}
+
+/// constraints of an allowed syscall.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Allowed {
+    /// no constraint.
+    Always,
+    /// `open`/`openat`/`creat` under one of the path prefixes.
+    Paths(Vec<PathBuf>),
+    /// `connect` to one of the addresses.
+    Addrs(Vec<SockAddr>),
+}
+
+/// `connect` destination, as used in policies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SockAddr {
+    /// `ip[:port]` or `[ipv6][:port]`, any port if `None`.
+    Inet(String, Option<u16>),
+    /// `unix:/path`, matched as a prefix; abstract sockets start with `@`.
+    Unix(String),
+}
+
+impl SockAddr {
+    fn parse(s: &str) -> Result<Self> {
+        let bad = || Error::new(ErrorKind::Other, format!("invalid address: {:?}", s));
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(SockAddr::Unix(path.to_string()));
+        }
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let k = rest.find(']').ok_or_else(bad)?;
+            let host = rest[..k].parse::<Ipv6Addr>().map_err(|_| bad())?.to_string();
+            match rest[k + 1..].strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None if rest.len() == k + 1 => (host, None),
+                None => return Err(bad()),
+            }
+        } else {
+            match s.find(':') {
+                Some(k) => (s[..k].parse::<Ipv4Addr>().map_err(|_| bad())?.to_string(), Some(&s[k + 1..])),
+                None => (s.parse::<Ipv4Addr>().map_err(|_| bad())?.to_string(), None),
+            }
+        };
+        let port = match port {
+            Some(port) => Some(port.parse::<u16>().map_err(|_| bad())?),
+            None => None,
+        };
+        Ok(SockAddr::Inet(host, port))
+    }
+
+    /// decode a `struct sockaddr`, `None` for families other than
+    /// `AF_UNIX`, `AF_INET` and `AF_INET6`.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 2 {
+            return None;
+        }
+        let family = u16::from_ne_bytes([bytes[0], bytes[1]]) as i32;
+        match family {
+            libc::AF_UNIX => {
+                let path = &bytes[2..];
+                let res = match path.first() {
+                    // abstract socket
+                    Some(0) => format!("@{}", String::from_utf8_lossy(&path[1..])),
+                    _ => String::from_utf8_lossy(path.split(|c| *c == 0).next().unwrap_or(&[])).to_string(),
+                };
+                Some(SockAddr::Unix(res))
+            }
+            libc::AF_INET if bytes.len() >= 8 => {
+                let port = u16::from_be_bytes([bytes[2], bytes[3]]);
+                let ip = Ipv4Addr::new(bytes[4], bytes[5], bytes[6], bytes[7]);
+                Some(SockAddr::Inet(ip.to_string(), Some(port)))
+            }
+            libc::AF_INET6 if bytes.len() >= 24 => {
+                let port = u16::from_be_bytes([bytes[2], bytes[3]]);
+                let mut raw = [0u8; 16];
+                raw.copy_from_slice(&bytes[8..24]);
+                Some(SockAddr::Inet(Ipv6Addr::from(raw).to_string(), Some(port)))
+            }
+            _ => None,
+        }
+    }
+
+    // does policy address `self` allow `addr`
+    fn allows(&self, addr: &SockAddr) -> bool {
+        match (self, addr) {
+            (SockAddr::Inet(host, port), SockAddr::Inet(host1, port1)) => {
+                host == host1 && (port.is_none() || port == port1)
+            }
+            (SockAddr::Unix(prefix), SockAddr::Unix(path)) => path.starts_with(prefix.as_str()),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for SockAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SockAddr::Inet(host, Some(port)) if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            SockAddr::Inet(host, Some(port)) => write!(f, "{}:{}", host, port),
+            SockAddr::Inet(host, None) => write!(f, "{}", host),
+            SockAddr::Unix(path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+/// result of checking a syscall against a policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// denied with errno, and the reason.
+    Deny(i32, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SandboxPolicy {
+    pub allowed: BTreeMap<u32, Allowed>,
+    /// errno of denied syscalls, per syscall (`deny .. errno=`).
+    pub denied: BTreeMap<u32, i32>,
+    pub errno: i32,
+    pub audit_log: Option<PathBuf>,
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        SandboxPolicy {
+            allowed: BTreeMap::new(),
+            denied: BTreeMap::new(),
+            errno: libc::EPERM,
+            audit_log: None,
+        }
+    }
+}
+
+impl SandboxPolicy {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut res = SandboxPolicy::default();
+        for (k, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            res.parse_directive(line)
+                .map_err(|e| Error::new(ErrorKind::Other, format!("line {}: {}", k + 1, e)))?;
+        }
+        Ok(res)
+    }
+
+    pub fn parse_from<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        SandboxPolicy::parse(&text)
+    }
+
+    fn parse_directive(&mut self, line: &str) -> Result<()> {
+        let mut words = line.split_whitespace();
+        let directive = words.next().unwrap_or("");
+        let words: Vec<&str> = words.collect();
+        let bad = || Error::new(ErrorKind::Other, format!("invalid directive: {:?}", line));
+        match directive {
+            "errno" if words.len() == 1 => self.errno = parse_errno(words[0])?,
+            "audit" if words.len() == 1 => self.audit_log = Some(PathBuf::from(words[0])),
+            "deny" | "allow" => {
+                let (constraints, syscalls): (Vec<&str>, Vec<&str>) =
+                    words.iter().partition(|w| w.contains('='));
+                if syscalls.is_empty() {
+                    return Err(bad());
+                }
+                let nrs = syscalls
+                    .iter()
+                    .map(|s| s.parse::<SyscallNo>().map(|nr| nr as u32))
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(|e| Error::new(ErrorKind::Other, e))?;
+                if directive == "deny" {
+                    if let Some(nr) = nrs.iter().find(|nr| SANDBOX_REQUIRED_SYSCALLS.iter().any(|n| *n as u32 == **nr)) {
+                        return Err(Error::new(
+                            ErrorKind::Other,
+                            format!("{} cannot be denied, systrace needs it", SyscallNo::from(*nr as i32).to_string()),
+                        ));
+                    }
+                    let mut errno = None;
+                    for c in constraints {
+                        match c.strip_prefix("errno=") {
+                            Some(e) => errno = Some(parse_errno(e)?),
+                            None => return Err(bad()),
+                        }
+                    }
+                    for nr in nrs {
+                        self.allowed.remove(&nr);
+                        match errno {
+                            Some(errno) => self.denied.insert(nr, errno),
+                            None => self.denied.remove(&nr),
+                        };
+                    }
+                } else {
+                    for nr in nrs {
+                        self.allow(nr, &constraints)?;
+                    }
+                }
+            }
+            _ => return Err(bad()),
+        }
+        Ok(())
+    }
+
+    fn allow(&mut self, nr: u32, constraints: &[&str]) -> Result<()> {
+        let is_path = PATH_SYSCALLS.iter().any(|(n, _)| *n as u32 == nr);
+        let is_connect = nr == SYS_connect as u32;
+        let mut paths = Vec::new();
+        let mut addrs = Vec::new();
+        for c in constraints {
+            if let (Some(path), true) = (c.strip_prefix("path="), is_path) {
+                paths.push(normalize(Path::new(path)));
+            } else if let (Some(addr), true) = (c.strip_prefix("addr="), is_connect) {
+                addrs.push(SockAddr::parse(addr)?);
+            } else {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("invalid constraint for {}: {:?}", SyscallNo::from(nr as i32).to_string(), c),
+                ));
+            }
+        }
+        self.denied.remove(&nr);
+        let allowed = self.allowed.entry(nr).or_insert_with(|| {
+            if is_connect { Allowed::Addrs(Vec::new()) } else { Allowed::Paths(Vec::new()) }
+        });
+        match allowed {
+            _ if constraints.is_empty() => *allowed = Allowed::Always,
+            Allowed::Always => (),
+            Allowed::Paths(v) => v.extend(paths),
+            Allowed::Addrs(v) => v.extend(addrs),
+        }
+        Ok(())
+    }
+
+    /// allow opening `paths` (i.e.: the preloaded trampoline and tools,
+    /// opened by the dynamic loader), unless already allowed.
+    pub fn allow_preloads(&mut self, paths: &[PathBuf]) {
+        for (nr, _) in PATH_SYSCALLS {
+            let nr = *nr as u32;
+            self.denied.remove(&nr);
+            if let Allowed::Paths(v) = self.allowed.entry(nr).or_insert_with(|| Allowed::Paths(Vec::new())) {
+                v.extend(paths.iter().map(|p| normalize(p)));
+            }
+        }
+    }
+
+    fn is_always_allowed(&self, nr: u32) -> bool {
+        self.allowed.get(&nr) == Some(&Allowed::Always)
+            || SANDBOX_REQUIRED_SYSCALLS.iter().any(|n| *n as u32 == nr)
+    }
+
+    /// seccomp rules trapping syscalls which are not unconditionally
+    /// allowed, to be checked by `check`. must precede any rule allowing
+    /// the trampoline's forwarded syscall.
+    pub fn seccomp_rules(&self) -> Vec<bpf::Rule> {
+        let mut res: Vec<bpf::Rule> = Vec::new();
+        let mut range: Option<(u32, u32)> = None;
+        let nr_max = NR_SYSCALLS as u32;
+        for nr in 0..nr_max {
+            if self.is_always_allowed(nr) {
+                continue;
+            }
+            range = match range {
+                Some((lo, hi)) if hi + 1 == nr => Some((lo, nr)),
+                Some((lo, hi)) => {
+                    res.push(bpf::Rule::syscall_range(lo, hi, bpf::Action::Trace));
+                    Some((nr, nr))
+                }
+                None => Some((nr, nr)),
+            };
+        }
+        if let Some((lo, hi)) = range {
+            res.push(bpf::Rule::syscall_range(lo, hi, bpf::Action::Trace));
+        }
+        // unknown (i.e.: newer) syscalls are denied as well.
+        res.push(bpf::Rule::syscall_range(nr_max, u32::MAX, bpf::Action::Trace));
+        res
+    }
+
+    fn deny(&self, nr: u32, reason: String) -> Verdict {
+        Verdict::Deny(*self.denied.get(&nr).unwrap_or(&self.errno), reason)
+    }
+
+    /// check syscall `nr` of task `pid`, pointers are read from `task`.
+    pub fn check<R: Remote>(&self, task: &R, pid: Pid, nr: u32, args: &[u64; 6]) -> Verdict {
+        if self.is_always_allowed(nr) {
+            return Verdict::Allow;
+        }
+        match self.allowed.get(&nr) {
+            None => self.deny(nr, String::from("syscall not allowed")),
+            Some(Allowed::Always) => Verdict::Allow,
+            Some(Allowed::Paths(prefixes)) => {
+                let path = PATH_SYSCALLS
+                    .iter()
+                    .find(|(n, _)| *n as u32 == nr)
+                    .and_then(|(_, k)| {
+                        let dirfd = if nr == SYS_openat as u32 { args[0] as i32 } else { AT_FDCWD };
+                        let path = read_c_string(task, args[*k]).ok()?;
+                        Some(resolve_path(pid, dirfd, &path))
+                    });
+                match path {
+                    Some(path) if prefixes.iter().any(|p| path.starts_with(resolve_symlinks(pid, p))) => {
+                        Verdict::Allow
+                    }
+                    Some(path) => self.deny(nr, format!("path {:?} not allowed", path)),
+                    None => self.deny(nr, String::from("cannot read path")),
+                }
+            }
+            Some(Allowed::Addrs(addrs)) => {
+                let size = std::cmp::min(args[2] as usize, std::mem::size_of::<libc::sockaddr_storage>());
+                let addr = task
+                    .peek_bytes(RemotePtr::new(args[1] as *mut u8), size)
+                    .ok()
+                    .and_then(|bytes| SockAddr::from_bytes(&bytes));
+                match addr {
+                    Some(addr) if addrs.iter().any(|a| a.allows(&addr)) => Verdict::Allow,
+                    Some(addr) => self.deny(nr, format!("address {} not allowed", addr)),
+                    None => self.deny(nr, String::from("unsupported address")),
+                }
+            }
+        }
+    }
+}
+
+// read a NUL terminated string, without crossing into the next page
+// unless needed, as it may not be mapped.
+fn read_c_string<R: Remote>(task: &R, addr: u64) -> Result<Vec<u8>> {
+    let mut res = Vec::new();
+    let mut at = addr;
+    while res.len() < PATH_MAX {
+        let size = std::cmp::max(16, 0x1000 - (at & 0xfff) as usize);
+        let bytes = task.peek_bytes(RemotePtr::new(at as *mut u8), size)?;
+        if let Some(k) = bytes.iter().position(|c| *c == 0) {
+            res.extend_from_slice(&bytes[..k]);
+            return Ok(res);
+        }
+        res.extend_from_slice(&bytes);
+        at += size as u64;
+    }
+    Err(Error::new(ErrorKind::Other, format!("string @{:x} too long", addr)))
+}
+
+// absolute, normalized path of `path` relative to `dirfd` of `pid`, with
+// symlinks resolved.
+fn resolve_path(pid: Pid, dirfd: i32, path: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    let path = Path::new(std::ffi::OsStr::from_bytes(path));
+    if path.is_absolute() {
+        return resolve_symlinks(pid, &normalize(path));
+    }
+    let base = if dirfd == AT_FDCWD {
+        format!("/proc/{}/cwd", pid)
+    } else {
+        format!("/proc/{}/fd/{}", pid, dirfd)
+    };
+    // unknown base: resolve against `/`, which is unlikely to be allowed.
+    let base = std::fs::read_link(base).unwrap_or_else(|_| PathBuf::from("/"));
+    resolve_symlinks(pid, &normalize(&base.join(path)))
+}
+
+// resolve symlinks of absolute, normalized `path`, as seen from the root
+// of `pid`. trailing components which do not exist (yet, i.e.: `O_CREAT`)
+// are kept as is, `path` is returned if the root of `pid` is unknown.
+fn resolve_symlinks(pid: Pid, path: &Path) -> PathBuf {
+    let root = PathBuf::from(format!("/proc/{}/root", pid));
+    let real_root = match std::fs::canonicalize(&root) {
+        Ok(real_root) => real_root,
+        Err(_) => return path.to_path_buf(),
+    };
+    let mut existing = path.to_path_buf();
+    let mut missing = Vec::new();
+    loop {
+        let relative = existing.strip_prefix("/").unwrap_or(&existing);
+        if let Ok(real) = std::fs::canonicalize(root.join(relative)) {
+            // i.e.: a symlink escaping a chroot, kept as is, as it is
+            // unlikely to be allowed.
+            let mut res = match real.strip_prefix(&real_root) {
+                Ok(inside) => Path::new("/").join(inside),
+                Err(_) => real,
+            };
+            res.extend(missing.iter().rev());
+            return res;
+        }
+        match existing.file_name() {
+            Some(name) => missing.push(name.to_os_string()),
+            None => return path.to_path_buf(),
+        }
+        existing.pop();
+    }
+}
+
+/// normalize `path` lexically: `.` and `..` are removed.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut res = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => res.push(c),
+            Component::ParentDir => {
+                res.pop();
+            }
+            _ => (),
+        }
+    }
+    res
+}
+
+lazy_static! {
+    static ref AUDIT_LOG: Mutex<Option<File>> = Mutex::new(None);
+}
+
+static NR_DENIED: AtomicUsize = AtomicUsize::new(0);
+
+/// open the audit log, stderr is used if `path` is `None`.
+pub fn open_audit_log(path: Option<&Path>) -> Result<()> {
+    let file = match path {
+        Some(path) => Some(OpenOptions::new().write(true).truncate(true).create(true).open(path)?),
+        None => None,
+    };
+    *AUDIT_LOG.lock().unwrap() = file;
+    Ok(())
+}
+
+fn audit(line: &str) {
+    let mut log = AUDIT_LOG.lock().unwrap();
+    let res = match log.as_mut() {
+        Some(file) => writeln!(file, "{}", line),
+        None => writeln!(std::io::stderr(), "{}", line),
+    };
+    if let Err(e) = res {
+        log::warn!("[sandbox] failed to write audit log: {}", e);
+    }
+}
+
+/// report a denied syscall to the audit log.
+pub fn audit_denied(pid: Pid, nr: u32, args: &[u64; 6], errno: i32, reason: &str) {
+    NR_DENIED.fetch_add(1, Ordering::SeqCst);
+    let name = if nr < NR_SYSCALLS as u32 {
+        SyscallNo::from(nr as i32).to_string()
+    } else {
+        format!("syscall_{}", nr)
+    };
+    audit(&format!(
+        "[audit] pid {}: {}({:#x}, {:#x}, {:#x}, {:#x}, {:#x}, {:#x}) denied with {}: {}",
+        pid, name, args[0], args[1], args[2], args[3], args[4], args[5], errno_name(errno), reason
+    ));
+}
+
+/// summary line, once the tracees exited.
+pub fn audit_summary() {
+    audit(&format!("[audit] {} syscall(s) denied", NR_DENIED.load(Ordering::SeqCst)));
+}
+
+#[test]
+fn sandbox_policy_can_parse() -> Result<()> {
+    let policy = SandboxPolicy::parse(
+        "allow read write # comment\n\
+         allow openat path=/usr path=/tmp/../lib\n\
+         allow connect addr=127.0.0.1:80 addr=[::1] addr=unix:/run/\n\
+         errno EACCES\n\
+         deny ptrace errno=ENOSYS\n\
+         audit /tmp/audit.log\n",
+    )?;
+    assert_eq!(policy.allowed.get(&(SYS_read as u32)), Some(&Allowed::Always));
+    assert_eq!(
+        policy.allowed.get(&(SYS_openat as u32)),
+        Some(&Allowed::Paths(vec![PathBuf::from("/usr"), PathBuf::from("/lib")]))
+    );
+    assert_eq!(
+        policy.allowed.get(&(SYS_connect as u32)),
+        Some(&Allowed::Addrs(vec![
+            SockAddr::Inet(String::from("127.0.0.1"), Some(80)),
+            SockAddr::Inet(String::from("::1"), None),
+            SockAddr::Unix(String::from("/run/")),
+        ]))
+    );
+    assert_eq!(policy.errno, libc::EACCES);
+    assert_eq!(policy.denied.get(&(SYS_ptrace as u32)), Some(&libc::ENOSYS));
+    assert_eq!(policy.audit_log, Some(PathBuf::from("/tmp/audit.log")));
+    assert!(SandboxPolicy::parse("allow read path=/tmp").is_err());
+    assert!(SandboxPolicy::parse("allow connect addr=localhost").is_err());
+    assert!(SandboxPolicy::parse("errno EWHATEVER").is_err());
+    let e = SandboxPolicy::parse("allow read\ndeny write mprotect").unwrap_err();
+    assert_eq!(e.to_string(), "line 2: mprotect cannot be denied, systrace needs it");
+    Ok(())
+}
+
+#[test]
+fn sandbox_policy_seccomp_rules() -> Result<()> {
+    let policy = SandboxPolicy::parse("allow read write getpid\nallow openat path=/tmp")?;
+    let mut filter = bpf::SeccompFilter::new(bpf::Action::Allow);
+    filter.add_rules(policy.seccomp_rules());
+    let prog = filter.compile()?;
+    let action = |nr: u32| {
+        let ret = bpf::simulate(&prog, nr, 0x7000_0002, &[0; 6]).unwrap();
+        bpf::Action::from_seccomp_ret(ret).unwrap()
+    };
+    assert_eq!(action(SYS_read as u32), bpf::Action::Allow);
+    assert_eq!(action(SYS_getpid as u32), bpf::Action::Allow);
+    assert_eq!(action(SYS_mmap as u32), bpf::Action::Allow);
+    assert_eq!(action(SYS_openat as u32), bpf::Action::Trace);
+    assert_eq!(action(SYS_ptrace as u32), bpf::Action::Trace);
+    assert_eq!(action(1000), bpf::Action::Trace);
+    Ok(())
+}
+
+#[test]
+fn sandbox_sockaddr_sanity_check() {
+    let mut inet = vec![0u8; 16];
+    inet[..2].copy_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+    inet[2..4].copy_from_slice(&8080u16.to_be_bytes());
+    inet[4..8].copy_from_slice(&[127, 0, 0, 1]);
+    let addr = SockAddr::from_bytes(&inet).unwrap();
+    assert_eq!(addr.to_string(), "127.0.0.1:8080");
+    assert!(SockAddr::parse("127.0.0.1").unwrap().allows(&addr));
+    assert!(SockAddr::parse("127.0.0.1:8080").unwrap().allows(&addr));
+    assert!(!SockAddr::parse("127.0.0.1:80").unwrap().allows(&addr));
+    let mut unix = vec![0u8; 2];
+    unix[..2].copy_from_slice(&(libc::AF_UNIX as u16).to_ne_bytes());
+    unix.extend_from_slice(b"/run/foo.sock\0");
+    let addr = SockAddr::from_bytes(&unix).unwrap();
+    assert!(SockAddr::parse("unix:/run/").unwrap().allows(&addr));
+    assert!(!SockAddr::parse("unix:/tmp/").unwrap().allows(&addr));
+    assert_eq!(normalize(Path::new("/a/./b/../c")), PathBuf::from("/a/c"));
+}
+
+#[test]
+fn sandbox_resolves_symlinks() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("systrace-sandbox-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("allowed"))?;
+    let _ = std::fs::remove_file(dir.join("allowed/link"));
+    std::os::unix::fs::symlink("/etc/passwd", dir.join("allowed/link"))?;
+    let pid = nix::unistd::getpid();
+    let dir = std::fs::canonicalize(dir)?;
+    assert_eq!(resolve_symlinks(pid, &dir.join("allowed/link")), std::fs::canonicalize("/etc/passwd")?);
+    assert_eq!(resolve_symlinks(pid, &dir.join("allowed/new/file")), dir.join("allowed/new/file"));
+    let path = dir.join("allowed/link");
+    let resolved = resolve_path(pid, AT_FDCWD, path.to_string_lossy().as_bytes());
+    assert!(!resolved.starts_with(resolve_symlinks(pid, &dir.join("allowed"))));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -11,6 +11,7 @@ use std::sync::atomic::{Ordering, AtomicUsize};
 use procfs;
 
 use crate::consts;
+use crate::detach;
 use crate::nr::*;
 use crate::remote;
 use crate::remote::*;
@@ -99,6 +100,10 @@ fn is_ptrace_group_stop(pid: Pid, sig: signal::Signal) -> bool {
 fn ptracer_get_next(tasks: &mut SchedWait) -> Option<TracedTask> {
     let mut retry = true;
     while retry {
+        // tasks are left to `detach`.
+        if detach::detach_requested() {
+            return None;
+        }
         let tid_ = tasks
             .run_queue
             .pop_front()
@@ -120,8 +125,12 @@ fn ptracer_get_next(tasks: &mut SchedWait) -> Option<TracedTask> {
                         Ok(LinuxTaskState::SleepUninterruptible) => {
                             tasks.blocked_queue.push_back(tid);
                         }
-                        Ok(LinuxTaskState::Running) => continue,
-                        Ok(LinuxTaskState::Ptraced) => continue,
+                        Ok(LinuxTaskState::Running) |
+                        Ok(LinuxTaskState::Ptraced) if !detach::detach_requested() => continue,
+                        // a running task may never stop, i.e.: all its
+                        // syscalls are patched.
+                        Ok(LinuxTaskState::Running) |
+                        Ok(LinuxTaskState::Ptraced) => tasks.run_queue.push_back(tid),
                         unknown => panic!("unknown state: {:?}", unknown),
                     }
                     break;
@@ -141,7 +150,8 @@ fn ptracer_get_next(tasks: &mut SchedWait) -> Option<TracedTask> {
                         .expect(&format!("unknown pid {:}", tid));
                     return Some(task);
                 }
-                Ok(WaitStatus::PtraceEvent(_, sig, event)) if sig == signal::SIGTRAP => {
+                Ok(WaitStatus::PtraceEvent(_, sig, event))
+                    if sig == signal::SIGTRAP || event as i64 == PTRACE_EVENT_STOP => {
                     let mut task = tasks
                         .tasks
                         .remove(&tid)
@@ -187,6 +197,13 @@ fn ptracer_get_next(tasks: &mut SchedWait) -> Option<TracedTask> {
     None
 }
 
+// add tasks spawned by tool callbacks of `task`, see `TracedTask::spawned`.
+fn add_spawned(sched: &mut SchedWait, task: &mut TracedTask) {
+    for child in task.spawned.drain(..) {
+        sched.add(child);
+    }
+}
+
 pub fn sched_wait_event_loop(sched: &mut SchedWait) -> i32 {
     let mut exit_code = 0i32;
     while let Some(task) = sched.next() {
@@ -194,13 +211,17 @@ pub fn sched_wait_event_loop(sched: &mut SchedWait) -> i32 {
         let run_result = task.run();
         match run_result {
             Ok(RunTask::Exited(_code)) => exit_code = _code,
-            Ok(RunTask::Blocked(task1)) => {
+            Ok(RunTask::Blocked(mut task1)) => {
+                add_spawned(sched, &mut task1);
                 sched.add_blocked(task1);
             }
-            Ok(RunTask::Runnable(task1)) => {
+            Ok(RunTask::Runnable(mut task1)) => {
+                add_spawned(sched, &mut task1);
                 sched.add_and_schedule(task1);
             }
-            Ok(RunTask::Forked(parent, child)) => {
+            Ok(RunTask::Forked(mut parent, mut child)) => {
+                add_spawned(sched, &mut child);
+                add_spawned(sched, &mut parent);
                 sched.add(child);
                 sched.add_and_schedule(parent);
             }
@@ -256,5 +277,9 @@ pub fn sched_wait_event_loop(sched: &mut SchedWait) -> i32 {
             }
         }
     }
+    if detach::detach_requested() {
+        let tasks: Vec<TracedTask> = sched.tasks.drain().map(|(_, task)| task).collect();
+        detach::detach(tasks);
+    }
     exit_code
 }
@@ -10,6 +10,7 @@ pub struct SystraceState {
     pub nr_syscalls_ptraced: AtomicUsize,
     pub nr_syscalls_patched: AtomicUsize,
     pub nr_syscalls_captured: AtomicUsize,
+    pub nr_syscalls_routed: AtomicUsize,
     pub nr_read_retries: AtomicUsize,
     pub nr_write_retries: AtomicUsize,
     pub nr_getrandom: AtomicUsize,
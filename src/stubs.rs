@@ -6,6 +6,7 @@ use std::path::PathBuf;
 
 use crate::consts;
 use crate::hooks;
+use crate::x86;
 
 // jmp *0x0(pc)
 // .qword offset_64bit.
@@ -64,3 +65,63 @@ pub fn gen_extended_jump_stubs(hooks: &Vec<hooks::SyscallHook>, addr: u64) -> Ve
     });
     res
 }
+
+// lea -0x80(%rsp),%rsp: skip the red zone, without changing flags.
+const X64_LEA_RSP_SKIP_RED_ZONE: &[u8] = &[0x48, 0x8d, 0x64, 0x24, 0x80];
+// lea 0x80(%rsp),%rsp
+const X64_LEA_RSP_RESTORE_RED_ZONE: &[u8] = &[0x48, 0x8d, 0xa4, 0x24, 0x80, 0x00, 0x00, 0x00];
+// callq *0x0(pc)
+const X64_CALL_ABS_PC_RELA: &[u8] = &[0xff, 0x15, 0x00, 0x00, 0x00, 0x00];
+
+/// per-site stub for a syscall site which matches no syscall hook, placed
+/// at `addr`, the instructions covered by `site` are relocated around a
+/// call to `trampoline` (`_syscall_hook_trampoline`):
+///     <relocated instructions before syscall>
+///     lea -0x80(%rsp),%rsp
+///     callq *trampoline(%rip)
+///     lea 0x80(%rsp),%rsp
+///     <relocated instructions after syscall>
+///     jmp *0(%rip)
+///     .qword site_end
+/// trampoline:
+///     .qword _syscall_hook_trampoline
+/// returns the stub, and the offset where the syscall is done.
+pub fn gen_relocated_stub(site: &x86::SyscallSite, addr: u64, trampoline: u64) -> Result<(Vec<u8>, usize)> {
+    let mut res: Vec<u8> = Vec::new();
+    for (from, bytes) in &site.before {
+        let mut insn = x86::relocate(bytes, *from, addr + res.len() as u64)?;
+        res.append(&mut insn);
+    }
+    let syscall_offset = res.len();
+    res.extend_from_slice(X64_LEA_RSP_SKIP_RED_ZONE);
+    let call_offset = res.len();
+    res.extend_from_slice(X64_CALL_ABS_PC_RELA);
+    res.extend_from_slice(X64_LEA_RSP_RESTORE_RED_ZONE);
+    for (from, bytes) in &site.after {
+        let mut insn = x86::relocate(bytes, *from, addr + res.len() as u64)?;
+        res.append(&mut insn);
+    }
+    res.append(&mut gen_extended_jump(site.address + site.len as u64));
+    while !res.len().is_multiple_of(std::mem::size_of::<u64>()) {
+        res.push(0xcc);
+    }
+    let rela = (res.len() - call_offset - X64_CALL_ABS_PC_RELA.len()) as u32;
+    res[call_offset + 2..call_offset + 6].copy_from_slice(&rela.to_le_bytes());
+    res.extend_from_slice(&trampoline.to_le_bytes());
+    Ok((res, syscall_offset))
+}
+
+#[test]
+fn relocated_stub_sanity() {
+    // mov $0x27,%eax; syscall
+    let code = [0xb8, 0x27, 0, 0, 0, 0x0f, 0x05];
+    let site = x86::cover_syscall_site(&code, 0x1000, 0x1005).unwrap();
+    let (stub, syscall_offset) = gen_relocated_stub(&site, 0x2000, 0x12345678).unwrap();
+    assert_eq!(syscall_offset, 5);
+    assert_eq!(&stub[..5], &code[..5]);
+    assert_eq!(stub.len() % 8, 0);
+    assert_eq!(&stub[stub.len() - 8..], &0x12345678u64.to_le_bytes());
+    // the jump back
+    let jump = &stub[syscall_offset + 19..syscall_offset + 33];
+    assert_eq!(jump, gen_extended_jump(0x1007).as_slice());
+}
@@ -0,0 +1,182 @@
+//! function symbols of ELF files mapped by tracees
+//!
+//! symbols are read from both `.symtab` (if not stripped) and `.dynsym`,
+//! they are cached per file (and its size and mtime), and relocated by the load bias of the file's
+//! mapping in the tracee.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::Elf;
+
+use crate::proc::*;
+
+/// a function of a mapped ELF file, at runtime addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub file: PathBuf,
+    pub start: u64,
+    pub end: u64,
+}
+
+// function symbols `(vaddr, size, name)` sorted by vaddr, and the lowest
+// (page aligned) `PT_LOAD` vaddr.
+struct ElfSymbols {
+    functions: Vec<(u64, u64, String)>,
+    load_vaddr: u64,
+}
+
+lazy_static! {
+    static ref ELF_SYMBOLS: Mutex<HashMap<PathBuf, (FileStamp, Arc<ElfSymbols>)>> = Mutex::new(HashMap::new());
+}
+
+/// size and mtime of a file, what is cached about a file is stale once
+/// its stamp changes (i.e.: the file was rebuilt).
+pub type FileStamp = (u64, SystemTime);
+
+pub fn file_stamp(path: &Path) -> Result<FileStamp> {
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.len(), metadata.modified()?))
+}
+
+fn read_elf_symbols(path: &Path) -> Result<ElfSymbols> {
+    let mut bytes: Vec<u8> = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let elf = Elf::parse(bytes.as_slice()).map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let load_vaddr = elf
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .map(|ph| ph.p_vaddr & !0xfff)
+        .min()
+        .unwrap_or(0);
+    let mut functions: Vec<(u64, u64, String)> = Vec::new();
+    let symtab = elf.syms.iter().map(|sym| (sym, &elf.strtab));
+    let dynsym = elf.dynsyms.iter().map(|sym| (sym, &elf.dynstrtab));
+    for (sym, strtab) in symtab.chain(dynsym) {
+        if sym.is_function() && sym.st_value != 0 && sym.st_size != 0 {
+            functions.push((sym.st_value, sym.st_size, String::from(&strtab[sym.st_name])));
+        }
+    }
+    // stable: `.symtab` names are preferred over aliases from `.dynsym`.
+    functions.sort_by_key(|f| f.0);
+    functions.dedup_by_key(|f| f.0);
+    Ok(ElfSymbols { functions, load_vaddr })
+}
+
+fn elf_symbols(path: &Path) -> Result<Arc<ElfSymbols>> {
+    let mut cache = ELF_SYMBOLS.lock().unwrap();
+    let stamp = file_stamp(path)?;
+    if let Some((cached, symbols)) = cache.get(path) {
+        if *cached == stamp {
+            return Ok(symbols.clone());
+        }
+    }
+    let symbols = Arc::new(read_elf_symbols(path)?);
+    cache.insert(path.to_path_buf(), (stamp, symbols.clone()));
+    Ok(symbols)
+}
+
+// symbols of the ELF file mapped at `addr`, and its load bias, which is
+// given by the mapping of the ELF header.
+fn mapped_elf_symbols(maps: &[ProcMapsEntry], addr: u64) -> Option<(&PathBuf, Arc<ElfSymbols>, u64)> {
+    let file = maps
+        .iter()
+        .find(|e| addr >= e.base() && addr < e.end())?
+        .filename()?;
+    let base = maps
+        .iter()
+        .find(|e| e.filename() == Some(file) && e.offset() == 0)?
+        .base();
+    let symbols = elf_symbols(file).ok()?;
+    let bias = base.wrapping_sub(symbols.load_vaddr);
+    Some((file, symbols, bias))
+}
+
+/// the ELF file mapped at `addr` and its load bias (runtime address minus
+/// link time address), given the memory map `maps` of the process.
+pub fn load_bias(maps: &[ProcMapsEntry], addr: u64) -> Option<(PathBuf, u64)> {
+    let (file, _, bias) = mapped_elf_symbols(maps, addr)?;
+    Some((file.clone(), bias))
+}
+
+/// the function containing `addr`, given the memory map `maps` of the
+/// process, if `addr` is in a mapped ELF file with symbols.
+pub fn enclosing_function(maps: &[ProcMapsEntry], addr: u64) -> Option<Function> {
+    let (file, symbols, bias) = mapped_elf_symbols(maps, addr)?;
+    let vaddr = addr.wrapping_sub(bias);
+    let k = symbols.functions.partition_point(|f| f.0 <= vaddr);
+    let (start, size, name) = symbols.functions.get(k.checked_sub(1)?)?;
+    if vaddr >= start + size {
+        return None;
+    }
+    Some(Function {
+        name: name.clone(),
+        file: file.clone(),
+        start: start.wrapping_add(bias),
+        end: (start + size).wrapping_add(bias),
+    })
+}
+
+/// functions lying entirely within `start..end`, which must be in a single
+/// mapping of an ELF file, sorted by address.
+pub fn functions_within(maps: &[ProcMapsEntry], start: u64, end: u64) -> Vec<Function> {
+    let (file, symbols, bias) = match mapped_elf_symbols(maps, start) {
+        Some(found) => found,
+        None => return Vec::new(),
+    };
+    symbols
+        .functions
+        .iter()
+        .map(|(vaddr, size, name)| (vaddr.wrapping_add(bias), size, name))
+        .filter(|(at, size, _)| *at >= start && at + *size <= end)
+        .map(|(at, size, name)| Function {
+            name: name.clone(),
+            file: file.clone(),
+            start: at,
+            end: at + size,
+        })
+        .collect()
+}
+
+/// functions of the ELF file `path`, at link time addresses, sorted by
+/// address.
+pub fn elf_functions(path: &Path) -> Result<Vec<Function>> {
+    let symbols = elf_symbols(path)?;
+    Ok(symbols
+        .functions
+        .iter()
+        .map(|(vaddr, size, name)| Function {
+            name: name.clone(),
+            file: path.to_path_buf(),
+            start: *vaddr,
+            end: vaddr + size,
+        })
+        .collect())
+}
+
+#[test]
+fn can_find_enclosing_function() {
+    let maps = decode_proc_maps(nix::unistd::getpid()).unwrap();
+    let getpid = libc::getpid as *const () as u64;
+    let function = enclosing_function(&maps, getpid).unwrap();
+    assert_eq!(function.start, getpid);
+    assert!(function.end > function.start);
+    assert_eq!(enclosing_function(&maps, 0), None);
+}
+
+#[test]
+fn can_find_functions_within() {
+    let maps = decode_proc_maps(nix::unistd::getpid()).unwrap();
+    let getpid = libc::getpid as *const () as u64;
+    let text = maps.iter().find(|e| getpid >= e.base() && getpid < e.end()).unwrap();
+    let functions = functions_within(&maps, text.base(), text.end());
+    assert!(functions.iter().any(|f| f.start == getpid));
+    assert!(functions.iter().all(|f| f.start >= text.base() && f.end <= text.end()));
+}
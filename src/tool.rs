@@ -0,0 +1,237 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::PathBuf;
+
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::Elf;
+use nix::unistd::Pid;
+
+use crate::consts;
+use crate::nr::*;
+use crate::proc::*;
+
+// syscalls the tracer needs to see, regardless of tools' interest.
+const TRACER_REQUIRED_SYSCALLS: &[SyscallNo] = &[
+    SYS_exit_group, // `systrace_on_exit`
+];
+
+/// lifecycle callbacks a tool can export, called by the tracer in
+/// tracee's context (see `remote_call_at`):
+///
+/// `void systrace_on_thread_start(void)`: in a new thread, before it runs.
+/// `void systrace_on_fork_child(pid_t parent)`: in a forked child, before it runs.
+/// `void systrace_on_exec(void)`: after `execve`, once the tool is loaded.
+/// `void systrace_on_exit(int status)`: before `exit_group`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ToolHook {
+    ThreadStart,
+    ForkChild,
+    Exec,
+    Exit,
+}
+
+impl ToolHook {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            ToolHook::ThreadStart => "systrace_on_thread_start",
+            ToolHook::ForkChild => "systrace_on_fork_child",
+            ToolHook::Exec => "systrace_on_exec",
+            ToolHook::Exit => "systrace_on_exit",
+        }
+    }
+}
+
+const TOOL_HOOKS: &[ToolHook] = &[
+    ToolHook::ThreadStart,
+    ToolHook::ForkChild,
+    ToolHook::Exec,
+    ToolHook::Exit,
+];
+
+/// entry points exported by a tool (`--tool`), as offsets relative to
+/// the tool's load bias, or runtime addresses once `relocated`.
+///
+/// a tool can export `captured_syscall_enter`, which returns zero to pass
+/// the syscall to the next tool in the chain, or non-zero to short-circuit
+/// it (with `*retval` as the result); a tool only exporting
+/// `captured_syscall` always short-circuits, because it runs the syscall
+/// by itself. `captured_syscall_exit` is called in reverse order, by every
+/// tool which has seen the syscall.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ToolSymbols {
+    pub path: PathBuf,
+    pub captured_syscall_enter: Option<u64>,
+    pub captured_syscall: Option<u64>,
+    pub captured_syscall_exit: Option<u64>,
+    pub hooks: Vec<(ToolHook, u64)>,
+    /// `SYSTRACE_INTEREST` bitmap, `None` if the tool is interested in all
+    /// syscalls.
+    pub interest: Option<[u64; consts::SYSTRACE_INTEREST_WORDS]>,
+    // lowest `PT_LOAD` vaddr, which is mapped at the tool's base address
+    pub load_vaddr: u64,
+}
+
+impl ToolSymbols {
+    pub fn hook(&self, hook: ToolHook) -> Option<u64> {
+        self.hooks.iter().find(|(h, _)| *h == hook).map(|(_, addr)| *addr)
+    }
+
+    /// tool chain entry in the layout expected by libtrampoline:
+    /// `[enter, captured_syscall, exit]`, missing entry points are zero.
+    pub fn chain_entry(&self) -> [u64; 3] {
+        [
+            self.captured_syscall_enter.unwrap_or(0),
+            self.captured_syscall.unwrap_or(0),
+            self.captured_syscall_exit.unwrap_or(0),
+        ]
+    }
+
+    fn relocated(&self, base: u64) -> Self {
+        let at = |sym: u64| base - self.load_vaddr + sym;
+        ToolSymbols {
+            path: self.path.clone(),
+            captured_syscall_enter: self.captured_syscall_enter.map(at),
+            captured_syscall: self.captured_syscall.map(at),
+            captured_syscall_exit: self.captured_syscall_exit.map(at),
+            hooks: self.hooks.iter().map(|(h, sym)| (*h, at(*sym))).collect(),
+            interest: self.interest,
+            load_vaddr: base,
+        }
+    }
+}
+
+/// resolve tool entry points from the dynamic symbol table of `tool`
+pub fn resolve_tool_symbols_from(tool: PathBuf) -> Result<ToolSymbols> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut file = File::open(&tool)?;
+    file.read_to_end(&mut bytes)?;
+    let elf = Elf::parse(bytes.as_slice()).map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let mut res = ToolSymbols {
+        path: tool.clone(),
+        captured_syscall_enter: None,
+        captured_syscall: None,
+        captured_syscall_exit: None,
+        hooks: Vec::new(),
+        interest: None,
+        load_vaddr: elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .map(|ph| ph.p_vaddr & !0xfff)
+            .min()
+            .unwrap_or(0),
+    };
+    for sym in elf.dynsyms.iter() {
+        if sym.st_value == 0 || sym.st_shndx == 0 {
+            continue;
+        }
+        let name = &elf.dynstrtab[sym.st_name];
+        match name {
+            "captured_syscall_enter" => res.captured_syscall_enter = Some(sym.st_value),
+            "captured_syscall" => res.captured_syscall = Some(sym.st_value),
+            "captured_syscall_exit" => res.captured_syscall_exit = Some(sym.st_value),
+            consts::SYSTRACE_INTEREST_SYMBOL => {
+                res.interest = Some(read_interest(&elf, &bytes, sym.st_value)?);
+            }
+            _ => {
+                if let Some(hook) = TOOL_HOOKS.iter().find(|h| h.symbol() == name) {
+                    res.hooks.push((*hook, sym.st_value));
+                }
+            }
+        }
+    }
+    if res.captured_syscall_enter.is_none() && res.captured_syscall.is_none()
+        && res.captured_syscall_exit.is_none() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("{:?}: no captured_syscall* symbols exported", tool),
+        ));
+    }
+    Ok(res)
+}
+
+// read `SYSTRACE_INTEREST` at `vaddr` from the file.
+fn read_interest(elf: &Elf, bytes: &[u8], vaddr: u64) -> Result<[u64; consts::SYSTRACE_INTEREST_WORDS]> {
+    let size = (8 * consts::SYSTRACE_INTEREST_WORDS) as u64;
+    let offset = elf
+        .program_headers
+        .iter()
+        .find(|ph| ph.p_type == PT_LOAD && vaddr >= ph.p_vaddr && vaddr + size <= ph.p_vaddr + ph.p_filesz)
+        .map(|ph| (vaddr - ph.p_vaddr + ph.p_offset) as usize)
+        .ok_or(Error::new(
+            ErrorKind::Other,
+            format!("{} @{:x} not in file", consts::SYSTRACE_INTEREST_SYMBOL, vaddr),
+        ))?;
+    let mut res = [0u64; consts::SYSTRACE_INTEREST_WORDS];
+    for (k, word) in res.iter_mut().enumerate() {
+        let mut raw = [0u8; 8];
+        raw.copy_from_slice(&bytes[offset + 8 * k..offset + 8 * k + 8]);
+        *word = u64::from_le_bytes(raw);
+    }
+    Ok(res)
+}
+
+/// union of tools' syscall interest, `None` if any tool is interested
+/// in all syscalls (or there is no tool at all).
+pub fn syscall_interest(tools: &[ToolSymbols]) -> Option<[u64; consts::SYSTRACE_INTEREST_WORDS]> {
+    if tools.is_empty() {
+        return None;
+    }
+    let mut res = [0u64; consts::SYSTRACE_INTEREST_WORDS];
+    for tool in tools {
+        let interest = tool.interest?;
+        for (x, y) in res.iter_mut().zip(interest.iter()) {
+            *x |= *y;
+        }
+    }
+    Some(res)
+}
+
+/// ranges (inclusive) of syscalls no tool is interested in, hence can be
+/// allowed by seccomp without being traced.
+pub fn uninteresting_syscall_ranges(interest: &[u64; consts::SYSTRACE_INTEREST_WORDS]) -> Vec<(u32, u32)> {
+    let mut interest = *interest;
+    for nr in TRACER_REQUIRED_SYSCALLS {
+        let nr = *nr as usize;
+        interest[nr / 64] |= 1u64 << (nr % 64);
+    }
+    let mut res: Vec<(u32, u32)> = Vec::new();
+    for nr in 0..(64 * consts::SYSTRACE_INTEREST_WORDS) as u32 {
+        if interest[nr as usize / 64] & (1u64 << (nr % 64)) != 0 {
+            continue;
+        }
+        match res.last_mut() {
+            Some(range) if range.1 + 1 == nr => range.1 = nr,
+            _ => res.push((nr, nr)),
+        }
+    }
+    res
+}
+
+#[test]
+fn uninteresting_syscall_ranges_sanity_check() {
+    let mut interest = [0u64; consts::SYSTRACE_INTEREST_WORDS];
+    interest[0] = 1u64 << SYS_read as u64 | 1u64 << SYS_write as u64;
+    let ranges = uninteresting_syscall_ranges(&interest);
+    let exit_group = SYS_exit_group as u32;
+    assert_eq!(ranges[0], (SYS_open as u32, exit_group - 1));
+    assert_eq!(ranges[1], (exit_group + 1, 511));
+    assert!(ranges.iter().all(|(lo, hi)| !(*lo <= SYS_read as u32 && SYS_read as u32 <= *hi)));
+}
+
+/// tools with their runtime addresses in task `pid`, tools not (yet)
+/// mapped are skipped.
+pub fn loaded_tools(pid: Pid, tools: &[ToolSymbols]) -> Result<Vec<ToolSymbols>> {
+    let maps = decode_proc_maps(pid)?;
+    let res = tools
+        .iter()
+        .filter_map(|tool| {
+            let base = maps
+                .iter()
+                .find(|e| e.offset() == 0 && e.filename() == Some(&tool.path))?
+                .base();
+            Some(tool.relocated(base))
+        })
+        .collect();
+    Ok(res)
+}
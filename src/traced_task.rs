@@ -6,7 +6,7 @@ use nix::sys::{ptrace, signal, uio, wait};
 use nix::unistd;
 use nix::unistd::Pid;
 use std::io::{Error, ErrorKind, Result};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::ptr::NonNull;
 use std::rc::Rc;
 use std::cell::{RefCell, RefMut};
@@ -16,6 +16,7 @@ use std::sync::atomic::Ordering;
 
 use crate::consts;
 use crate::consts::*;
+use crate::coredump;
 use crate::hooks;
 use crate::nr::*;
 use crate::proc::*;
@@ -25,17 +26,54 @@ use crate::sched::Scheduler;
 use crate::sched_wait::*;
 use crate::stubs;
 use crate::task::*;
-use crate::remote_rwlock::*;
+use crate::address_space::AddressSpace;
 use crate::vdso;
+use crate::vsyscall;
 use crate::state::SystraceState;
 use crate::state_tracer::*;
+use crate::options::*;
+use crate::patch_cache;
+use crate::patch_report;
+use crate::patch_report::{patch_error, Failure};
+use crate::sandbox;
+use crate::symbols;
+use crate::tool;
+use crate::x86;
+
+/// `PTRACE_EVENT_STOP`, reported instead of `SIGSTOP` for tasks attached
+/// with `PTRACE_SEIZE`.
+pub const PTRACE_EVENT_STOP: i64 = 128;
 
 fn libtrampoline_load_address(pid: unistd::Pid) -> Option<u64> {
     match ptrace::read(
         pid,
         consts::SYSTRACE_LOCAL_SYSCALL_TRAMPOLINE as ptrace::AddressType,
     ) {
-        Ok(addr) if addr != 0 => Some(addr as u64 & !0xfff),
+        Ok(addr) if addr != 0 => Some(addr as u64 - 0x10af),
+        _otherwise => None,
+    }
+}
+
+// entry of `_syscall_hook_trampoline`, available once libtrampoline is
+// initialized.
+fn syscall_trampoline_address(pid: unistd::Pid) -> Option<u64> {
+    match ptrace::read(
+        pid,
+        consts::SYSTRACE_LOCAL_SYSCALL_TRAMPOLINE as ptrace::AddressType,
+    ) {
+        Ok(addr) if addr != 0 => Some(addr as u64),
+        _otherwise => None,
+    }
+}
+
+// entry of `_syscall_hook_trampoline_ptraced`, available once
+// libtrampoline is initialized.
+fn ptraced_trampoline_address(pid: unistd::Pid) -> Option<u64> {
+    match ptrace::read(
+        pid,
+        consts::SYSTRACE_LOCAL_SYSCALL_TRAMPOLINE_PTRACED as ptrace::AddressType,
+    ) {
+        Ok(addr) if addr != 0 => Some(addr as u64),
         _otherwise => None,
     }
 }
@@ -48,6 +86,48 @@ lazy_static! {
         )
         .expect(&format!("unable to load {}", consts::LIBTRAMPOLINE_SO))
     };
+    static ref TOOLS: Vec<tool::ToolSymbols> = {
+        get_systrace_options()
+            .tools
+            .iter()
+            .map(|path| {
+                tool::resolve_tool_symbols_from(path.clone())
+                    .unwrap_or_else(|e| panic!("unable to load tool {:?}: {}", path, e))
+            })
+            .collect()
+    };
+}
+
+// write the tool chain table into the private page, so that
+// libtrampoline dispatches captured syscalls through all tools.
+// must be called after libtrampoline is loaded, at this point all
+// `LD_PRELOAD` libraries are mapped.
+fn install_tool_chain(task: &mut TracedTask) -> Result<()> {
+    let chain = tool::loaded_tools(task.getpid(), &TOOLS)?;
+    if chain.len() != TOOLS.len() {
+        warn!("{} only {} of {} tools are loaded", task.gettid(), chain.len(), TOOLS.len());
+    }
+    for (i, tool) in chain.iter().enumerate() {
+        for (j, addr) in tool.chain_entry().iter().enumerate() {
+            let at = consts::SYSTRACE_TOOL_CHAIN_ADDR + 8 * (3 * i + j) as u64;
+            task.poke(RemotePtr::new(at as *mut u64), addr)?;
+        }
+    }
+    let size = RemotePtr::new(consts::SYSTRACE_LOCAL_TOOL_CHAIN_SIZE as *mut u64);
+    task.poke(size, &(chain.len() as u64))?;
+    task.loaded_tools = Rc::new(chain);
+    Ok(())
+}
+
+// call lifecycle `hook` of all loaded tools (in chain order), in tracee's
+// context. the task must be stopped, but not in a seccomp stop.
+fn notify_tools(task: &mut TracedTask, hook: tool::ToolHook, args: &[i64]) {
+    let tools = task.loaded_tools.clone();
+    for func in tools.iter().filter_map(|tool| tool.hook(hook)) {
+        if let Err(e) = remote_call_at(task, func, args) {
+            warn!("{} failed to call tool {:?} hook @{:x}: {}", task.gettid(), hook, func, e);
+        }
+    }
 }
 
 pub struct TracedTask {
@@ -79,6 +159,16 @@ pub struct TracedTask {
     pub injected_shared_page: Option<u64>,
     pub signal_to_deliver: Option<signal::Signal>,
     pub trampoline_hooks: &'static Vec<hooks::SyscallHook>,
+    /// tools loaded in this address space, with runtime addresses
+    pub loaded_tools: Rc<Vec<tool::ToolSymbols>>,
+    // `systrace_on_exit` already called, `exit_group` is being re-executed.
+    exit_notified: bool,
+    /// tasks forked or cloned by a tool callback (see `remote_call_at`),
+    /// running, to be added to the scheduler along with this task.
+    pub spawned: Vec<TracedTask>,
+    // registers of a child forked by a tool callback, restored once it
+    // returns from the callback (to `SYSTRACE_PRIVATE_BREAKPOINT`).
+    call_saved_regs: Option<libc::user_regs_struct>,
     //
     // Even though the tracee can be multi-threaded
     // the tracer is not. hence no need for locking
@@ -86,11 +176,7 @@ pub struct TracedTask {
     // each process should have its own copy of below data
     // however, threads do resides in the same address space
     // as a result they should share below data as well
-    pub memory_map: Rc<RefCell<Vec<ProcMapsEntry>>>,
-    pub stub_pages: Rc<RefCell<Vec<SyscallStubPage>>>,
-    pub unpatchable_syscalls: Rc<RefCell<Vec<u64>>>,
-    pub patched_syscalls: Rc<RefCell<Vec<u64>>>,
-    pub syscall_patch_lockset: Rc<RefCell<RemoteRWLock>>,
+    pub address_space: Rc<RefCell<AddressSpace>>,
 }
 
 impl std::fmt::Debug for TracedTask {
@@ -113,42 +199,23 @@ impl Task for TracedTask {
             state: TaskState::Ready,
             in_vfork: false,
             seccomp_hook_size: None,
-            memory_map: Rc::new(RefCell::new(Vec::new())),
-            stub_pages: Rc::new(RefCell::new(Vec::new())),
+            address_space: Rc::new(RefCell::new(AddressSpace::new())),
             trampoline_hooks: &SYSCALL_HOOKS,
+            loaded_tools: Rc::new(Vec::new()),
+            exit_notified: false,
+            spawned: Vec::new(),
+            call_saved_regs: None,
             ldpreload_address: libtrampoline_load_address(pid),
             injected_mmap_page: None,
             injected_shared_page: None,
             signal_to_deliver: None,
-            unpatchable_syscalls: Rc::new(RefCell::new(Vec::new())),
-            patched_syscalls: Rc::new(RefCell::new(Vec::new())),
-            syscall_patch_lockset: Rc::new(RefCell::new(RemoteRWLock::new())),
         }
     }
 
     fn cloned(&self) -> Self {
         let pid_raw = self.getevent().expect(&format!("{:?} ptrace getevent", self));
         let child = Pid::from_raw(pid_raw as libc::pid_t);
-        TracedTask {
-            tid: child,
-            pid: self.pid,
-            ppid: self.pid,
-            pgid: self.pgid,
-            systrace_state: get_systrace_state(),
-            state: TaskState::Ready,
-            in_vfork: false,
-            seccomp_hook_size: None,
-            memory_map: self.memory_map.clone(),
-            stub_pages: self.stub_pages.clone(),
-            trampoline_hooks: &SYSCALL_HOOKS,
-            ldpreload_address: self.ldpreload_address.clone(),
-            injected_mmap_page: self.injected_mmap_page.clone(),
-            injected_shared_page: self.injected_shared_page.clone(),
-            signal_to_deliver: None,
-            unpatchable_syscalls: self.unpatchable_syscalls.clone(),
-            patched_syscalls: self.patched_syscalls.clone(),
-            syscall_patch_lockset: self.syscall_patch_lockset.clone(),
-        }
+        self.thread(child)
     }
 
     fn forked(&self) -> Self {
@@ -163,28 +230,16 @@ impl Task for TracedTask {
             state: TaskState::Ready,
             in_vfork: false,
             seccomp_hook_size: None,
-            memory_map: {
-                let maps = self.memory_map.borrow().clone();
-                Rc::new(RefCell::new(maps))
-            },
-            stub_pages: {
-                let stubs = self.stub_pages.borrow().clone();
-                Rc::new(RefCell::new(stubs))
-            },
+            address_space: Rc::new(RefCell::new(self.address_space.borrow().fork())),
             trampoline_hooks: &SYSCALL_HOOKS,
+            loaded_tools: self.loaded_tools.clone(),
+            exit_notified: false,
+            spawned: Vec::new(),
+            call_saved_regs: None,
             ldpreload_address: self.ldpreload_address,
             injected_mmap_page: self.injected_mmap_page,
             injected_shared_page: self.injected_shared_page,
             signal_to_deliver: None,
-            unpatchable_syscalls: {
-                let unpatchables = self.unpatchable_syscalls.borrow().clone();
-                Rc::new(RefCell::new(unpatchables))
-            },
-            patched_syscalls: {
-                let patched = self.patched_syscalls.borrow().clone();
-                Rc::new(RefCell::new(patched))
-            },
-            syscall_patch_lockset: Rc::new(RefCell::new(RemoteRWLock::new())),
         }
     }
 
@@ -223,9 +278,27 @@ impl Task for TracedTask {
                 Ok(RunTask::Runnable(task))
             }
             TaskState::Stopped(signal) => {
-                if signal == signal::SIGSEGV || signal == signal::SIGILL {
+                if signal == signal::SIGTRAP && task.call_saved_regs.is_some() {
+                    let regs = task.getregs()?;
+                    if regs.rip == consts::SYSTRACE_PRIVATE_BREAKPOINT + 1 {
+                        let saved_regs = task.call_saved_regs.take().unwrap();
+                        task.setregs(saved_regs)?;
+                        return Ok(RunTask::Runnable(task));
+                    }
+                }
+                // unwinding is costly, and faults may be routine (i.e.: a JVM).
+                if (signal == signal::SIGSEGV || signal == signal::SIGILL) && log::log_enabled!(log::Level::Debug) {
                     show_fault_context(&task, signal);
                 }
+                if let Some(dir) = &get_systrace_options().core_dir {
+                    let si_code = task.getsiginfo().map_or(0, |siginfo| siginfo.si_code);
+                    if coredump::CORE_SIGNALS.contains(&signal) && coredump::is_fatal(task.gettid(), signal, si_code) {
+                        match coredump::write_core(&task, signal, dir) {
+                            Ok(path) => info!("{:?} got {:?}, core dumped to {}", task, signal, path.display()),
+                            Err(e) => warn!("{:?} got {:?}, failed to dump core: {}", task, signal, e),
+                        }
+                    }
+                }
                 task.signal_to_deliver = Some(signal);
                 Ok(RunTask::Runnable(task))
             }
@@ -237,7 +310,8 @@ impl Task for TracedTask {
 }
 
 // TODO: could check whether or not stack is valid
-fn show_stackframe(tid: Pid, stack: u64, top_size: usize, bot_size: usize) -> String{
+// words which look like code addresses are symbolized.
+fn show_stackframe(task: &TracedTask, maps: &[ProcMapsEntry], stack: u64, top_size: usize, bot_size: usize) -> String{
     let mut text = String::new();
     if stack < top_size as u64 {
         return text;
@@ -247,13 +321,22 @@ fn show_stackframe(tid: Pid, stack: u64, top_size: usize, bot_size: usize) -> St
     let mut sp = sp_top;
 
     while sp <= sp_bot {
-        match ptrace::read(tid, sp as ptrace::AddressType) {
+        match ptrace::read(task.gettid(), sp as ptrace::AddressType) {
             Err(_) => break,
             Ok(x)  => {
+                let x = x as u64;
+                let code = maps.iter().any(|e| {
+                    x >= e.base() && x < e.end() && e.prot() & libc::PROT_EXEC != 0
+                });
+                let symbol = if code {
+                    format!(" {}", show_code_address(task, maps, x))
+                } else {
+                    String::new()
+                };
                 if sp == stack {
-                    text += &format!(" => {:12x}: {:16x}\n", sp, x);
+                    text += &format!(" => {:12x}: {:16x}{}\n", sp, x, symbol);
                 } else {
-                    text += &format!("    {:12x}: {:16x}\n", sp, x);
+                    text += &format!("    {:12x}: {:16x}{}\n", sp, x, symbol);
                 }
             }
         }
@@ -262,6 +345,23 @@ fn show_stackframe(tid: Pid, stack: u64, top_size: usize, bot_size: usize) -> St
     text
 }
 
+// `addr` as `library+offset (symbol+offset)`, or as systrace's own code,
+// addresses within a patched syscall site are flagged.
+fn show_code_address(task: &TracedTask, maps: &[ProcMapsEntry], addr: u64) -> String {
+    let private_page = SYSTRACE_PRIVATE_PAGE_OFFSET..SYSTRACE_PRIVATE_PAGE_OFFSET + SYSTRACE_PRIVATE_PAGE_SIZE;
+    let mut res = if private_page.contains(&addr) {
+        format!("systrace private page+{:#x}", addr - SYSTRACE_PRIVATE_PAGE_OFFSET)
+    } else if let Some(page) = task.address_space.borrow().stub_page_of(addr) {
+        format!("systrace stub page@{:x}+{:#x}", page.address, addr - page.address)
+    } else {
+        crate::unwind::symbolize(maps, addr)
+    };
+    if task.address_space.borrow().patched_site_of(addr).is_some() {
+        res += " [patched syscall site]";
+    }
+    res
+}
+
 fn show_user_regs(regs: &libc::user_regs_struct) -> String {
     let mut res = String::new();
 
@@ -290,8 +390,31 @@ fn show_fault_context(task: &TracedTask, sig: signal::Signal) {
            siginfo.si_errno, siginfo.si_code,
            show_user_regs(&regs));
 
+    // the memory map is refreshed if the faulting address is not known,
+    // i.e.: after `mmap`s not tracked by the tracer.
+    let maps: Vec<ProcMapsEntry> = {
+        let known = task.address_space.borrow();
+        if known.mapping_of(regs.rip).is_some() {
+            known.maps().to_vec()
+        } else {
+            decode_proc_maps(tid).unwrap_or_else(|_| known.maps().to_vec())
+        }
+    };
+    debug!("{:?} faulted at {:x} {}", task, regs.rip, show_code_address(task, &maps, regs.rip));
+
+    let peek = |addr: u64| ptrace::read(tid, addr as ptrace::AddressType).ok().map(|x| x as u64);
+    let backtrace: Vec<String> = crate::unwind::unwind(&maps, regs.rip, regs.rsp, regs.rbp, peek)
+        .iter()
+        .enumerate()
+        .map(|(k, frame)| {
+            format!("#{:<2} {:12x} sp {:12x} {} ({:?})",
+                    k, frame.pc, frame.sp, show_code_address(task, &maps, frame.pc), frame.method)
+        })
+        .collect();
+    debug!("backtrace\n{}", backtrace.join("\n"));
+
     debug!("stackframe from rsp@{:x}\n{}", regs.rsp,
-           show_stackframe(tid, regs.rsp, 0x40, 0x80));
+           show_stackframe(task, &maps, regs.rsp, 0x40, 0x80));
 
     if regs.rip != 0 {
         let rptr = RemotePtr::new((regs.rip - 2) as *mut u8);
@@ -310,12 +433,32 @@ fn show_fault_context(task: &TracedTask, sig: signal::Signal) {
 }
 
 impl TracedTask {
+    /// another thread of the same process, sharing the address space.
+    pub fn thread(&self, child: Pid) -> Self {
+        TracedTask {
+            tid: child,
+            pid: self.pid,
+            ppid: self.pid,
+            pgid: self.pgid,
+            systrace_state: get_systrace_state(),
+            state: TaskState::Ready,
+            in_vfork: false,
+            seccomp_hook_size: None,
+            address_space: self.address_space.clone(),
+            trampoline_hooks: &SYSCALL_HOOKS,
+            loaded_tools: self.loaded_tools.clone(),
+            exit_notified: false,
+            spawned: Vec::new(),
+            call_saved_regs: None,
+            ldpreload_address: self.ldpreload_address.clone(),
+            injected_mmap_page: self.injected_mmap_page.clone(),
+            injected_shared_page: self.injected_shared_page.clone(),
+            signal_to_deliver: None,
+        }
+    }
+
     pub fn is_patched_syscall(&self, rip: u64) -> bool {
-        self.patched_syscalls
-            .borrow()
-            .iter()
-            .find(|&&pc| pc == rip)
-            .is_some()
+        self.address_space.borrow().is_patched(rip)
     }
     pub fn task_state_is_seccomp(&self) -> bool {
         self.state == TaskState::Event(7)
@@ -324,20 +467,9 @@ impl TracedTask {
 
 fn check_ref_counters(task: &TracedTask) {
     let expected = 1;
-    let refcnt = Rc::strong_count(&task.unpatchable_syscalls);
-    if refcnt != expected {
-        warn!("{:?} Rc::strong_count(&task.unpatchable_syscalls) expected {} got {}", task, expected, refcnt);
-    }
-    let expected = 1;
-    let refcnt = Rc::strong_count(&task.memory_map);
+    let refcnt = Rc::strong_count(&task.address_space);
     if refcnt != expected {
-        warn!("{:?} Rc::strong_count(&task.memory_map) expected {} got {}", task, expected, refcnt);
-    }
-
-    let expected = 1;
-    let refcnt = Rc::strong_count(&task.stub_pages);
-    if refcnt != expected {
-        warn!("{:?} Rc::strong_count(&task.stub_pages) expected {} got {}", task, expected, refcnt);
+        warn!("{:?} Rc::strong_count(&task.address_space) expected {} got {}", task, expected, refcnt);
     }
 }
 
@@ -347,24 +479,23 @@ fn check_ref_counters(task: &TracedTask) {
 // section: 1.x execve under ptrace.
 fn task_exec_reset(task: &mut TracedTask) {
     task.ldpreload_address = None;
+    task.loaded_tools = Rc::new(Vec::new());
+    task.exit_notified = false;
     task.injected_mmap_page = Some(0x7000_0000);
     task.signal_to_deliver = None;
     task.state = TaskState::Exited(0);
     task.in_vfork = false;
     task.seccomp_hook_size = None;
     check_ref_counters(task);
-    *(task.patched_syscalls.borrow_mut()) = Vec::new();
-    *(task.unpatchable_syscalls.borrow_mut()) = Vec::new();
-    *(task.memory_map.borrow_mut()) = Vec::new();
-    *(task.stub_pages.borrow_mut()) = Vec::new();
-    *(task.syscall_patch_lockset.borrow_mut()) = RemoteRWLock::new();
+    *(task.address_space.borrow_mut()) = AddressSpace::new();
 }
 
 fn update_memory_map(task: &mut TracedTask) {
     // update memory mapping from /proc/[pid]/maps
     // NB: we must use `pid` here.
-    *(task.memory_map.borrow_mut()) = decode_proc_maps(task.getpid())
+    let maps = decode_proc_maps(task.getpid())
         .unwrap_or_else(|_|Vec::new());
+    task.address_space.borrow_mut().set_maps(maps);
 }
 
 fn find_syscall_hook(task: &TracedTask, rip: u64) -> Option<&'static hooks::SyscallHook> {
@@ -383,11 +514,12 @@ fn find_syscall_hook(task: &TracedTask, rip: u64) -> Option<&'static hooks::Sysc
         }
     }
 
-    let mut it = task.trampoline_hooks.iter().filter(|hook| {
-        let sequence: &[u8] = &bytes[0..hook.instructions.len()];
-        sequence == hook.instructions.as_slice()
-    });
-    it.next()
+    match_syscall_hook(task.trampoline_hooks, &bytes)
+}
+
+// the hook matching instructions right after a `syscall`, if any.
+fn match_syscall_hook(hooks: &'static [hooks::SyscallHook], bytes: &[u8]) -> Option<&'static hooks::SyscallHook> {
+    hooks.iter().find(|hook| bytes.starts_with(&hook.instructions))
 }
 
 /// patch a syscall site @rip for a given task.
@@ -400,34 +532,37 @@ pub fn patch_syscall_with(task: &mut TracedTask, hook: &hooks::SyscallHook, sysc
     // is replaced with a new context, hence we don't patch any
     // syscall after vfork.
     if task.in_vfork {
-        return Err(Error::new(ErrorKind::Other, format!("skip syscall patching due to vork")));
+        return Err(patch_error(Failure::Vfork, "skip syscall patching due to vfork"));
     }
 
-    task.ldpreload_address.ok_or(Error::new(
-        ErrorKind::Other,
-        format!("libtrampoline not loaded"),
+    task.ldpreload_address.ok_or_else(|| patch_error(
+        Failure::NotLoaded,
+        "libtrampoline not loaded",
     ))?;
     if task.is_patched_syscall(rip) {
         // already patched
         unreachable!("{:?} already patched?", task);
     }
-    if task
-        .unpatchable_syscalls
-        .borrow()
-        .iter()
-        .find(|&&pc| pc == rip)
-        .is_some()
-    {
-        return Err(Error::new(
-            ErrorKind::Other,
+    if task.address_space.borrow().is_unpatchable(rip) {
+        return Err(patch_error(
+            Failure::Unpatchable,
             format!("process {} syscall at {} is not patchable", task.gettid(), rip),
         ));
     };
+    let maps = decode_proc_maps(task.getpid())?;
+    check_code_is_stable(task, &maps, rip)?;
+    if hook.is_multi {
+        let start = rip - SYSCALL_INSN_SIZE as u64;
+        if let Err(e) = check_patch_window(task, start, rip + hook.instructions.len() as u64) {
+            mark_unpatchable(task, &maps, rip);
+            return Err(e);
+        }
+    }
     let old_regs = ptrace::getregs(task.gettid()).expect("ptrace getregs");
-    task.syscall_patch_lockset.borrow_mut().try_read_unlock(task.gettid(), rip);
-    if !task.syscall_patch_lockset.borrow_mut().try_write_lock(task.gettid(), rip) {
-        return Err(Error::new(
-            ErrorKind::Other,
+    task.address_space.borrow_mut().patch_lock().try_read_unlock(task.gettid(), rip);
+    if !task.address_space.borrow_mut().patch_lock().try_write_lock(task.gettid(), rip) {
+        return Err(patch_error(
+            Failure::LockContention,
             format!("process {} cannot take write lock@{:x}", task.getpid(), rip),
             ));
     }
@@ -444,15 +579,266 @@ pub fn patch_syscall_with(task: &mut TracedTask, hook: &hooks::SyscallHook, sysc
     // PTRACE_EVENT_SECCOMP, as the kernel might allow previous syscall
     // to run through, this could cause chaotic issues if we rely ptrace
     // cont/breakpoint to control tracee's execution.
-    skip_seccomp_syscall(task, old_regs)?;
+    if let Err(e) = skip_seccomp_syscall(task, old_regs) {
+        task.address_space.borrow_mut().patch_lock().try_write_unlock(task.gettid(), rip);
+        return Err(e);
+    }
+
+    let indirect_jump_address = match extended_jump_from_to(task, hook, rip) {
+        Ok(address) => address,
+        Err(e) => {
+            // the syscall is skipped already, restart it, see
+            // `patch_syscall_relocated`.
+            task.address_space.borrow_mut().mark_unpatchable(rip);
+            task.address_space.borrow_mut().patch_lock().try_write_unlock(task.gettid(), rip);
+            restart_skipped_syscall(task, old_regs)?;
+            return Err(e);
+        }
+    };
+    let original_bytes = patch_syscall_at(task, syscall, hook, indirect_jump_address);
+    let address = rip - SYSCALL_INSN_SIZE as u64;
+    task.address_space.borrow_mut().add_patched(PatchedSyscall {
+        rip,
+        address,
+        original_bytes,
+        resume_from: address,
+    });
+    if let Ok(k) = hook_index(task, hook) {
+        record_patch_verdict(task, &maps, address, patch_cache::Verdict::Hook(k));
+    }
+    task.address_space.borrow_mut().patch_lock().try_write_unlock(task.gettid(), rip);
+    Ok(())
+}
 
-    let indirect_jump_address = extended_jump_from_to(task, hook, rip)?;
-    task.patched_syscalls.borrow_mut().push(rip);
-    patch_syscall_at(task, syscall, hook, indirect_jump_address);
-    task.syscall_patch_lockset.borrow_mut().try_write_unlock(task.gettid(), rip);
+// the syscall site (ending) @rip must be in code which cannot be rewritten
+// behind the tracer's back: not writable, nor shared (i.e.: JIT code in a
+// RWX mapping, or written through another mapping of the same memory).
+// such sites are left to ptrace, until the protection of their mapping
+// changes (see `track_address_space_change`).
+fn check_code_is_stable(task: &mut TracedTask, maps: &[ProcMapsEntry], rip: u64) -> Result<()> {
+    let address = rip - SYSCALL_INSN_SIZE as u64;
+    let writable = maps
+        .iter()
+        .find(|e| address >= e.base() && address < e.end())
+        .is_some_and(|e| e.prot() & libc::PROT_WRITE != 0 || e.flags() & libc::MAP_SHARED != 0);
+    if writable {
+        // not recorded to the patch cache, the code is not the file's.
+        task.address_space.borrow_mut().mark_unpatchable(rip);
+        return Err(patch_error(
+            Failure::WritableCode,
+            format!("process {} syscall at {:x} is in writable code", task.gettid(), rip),
+        ));
+    }
     Ok(())
 }
 
+// instructions to be relocated to patch the syscall @address, if any.
+fn relocatable_syscall_site(task: &TracedTask, maps: &[ProcMapsEntry], address: u64) -> Option<x86::SyscallSite> {
+    let mapping = maps.iter().find(|e| address >= e.base() && address < e.end())?;
+    // as many bytes after the syscall are more than enough.
+    let window = x86::RELOCATE_LOOKBEHIND as u64;
+    let start = std::cmp::max(mapping.base(), address.saturating_sub(window));
+    let end = std::cmp::min(mapping.end(), address + window);
+    let code = task.peek_bytes(RemotePtr::new(start as *mut u8), (end - start) as usize).ok()?;
+    x86::cover_syscall_site(&code, start, address)
+}
+
+/// patch a syscall site @rip which matches no syscall hook: instructions
+/// covering the patch (see `x86::cover_syscall_site`) are relocated into a
+/// per-site stub, which calls the trampoline directly, and the site is
+/// patched with a jump to the stub.
+/// returns OK(_) when patch success or Err(_) when patch failed, in which
+/// case the site is not tried again.
+pub fn patch_syscall_relocated(task: &mut TracedTask, syscall: SyscallNo, rip: u64) -> Result<()> {
+    if task.in_vfork {
+        return Err(patch_error(Failure::Vfork, "skip syscall patching due to vfork"));
+    }
+    let trampoline = syscall_trampoline_address(task.gettid()).ok_or_else(|| patch_error(
+        Failure::NotLoaded,
+        format!("{} not loaded", consts::LIBTRAMPOLINE_SO),
+    ))?;
+    let private_page = consts::SYSTRACE_PRIVATE_PAGE_OFFSET
+        ..consts::SYSTRACE_PRIVATE_PAGE_OFFSET + consts::SYSTRACE_PRIVATE_PAGE_SIZE;
+    if private_page.contains(&rip) {
+        return Err(patch_error(
+            Failure::Never,
+            format!("process {} syscall at {:x} is in the private page", task.gettid(), rip),
+        ));
+    }
+    if task.address_space.borrow().is_unpatchable(rip) {
+        return Err(patch_error(
+            Failure::Unpatchable,
+            format!("process {} syscall at {:x} is not patchable", task.gettid(), rip),
+        ));
+    }
+    let maps = decode_proc_maps(task.getpid())?;
+    check_code_is_stable(task, &maps, rip)?;
+    let site = match relocatable_syscall_site(task, &maps, rip - SYSCALL_INSN_SIZE as u64) {
+        Some(site) => site,
+        None => {
+            mark_unpatchable(task, &maps, rip);
+            return Err(patch_error(
+                Failure::NoHook,
+                format!("process {} syscall at {:x} cannot be relocated", task.gettid(), rip),
+            ));
+        }
+    };
+    if let Err(e) = check_patch_window(task, site.address, site.address + site.len as u64) {
+        mark_unpatchable(task, &maps, rip);
+        return Err(e);
+    }
+    let old_regs = ptrace::getregs(task.gettid()).expect("ptrace getregs");
+    task.address_space.borrow_mut().patch_lock().try_read_unlock(task.gettid(), rip);
+    if !task.address_space.borrow_mut().patch_lock().try_write_lock(task.gettid(), rip) {
+        return Err(patch_error(
+            Failure::LockContention,
+            format!("process {} cannot take write lock@{:x}", task.getpid(), rip),
+            ));
+    }
+
+    // see `patch_syscall_with`.
+    skip_seccomp_syscall(task, old_regs)?;
+
+    let res = relocate_syscall_site(task, &site, syscall, rip, trampoline);
+    let mut new_regs = old_regs;
+    new_regs.rax = old_regs.orig_rax;
+    match res {
+        Ok(resume_from) => {
+            new_regs.rip = resume_from;
+            task.setregs(new_regs)?;
+            synchronize_from(task, resume_from);
+        }
+        Err(_) => {
+            // the syscall is skipped already, restart it.
+            task.address_space.borrow_mut().mark_unpatchable(rip);
+            new_regs.orig_rax = -1i64 as u64;
+            new_regs.rip = rip - SYSCALL_INSN_SIZE as u64;
+            task.setregs(new_regs)?;
+        }
+    }
+    task.address_space.borrow_mut().patch_lock().try_write_unlock(task.gettid(), rip);
+    res.map(|_| ())
+}
+
+// write the per-site stub of `site` and patch the site, returns the
+// address in the stub where the syscall is done.
+fn relocate_syscall_site(task: &mut TracedTask, site: &x86::SyscallSite, syscall: SyscallNo, rip: u64, trampoline: u64) -> Result<u64> {
+    let (stub, _) = stubs::gen_relocated_stub(site, site.address, trampoline)?;
+    let stub_address = allocate_relocated_stub(task, site.address, stub.len())?;
+    let (stub, syscall_offset) = stubs::gen_relocated_stub(site, stub_address, trampoline)?;
+    poke_code(task, stub_address, &stub)?;
+
+    let jump = x86::gen_site_jump(site, stub_address).ok_or(Error::new(
+        ErrorKind::Other,
+        format!("stub @{:x} is out of range of {:x}", stub_address, site.address),
+    ))?;
+    let original_bytes = task.peek_bytes(RemotePtr::new(site.address as *mut u8), site.len)?;
+    // the first word is written last, see `patch_syscall_at`.
+    let word = std::mem::size_of::<u64>();
+    if jump.len() > word {
+        poke_code(task, site.address + word as u64, &jump[word..])?;
+    }
+    poke_code(task, site.address, &jump[..std::cmp::min(word, jump.len())])?;
+    debug!(
+        "{} patched {:?}@{:x} {:02x?} => {:02x?} (jmp {:x})",
+        task.gettid(),
+        syscall,
+        site.address,
+        original_bytes,
+        jump,
+        stub_address
+    );
+    let resume_from = stub_address + syscall_offset as u64;
+    task.address_space.borrow_mut().add_patched(PatchedSyscall {
+        rip,
+        address: site.address,
+        original_bytes,
+        resume_from,
+    });
+    Ok(resume_from)
+}
+
+// allocate `size` bytes for a per-site stub, from stub pages within
+// +/- 2GB of @rip, new stub pages are allocated if necessary.
+fn allocate_relocated_stub(task: &mut TracedTask, rip: u64, size: usize) -> Result<u64> {
+    let two_gb = 2u64.wrapping_shl(30);
+    let size = (size + 0xf) & !0xf;
+    let found = task.address_space.borrow().stub_pages().find(|page| {
+        let (start, end) = (page.address, page.address + page.size as u64);
+        let reachable = if end <= rip {
+            rip - start <= two_gb
+        } else if start >= rip {
+            end - rip <= two_gb
+        } else {
+            false
+        };
+        reachable && page.allocated + size <= page.size
+    }).map(|page| page.address);
+    let address = match found {
+        Some(address) => address,
+        None => allocate_extended_jumps(task, rip)
+            .map_err(|e| patch_error(Failure::StubAllocation, e.to_string()))?,
+    };
+    let mut address_space = task.address_space.borrow_mut();
+    let page = address_space.stub_page_mut(address).expect("stub page");
+    if page.allocated + size > page.size {
+        return Err(patch_error(Failure::StubAllocation, format!("no room for stub of size {}", size)));
+    }
+    let at = page.address + page.allocated as u64;
+    page.allocated += size;
+    Ok(at)
+}
+
+// functions larger than this are not scanned by `check_patch_window`.
+const MAX_SCANNED_FUNCTION_SIZE: u64 = 0x10000;
+
+// a patch spanning multiple instructions (`start..end`) breaks the program
+// if a branch lands inside, i.e.: `clock_nanosleep` in some glibc versions,
+// hence branches of the enclosing function (found by its ELF symbol) are
+// checked. the patch is refused when the function is unknown.
+fn check_patch_window(task: &TracedTask, start: u64, end: u64) -> Result<()> {
+    let maps = decode_proc_maps(task.getpid())?;
+    let function = match symbols::enclosing_function(&maps, start) {
+        Some(function) => function,
+        None => {
+            return Err(patch_error(
+                Failure::BranchInto,
+                format!("no known function encloses {:x}..{:x}", start, end),
+            ))
+        }
+    };
+    check_function_patch_window(&function, start, end, |size| {
+        task.peek_bytes(RemotePtr::new(function.start as *mut u8), size)
+    })
+}
+
+/// check no branch of `function` lands inside the patch `start..end`,
+/// the code of `function` is given by `code`, see `check_patch_window`.
+pub fn check_function_patch_window<F>(function: &symbols::Function, start: u64, end: u64, code: F) -> Result<()>
+where
+    F: FnOnce(usize) -> Result<Vec<u8>>,
+{
+    let size = function.end - function.start;
+    if size > MAX_SCANNED_FUNCTION_SIZE {
+        return Err(patch_error(
+            Failure::BranchInto,
+            format!("function {} is too large to be scanned ({} bytes)", function.name, size),
+        ));
+    }
+    let code = code(size as usize)?;
+    match x86::branches_into(&code, function.start, start, end) {
+        None => Err(patch_error(
+            Failure::BranchInto,
+            format!("function {}@{:x} cannot be decoded", function.name, function.start),
+        )),
+        Some(ref branches) if !branches.is_empty() => Err(patch_error(
+            Failure::BranchInto,
+            format!("function {} branches into {:x}..{:x} from {:x?}", function.name, start, end, branches),
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
 fn hook_index(task: &mut TracedTask, curr: &hooks::SyscallHook) -> Result<usize> {
     for (k, hook) in task.trampoline_hooks.iter().enumerate() {
         if hook == curr {
@@ -484,9 +870,9 @@ fn extended_jump_offset_from_stub_page(
 fn extended_jump_from_to(task: &mut TracedTask, hook: &hooks::SyscallHook, rip: u64) -> Result<u64> {
     let two_gb = 2u64.wrapping_shl(30);
     let stub_address = task
-        .stub_pages
+        .address_space
         .borrow()
-        .iter()
+        .stub_pages()
         .find(|page| {
             let (start, end) = (page.address, page.address + page.size as u64);
             if end <= rip {
@@ -501,10 +887,11 @@ fn extended_jump_from_to(task: &mut TracedTask, hook: &hooks::SyscallHook, rip:
     // see: https://doc.rust-lang.org/std/result/enum.Result.html#method.unwrap_or
     // for more details
     let page_address = match stub_address {
-        None => allocate_extended_jumps(task, rip)?,
+        None => allocate_extended_jumps(task, rip)
+            .map_err(|e| patch_error(Failure::StubAllocation, e.to_string()))?,
         Some(x) => x,
     };
-    trace!("=== {:?} extended_jump_from_to rip {:x}, new pa: {:x}, stubs: {:x?}", task, rip, page_address, task.stub_pages.borrow().clone());
+    trace!("=== {:?} extended_jump_from_to rip {:x}, new pa: {:x}, stubs: {:x?}", task, rip, page_address, task.address_space.borrow().stub_pages().collect::<Vec<_>>());
     let offset = extended_jump_offset_from_stub_page(task, hook)?;
     Ok(page_address + offset as u64)
 }
@@ -516,11 +903,13 @@ fn extended_jump_from_to(task: &mut TracedTask, hook: &hooks::SyscallHook, rip:
 fn allocate_extended_jumps(task: &mut TracedTask, rip: u64) -> Result<u64> {
     let size = (stubs::extended_jump_pages() * 0x1000) as i64;
     let at = search_stub_page(task.gettid(), rip, size as usize)? as i64;
+    // NB: not executable until populated, executable `mmap`s could be
+    // traced (see `patches_mapped_text`).
     let allocated_at = task.untraced_syscall(
         SYS_mmap,
         at,
         size,
-        (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as i64,
+        (libc::PROT_READ | libc::PROT_WRITE) as i64,
         (libc::MAP_PRIVATE | libc::MAP_FIXED | libc::MAP_ANONYMOUS) as i64,
         -1i64,
         0,
@@ -532,7 +921,7 @@ fn allocate_extended_jumps(task: &mut TracedTask, rip: u64) -> Result<u64> {
         format!("{} not loaded", consts::LIBTRAMPOLINE_SO),
     ))?;
     let stubs = stubs::gen_extended_jump_stubs(task.trampoline_hooks, preload_address);
-    task.stub_pages.borrow_mut().push(SyscallStubPage {
+    task.address_space.borrow_mut().add_stub_page(SyscallStubPage {
         address: at as u64,
         size: size as usize,
         allocated: stubs.len(),
@@ -707,20 +1096,26 @@ fn remote_do_syscall_at(
     task.setregs(regs)?;
 
     task.resume(None)?;
-    let status = wait::waitpid(tid, None).expect("waitpid");
-    match status {
-        WaitStatus::Stopped(_pid, signal::SIGTRAP) => (),
-        WaitStatus::Stopped(_pid, signal::SIGCHLD) => {
-            task.signal_to_deliver = Some(signal::SIGCHLD)
-        }
-        otherwise => {
-            let regs = task.getregs()?;
-            panic!(
-                "when doing syscall {:?} waitpid {} returned unknown status: {:x?} pc: {:x}",
-                nr, tid, otherwise, regs.rip
-            );
+    loop {
+        let status = wait::waitpid(tid, None).expect("waitpid");
+        match status {
+            WaitStatus::Stopped(_pid, signal::SIGTRAP) => break,
+            WaitStatus::Stopped(_pid, signal::SIGCHLD) => {
+                task.signal_to_deliver = Some(signal::SIGCHLD);
+                break;
+            }
+            // address space changes are always traced, even from our
+            // private page, callers keep their own records up to date.
+            WaitStatus::PtraceEvent(_, _, 7) => task.resume(None)?,
+            otherwise => {
+                let regs = task.getregs()?;
+                panic!(
+                    "when doing syscall {:?} waitpid {} returned unknown status: {:x?} pc: {:x}",
+                    nr, tid, otherwise, regs.rip
+                );
+            }
         }
-    };
+    }
     let newregs = task.getregs()?;
     task.setregs(oldregs)?;
     if newregs.rax as u64 > (-4096i64) as u64 {
@@ -730,6 +1125,84 @@ fn remote_do_syscall_at(
     }
 }
 
+// call function `func` with `args` (up to 6) in tracee's context, the
+// function returns to `SYSTRACE_PRIVATE_BREAKPOINT`. the call frame is
+// pushed below the red zone, registers are restored after the call.
+// syscalls done by the function are let through, signals received are
+// delayed to `signal_to_deliver`. tasks forked or cloned by the function
+// are queued to `spawned`, a forked child returns from the function as
+// well, then resumes from the registers of the caller.
+// - the task must be stopped, but not in a seccomp stop.
+// - the function must not `vfork` (i.e.: `posix_spawn`): the parent is
+//   suspended until the child execs or exits, which cannot happen before
+//   the child is scheduled, after the call returned.
+fn remote_call_at(task: &mut TracedTask, func: u64, args: &[i64]) -> Result<i64> {
+    let tid = task.tid;
+    let oldregs = task.getregs()?;
+    let mut regs = oldregs;
+
+    assert!(args.len() <= 6);
+    let mut argv = [0u64; 6];
+    for (k, arg) in args.iter().enumerate() {
+        argv[k] = *arg as u64;
+    }
+    regs.rdi = argv[0];
+    regs.rsi = argv[1];
+    regs.rdx = argv[2];
+    regs.rcx = argv[3];
+    regs.r8 = argv[4];
+    regs.r9 = argv[5];
+    regs.rax = 0;
+    // not in a syscall, prevent syscall restart.
+    regs.orig_rax = -1i64 as u64;
+    // `rsp + 8` must be 16-byte aligned on function entry.
+    regs.rsp = ((oldregs.rsp - consts::SYSTRACE_RED_ZONE_SIZE) & !0xf) - std::mem::size_of::<u64>() as u64;
+    regs.rip = func;
+    task.poke(RemotePtr::new(regs.rsp as *mut u64), &consts::SYSTRACE_PRIVATE_BREAKPOINT)?;
+    task.setregs(regs)?;
+
+    let mut sig = None;
+    loop {
+        task.resume(sig.take())?;
+        match wait::waitpid(tid, None).map_err(from_nix_error)? {
+            WaitStatus::Stopped(_, signal::SIGTRAP) => {
+                let newregs = task.getregs()?;
+                if newregs.rip == consts::SYSTRACE_PRIVATE_BREAKPOINT + 1 {
+                    task.setregs(oldregs)?;
+                    return Ok(newregs.rax as i64);
+                }
+                sig = Some(signal::SIGTRAP);
+            }
+            WaitStatus::Stopped(_, other) => {
+                if task.signal_to_deliver.is_none() {
+                    task.signal_to_deliver = Some(other);
+                }
+            }
+            WaitStatus::PtraceEvent(_, _, event) if event == libc::PTRACE_EVENT_CLONE
+                || event == libc::PTRACE_EVENT_FORK => {
+                let mut child = if event == libc::PTRACE_EVENT_CLONE {
+                    task.cloned()
+                } else {
+                    task.forked()
+                };
+                wait_sigstop(&child)?;
+                if event == libc::PTRACE_EVENT_FORK {
+                    child.call_saved_regs = Some(oldregs);
+                }
+                child.resume(None)?;
+                task.spawned.push(child);
+            }
+            WaitStatus::PtraceEvent(_, _, _) => (),
+            otherwise => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("calling {:x}: unexpected status {:?}", func, otherwise),
+                ));
+            }
+        }
+    }
+}
+
 fn ptrace_get_stopsig(tid: Pid) -> libc::siginfo_t {
     let si = ptrace::getsiginfo(tid).unwrap();
     si
@@ -806,11 +1279,368 @@ fn handle_syscall_exit(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
             }
         }
     }
-    task.syscall_patch_lockset.borrow_mut().try_read_unlock(tid, rip);
+    task.address_space.borrow_mut().patch_lock().try_read_unlock(tid, rip);
+    track_address_space_change(&mut task, regs);
+    if get_systrace_options().patches_mapped_text() {
+        patch_text_mapped_by_syscall(&mut task, regs);
+    }
     task.state = TaskState::Running;
     Ok(RunTask::Runnable(task))
 }
 
+// patch records are kept valid across changes of the address space by the
+// syscall just returned (traced, or notified by the trampoline if patched,
+// see `systrace_seccomp_filter`):
+// records of unmapped or replaced memory are dropped, so are unpatchable
+// sites of memory whose protection changed (its code could be rewritten).
+// patched sites of memory made writable (or moved) are unpatched, others
+// are still patched, until a `syscall` shows up there again (see
+// `do_ptrace_seccomp`).
+fn track_address_space_change(task: &mut TracedTask, regs: libc::user_regs_struct) {
+    if regs.rax >= -4096i64 as u64 {
+        return;
+    }
+    let pages = |size: u64| (size + 0xfff) & !0xfff;
+    let nr = regs.orig_rax;
+    if nr == SYS_munmap as u64 {
+        invalidate_address_range(task, regs.rdi, regs.rdi + pages(regs.rsi), false);
+    } else if nr == SYS_mmap as u64 && regs.r10 & libc::MAP_FIXED as u64 != 0 {
+        let exec = regs.rdx & libc::PROT_EXEC as u64 != 0;
+        invalidate_address_range(task, regs.rax, regs.rax + pages(regs.rsi), exec);
+    } else if nr == SYS_mremap as u64 {
+        let (old, old_size, new_size) = (regs.rdi, pages(regs.rsi), pages(regs.rdx));
+        if regs.rax != old {
+            // patched code moved along, with callq/jmp to stubs out of range.
+            let moved = std::cmp::min(old_size, new_size);
+            unpatch_syscall_sites(task, old, old + moved, regs.rax.wrapping_sub(old));
+            invalidate_address_range(task, old, old + old_size, false);
+            invalidate_address_range(task, regs.rax, regs.rax + new_size, false);
+        } else if new_size < old_size {
+            invalidate_address_range(task, old + new_size, old + old_size, false);
+        }
+    } else if nr == SYS_mprotect as u64 {
+        let (start, end) = (regs.rdi, regs.rdi + pages(regs.rsi));
+        task.address_space.borrow_mut().forget_unpatchable_within(start, end);
+        // code made writable is about to be rewritten (i.e.: by a JIT).
+        if regs.rdx & libc::PROT_WRITE as u64 != 0 {
+            unpatch_syscall_sites(task, start, end, 0);
+        }
+        let exec = regs.rdx & libc::PROT_EXEC as u64 != 0;
+        if exec || task.address_space.borrow().has_text_within(start, end) {
+            update_memory_map(task);
+        }
+    }
+}
+
+// patched sites within `start..end` are unpatched: their original bytes are
+// restored, `delta` bytes away (i.e.: code moved by `mremap`), and they are
+// forgotten, hence ptraced when they trap again, then patched again if their
+// code still allows it. a thread returning from the hook of such a site
+// would land in the middle of the original instructions, programs are
+// assumed not to rewrite code they are running.
+fn unpatch_syscall_sites(task: &mut TracedTask, start: u64, end: u64, delta: u64) {
+    let sites = task.address_space.borrow_mut().remove_patched_within(start, end);
+    let word = std::mem::size_of::<u64>();
+    for site in &sites {
+        let address = site.address.wrapping_add(delta);
+        let bytes = &site.original_bytes;
+        // the first word is written last, see `patch_syscall_at`.
+        let res = if bytes.len() > word {
+            poke_code(task, address + word as u64, &bytes[word..])
+        } else {
+            Ok(())
+        };
+        if let Err(e) = res.and_then(|_| poke_code(task, address, &bytes[..std::cmp::min(word, bytes.len())])) {
+            warn!("{} failed to unpatch syscall site @{:x}: {}", task.gettid(), address, e);
+        }
+    }
+    if !sites.is_empty() {
+        debug!("{} {:x}..{:x} made writable or moved, unpatched {} syscall sites",
+               task.gettid(), start, end, sites.len());
+    }
+}
+
+// drop patch records of `start..end`, which is unmapped or replaced
+// (by text if `exec`): patched and unpatchable sites, and stub pages.
+// the memory map is refreshed if text is gone or mapped.
+fn invalidate_address_range(task: &mut TracedTask, start: u64, end: u64, exec: bool) {
+    let (patched, unpatchable, pages) = task.address_space.borrow_mut().invalidate_range(start, end);
+    for page in &pages {
+        warn!("{} stub page @{:x} unmapped by the tracee, its patched sites are broken", task.gettid(), page.address);
+    }
+    if patched + unpatchable != 0 {
+        debug!("{} {:x}..{:x} unmapped, dropped {} patched and {} unpatchable syscall sites",
+               task.gettid(), start, end, patched, unpatchable);
+    }
+    if exec || patched != 0 || task.address_space.borrow().has_text_within(start, end) {
+        update_memory_map(task);
+    }
+}
+
+// syscall sites of text mapped by the syscall just returned are patched
+// (see `patches_mapped_text`), no other thread can run the text yet.
+fn patch_text_mapped_by_syscall(task: &mut TracedTask, regs: libc::user_regs_struct) {
+    if regs.orig_rax == SYS_mmap as u64
+        && regs.rdx & libc::PROT_EXEC as u64 != 0
+        && regs.rax < -4096i64 as u64 {
+        patch_mapped_text(task, &[(regs.rax, regs.rax + regs.rsi)]);
+    }
+}
+
+// syscall sites of all text mapped so far are patched (see
+// `patches_mapped_text`) once libtrampoline is loaded (by `LD_PRELOAD`),
+// while the process is still single threaded. processes attached by
+// `--attach` are running already, hence only their new mappings are
+// patched.
+fn patch_all_mapped_text(task: &mut TracedTask) {
+    let ranges: Vec<(u64, u64)> = decode_proc_maps(task.getpid())
+        .unwrap_or_default()
+        .iter()
+        .filter(|e| e.prot() & libc::PROT_EXEC != 0)
+        .map(|e| (e.base(), e.end()))
+        .collect();
+    patch_mapped_text(task, &ranges);
+}
+
+// patch syscall sites of (mapped) executable `ranges`, except those of
+// libtrampoline and tools: sites known by the patch cache first, then
+// sites found by `--eager-patching`.
+fn patch_mapped_text(task: &mut TracedTask, ranges: &[(u64, u64)]) {
+    if task.ldpreload_address.is_none() || task.in_vfork {
+        return;
+    }
+    let options = get_systrace_options();
+    let maps = decode_proc_maps(task.getpid()).unwrap_or_default();
+    let loaded_tools = task.loaded_tools.clone();
+    let is_ours = |file: &PathBuf| {
+        file.file_name() == Some(std::ffi::OsStr::new(consts::LIBTRAMPOLINE_SO))
+            || loaded_tools.iter().any(|tool| &tool.path == file)
+    };
+    for &(start, end) in ranges {
+        let mapping = match maps.iter().find(|e| start >= e.base() && start < e.end()) {
+            Some(mapping) => mapping,
+            None => continue,
+        };
+        match mapping.filename() {
+            Some(file) if file.is_absolute() && !is_ours(file) => {
+                if let Some(dir) = &options.patch_cache {
+                    let patched = patch_cached_syscall_sites(task, &maps, dir, file, mapping, start, end);
+                    debug!("{} patched {} cached syscall sites of {:?}@{:x}", task.gettid(), patched, file, start);
+                }
+                if options.eager_patching {
+                    let patched = patch_syscall_sites_within(task, &maps, start, end);
+                    debug!("{} patched {} syscall sites of {:?}@{:x}", task.gettid(), patched, file, start);
+                }
+            }
+            _otherwise => (),
+        }
+    }
+}
+
+// patch the sites of `file` (mapped by `mapping`) within `start..end`
+// known by the patch cache, known unpatchable sites are not tried again.
+// returns the number of sites patched.
+fn patch_cached_syscall_sites(
+    task: &mut TracedTask,
+    maps: &[ProcMapsEntry],
+    dir: &Path,
+    file: &Path,
+    mapping: &ProcMapsEntry,
+    start: u64,
+    end: u64,
+) -> usize {
+    let sites = match patch_cache::lookup(dir, file) {
+        Ok(sites) => sites,
+        Err(e) => {
+            debug!("{} cannot read patch cache of {:?}: {}", task.gettid(), file, e);
+            return 0;
+        }
+    };
+    let rip = task.getregs().map(|regs| regs.rip).unwrap_or(0);
+    let mut patched = 0;
+    for (offset, verdict) in sites {
+        let address = match patch_cache::mapped_address(mapping, offset) {
+            Some(address) if address >= start && address < end => address,
+            _otherwise => continue,
+        };
+        let syscall_end = address + SYSCALL_INSN_SIZE as u64;
+        let hook = match verdict {
+            patch_cache::Verdict::Unpatchable => {
+                task.address_space.borrow_mut().mark_unpatchable(syscall_end);
+                continue;
+            }
+            patch_cache::Verdict::Hook(k) => match task.trampoline_hooks.get(k) {
+                Some(hook) => hook,
+                None => continue,
+            },
+        };
+        let site_end = syscall_end + hook.instructions.len() as u64;
+        if (rip > address && rip < site_end) || task.is_patched_syscall(syscall_end) || site_end > end {
+            continue;
+        }
+        // the file could have been changed by the program (i.e.: JIT), or
+        // the hooks by a new libtrampoline.
+        let expected = [&[0x0f, 0x05][..], hook.instructions.as_slice()].concat();
+        match task.peek_bytes(RemotePtr::new(address as *mut u8), expected.len()) {
+            Ok(ref bytes) if bytes == &expected => (),
+            _otherwise => continue,
+        }
+        match patch_syscall_site_with(task, maps, hook, address) {
+            Ok(_) => patched += 1,
+            Err(e) => {
+                warn!("{} failed to patch cached syscall site {:x}: {}", task.gettid(), address, e);
+                break;
+            }
+        }
+    }
+    patched
+}
+
+// patch syscall sites matching a hook, in functions (with ELF symbols)
+// within `start..end`, returns the number of sites patched. the caller
+// must make sure no other thread can be running the code, sites the
+// current thread is stopped in are left alone.
+fn patch_syscall_sites_within(task: &mut TracedTask, maps: &[ProcMapsEntry], start: u64, end: u64) -> usize {
+    let rip = task.getregs().map(|regs| regs.rip).unwrap_or(0);
+    let mut patched = 0;
+    for function in symbols::functions_within(maps, start, end) {
+        let size = function.end - function.start;
+        if size > MAX_SCANNED_FUNCTION_SIZE {
+            continue;
+        }
+        // hooks could cover the padding after the function, i.e.: `ret; nop`.
+        let padded = std::cmp::min(end, function.end + 2 * std::mem::size_of::<u64>() as u64);
+        let code = match task.peek_bytes(RemotePtr::new(function.start as *mut u8), (padded - function.start) as usize) {
+            Ok(code) => code,
+            Err(_) => continue,
+        };
+        let (code, following) = code.split_at(size as usize);
+        for offset in x86::syscall_sites(code).unwrap_or_default() {
+            let following = [&code[offset + SYSCALL_INSN_SIZE..], following].concat();
+            let hook = match match_syscall_hook(task.trampoline_hooks, &following) {
+                Some(hook) => hook,
+                None => continue,
+            };
+            let address = function.start + offset as u64;
+            let site_end = address + (SYSCALL_INSN_SIZE + hook.instructions.len()) as u64;
+            if (rip > address && rip < site_end) || task.is_patched_syscall(address + SYSCALL_INSN_SIZE as u64) {
+                continue;
+            }
+            if hook.is_multi
+                && x86::branches_into(code, function.start, address, site_end) != Some(Vec::new()) {
+                trace!("{} syscall site {}@{:x} is not patchable", task.gettid(), function.name, address);
+                mark_unpatchable(task, maps, address + SYSCALL_INSN_SIZE as u64);
+                continue;
+            }
+            if let Err(e) = patch_syscall_site_with(task, maps, hook, address) {
+                warn!("{} failed to patch syscall site {}@{:x}: {}", task.gettid(), function.name, address, e);
+                return patched;
+            }
+            patched += 1;
+        }
+    }
+    patched
+}
+
+// patch the syscall site @address (a `syscall` followed by `hook`) while
+// no thread is running it, the site is recorded in the patch cache.
+fn patch_syscall_site_with(task: &mut TracedTask, maps: &[ProcMapsEntry], hook: &hooks::SyscallHook, address: u64) -> Result<()> {
+    let rip = address + SYSCALL_INSN_SIZE as u64;
+    let target = extended_jump_from_to(task, hook, rip)?;
+    let (original_bytes, patch) = patch_syscall_site(task, address, hook, target);
+    trace!("{} patched syscall site @{:x} {:02x?} => {:02x?}", task.gettid(), address, original_bytes, patch);
+    task.address_space.borrow_mut().add_patched(PatchedSyscall {
+        rip,
+        address,
+        original_bytes,
+        resume_from: address,
+    });
+    let k = hook_index(task, hook)?;
+    record_patch_verdict(task, maps, address, patch_cache::Verdict::Hook(k));
+    report_syscall_site(task, address, None, Some(patch_report::Status::Hook(hook.name.clone())));
+    Ok(())
+}
+
+// patch the syscalls of vdso functions rewritten by `vdso::vdso_patch`, so
+// that they call the trampoline without being trapped first (see
+// `VdsoMode::Trampoline`), once libtrampoline is loaded. no other thread
+// can be running them: the process is single threaded, or stopped.
+fn patch_vdso_syscall_sites(task: &mut TracedTask) {
+    let sites = match vdso::vdso_syscall_sites(task) {
+        Ok(sites) => sites,
+        Err(e) => {
+            warn!("{} cannot find vdso syscall sites: {}", task.gettid(), e);
+            return;
+        }
+    };
+    for address in sites {
+        let rip = address + SYSCALL_INSN_SIZE as u64;
+        if task.is_patched_syscall(rip) {
+            continue;
+        }
+        let following = task
+            .peek_bytes(RemotePtr::new(rip as *mut u8), 2 * std::mem::size_of::<u64>())
+            .unwrap_or_default();
+        let hook = match match_syscall_hook(task.trampoline_hooks, &following) {
+            Some(hook) => hook,
+            None => continue,
+        };
+        // no maps: the vdso is not a file, its sites are not cached.
+        match patch_syscall_site_with(task, &[], hook, address) {
+            Ok(_) => debug!("{} patched vdso syscall site @{:x}", task.gettid(), address),
+            Err(e) => warn!("{} failed to patch vdso syscall site @{:x}: {}", task.gettid(), address, e),
+        }
+    }
+}
+
+// the syscall site (ending) @rip cannot be patched, whichever the task.
+fn mark_unpatchable(task: &mut TracedTask, maps: &[ProcMapsEntry], rip: u64) {
+    task.address_space.borrow_mut().mark_unpatchable(rip);
+    record_patch_verdict(task, maps, rip - SYSCALL_INSN_SIZE as u64, patch_cache::Verdict::Unpatchable);
+}
+
+// record the verdict of the syscall site @address to the patch cache, if
+// enabled. errors are ignored, the cache is merely an optimization.
+fn record_patch_verdict(task: &TracedTask, maps: &[ProcMapsEntry], address: u64, verdict: patch_cache::Verdict) {
+    let dir = match &get_systrace_options().patch_cache {
+        Some(dir) => dir,
+        None => return,
+    };
+    if let Some((file, offset)) = patch_cache::file_offset(maps, address) {
+        if let Err(e) = patch_cache::record(dir, &file, offset, verdict) {
+            debug!("{} cannot record {:?} of {:?}+{:x}: {}", task.gettid(), verdict, file, offset, e);
+        }
+    }
+}
+
+// record the syscall site @address to the patch report, if enabled, with
+// a hit of `syscall` if it is trapped, and the outcome of patching it.
+fn report_syscall_site(task: &mut TracedTask, address: u64, syscall: Option<SyscallNo>, status: Option<patch_report::Status>) {
+    if get_systrace_options().patch_report.is_none() {
+        return;
+    }
+    if task.address_space.borrow().mapping_of(address).is_none() {
+        update_memory_map(task);
+    }
+    let (key, symbol) = patch_report::site_of(task.address_space.borrow().maps(), address);
+    patch_report::record(key, symbol, syscall, status, || {
+        let at = address + SYSCALL_INSN_SIZE as u64;
+        let mut bytes = task
+            .peek_bytes(RemotePtr::new(at as *mut u8), std::mem::size_of::<u64>())
+            .unwrap_or_default();
+        // the site could be patched already (i.e.: just now).
+        for patched in task.address_space.borrow().patched_sites() {
+            let original = patched.address..patched.address + patched.original_bytes.len() as u64;
+            for (k, byte) in bytes.iter_mut().enumerate() {
+                if original.contains(&(at + k as u64)) {
+                    *byte = patched.original_bytes[(at + k as u64 - patched.address) as usize];
+                }
+            }
+        }
+        bytes
+    });
+}
+
 fn handle_ptrace_event(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
     let raw_event = match task.state {
         TaskState::Event(ev) => ev as i64,
@@ -834,6 +1664,9 @@ fn handle_ptrace_event(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
         do_ptrace_event_exit(task)
     } else if raw_event == ptrace::Event::PTRACE_EVENT_SECCOMP as i64 {
         do_ptrace_seccomp(task).and_then(|tsk| Ok(RunTask::Runnable(tsk)))
+    } else if raw_event == PTRACE_EVENT_STOP {
+        // group-stop of a seized task, not honored: resume.
+        Ok(RunTask::Runnable(task))
     } else {
         panic!("unknown ptrace event: {:x}", raw_event);
     }
@@ -848,11 +1681,15 @@ fn handle_ptrace_event(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
 // delivered to the children, causing them to enter signal-delivery-stop after they exit the
 // system call which created them.
 //
+// NB: children of a task attached with `PTRACE_SEIZE` (`--attach`) start
+// with `PTRACE_EVENT_STOP` instead.
 fn wait_sigstop(task: &TracedTask) -> Result<()> {
     let tid = task.gettid();
     match wait::waitpid(Some(tid), None) {
         Ok(WaitStatus::Stopped(new_pid, signal)) if signal == signal::SIGSTOP && new_pid == tid => {
-            task.resume(None)?;
+            Ok(())
+        }
+        Ok(WaitStatus::PtraceEvent(new_pid, _, event)) if event as i64 == PTRACE_EVENT_STOP && new_pid == tid => {
             Ok(())
         }
         _st => Err(Error::new(ErrorKind::Other, format!("expect SIGSTOP, got: {:?}", _st))),
@@ -864,8 +1701,10 @@ fn do_ptrace_vfork_done(task: TracedTask) -> Result<TracedTask> {
 }
 
 fn do_ptrace_clone(task: TracedTask) -> Result<(TracedTask, TracedTask)> {
-    let new_task = task.cloned();
+    let mut new_task = task.cloned();
     wait_sigstop(&new_task)?;
+    notify_tools(&mut new_task, tool::ToolHook::ThreadStart, &[]);
+    new_task.resume(None)?;
 
     let state = get_systrace_state();
     state.nr_syscalls.fetch_add(1, Ordering::SeqCst);
@@ -876,8 +1715,10 @@ fn do_ptrace_clone(task: TracedTask) -> Result<(TracedTask, TracedTask)> {
 }
 
 fn do_ptrace_fork(task: TracedTask) -> Result<(TracedTask, TracedTask)> {
-    let new_task = task.forked();
+    let mut new_task = task.forked();
     wait_sigstop(&new_task)?;
+    notify_tools(&mut new_task, tool::ToolHook::ForkChild, &[task.getpid().as_raw() as i64]);
+    new_task.resume(None)?;
 
     let state = get_systrace_state();
     state.nr_syscalls.fetch_add(1, Ordering::SeqCst);
@@ -890,7 +1731,10 @@ fn do_ptrace_fork(task: TracedTask) -> Result<(TracedTask, TracedTask)> {
 fn do_ptrace_vfork(task: TracedTask) -> Result<(TracedTask, TracedTask)> {
     let mut new_task = task.forked();
     new_task.in_vfork = true;
+    // NB: vfork child shares the parent's address space (and stack),
+    // tool hooks are not called.
     wait_sigstop(&new_task)?;
+    new_task.resume(None)?;
 
     let state = get_systrace_state();
     state.nr_syscalls.fetch_add(1, Ordering::SeqCst);
@@ -916,13 +1760,66 @@ fn do_ptrace_seccomp(mut task: TracedTask) -> Result<TracedTask> {
     let rip = regs.rip;
     let rip_before_syscall = regs.rip - consts::SYSCALL_INSN_SIZE as u64;
     let tid = task.gettid();
+
+    // not a syscall, see `SYSTRACE_SYSCALL_NOTIFY`.
+    let notified = regs.orig_rax == consts::SYSTRACE_SYSCALL_NOTIFY;
+
+    // checked first, denied syscalls could be unknown to `SyscallNo`.
+    if let Some(policy) = get_systrace_options().sandbox.as_ref().filter(|_| !notified) {
+        let nr = regs.orig_rax as u32;
+        let args = [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9];
+        if let sandbox::Verdict::Deny(errno, reason) = policy.check(&task, task.getpid(), nr, &args) {
+            sandbox::audit_denied(task.getpid(), nr, &args, errno, &reason);
+            if vsyscall::is_vsyscall(rip) {
+                vsyscall::skip_vsyscall(&mut task, regs, -(errno as i64))?;
+                task.state = TaskState::Running;
+                return Ok(task);
+            }
+            let mut new_regs = regs;
+            new_regs.rax = -(errno as i64) as u64;
+            skip_seccomp_syscall(&mut task, new_regs)?;
+            return Ok(task);
+        }
+    }
+
+    if let Some(syscall) = vsyscall::vsyscall_at(rip) {
+        do_vsyscall(&mut task, regs, syscall)?;
+        return Ok(task);
+    }
+
+    if task.ldpreload_address.is_none() {
+        task.ldpreload_address = libtrampoline_load_address(tid);
+        if task.ldpreload_address.is_some() {
+            if let Err(e) = install_tool_chain(&mut task) {
+                warn!("{} failed to install tool chain: {}", tid, e);
+            }
+            if libtrampoline_init_pending(&task) {
+                skip_seccomp_syscall(&mut task, regs)?;
+                let patched = init_libtrampoline(&mut task);
+                restart_skipped_syscall(&mut task, regs)?;
+                if patched {
+                    synchronize_from(&task, rip_before_syscall);
+                }
+                return Ok(task);
+            }
+        }
+    }
+
+    if notified {
+        do_trampoline_notify(&mut task, regs)?;
+        return Ok(task);
+    }
+
     let syscall = SyscallNo::from(regs.orig_rax as i32);
     if ev == 0x7fff {
         panic!("unfiltered syscall: {:?}", syscall);
     }
 
-    if task.ldpreload_address.is_none() {
-        task.ldpreload_address = libtrampoline_load_address(tid);
+    if syscall == SYS_exit_group && !task.exit_notified
+        && task.loaded_tools.iter().any(|t| t.hook(tool::ToolHook::Exit).is_some()) {
+        task.exit_notified = true;
+        notify_tools_and_restart(&mut task, regs, tool::ToolHook::Exit, &[regs.rdi as i32 as i64])?;
+        return Ok(task);
     }
     let hook = find_syscall_hook(&task, regs.rip);
     trace!("{} seccomp syscall {:?}@{:x}, hook: {:x?}, preloaded: {}", tid, syscall, rip, hook, task.ldpreload_address.is_some());
@@ -936,26 +1833,71 @@ fn do_ptrace_seccomp(mut task: TracedTask) -> Result<TracedTask> {
     if !is_syscall_insn(tid, rip_before_syscall)? {
         let mut new_regs = regs;
         new_regs.rax = regs.orig_rax;
-        debug!("{} seccomp syscall {:?}@{:x} restart because it is already patched, rax: {:x}", tid, syscall, rip, regs.rax);
+        let resume_from = task
+            .address_space
+            .borrow()
+            .patched_at(rip)
+            .map_or(rip_before_syscall, |patched| patched.resume_from);
+        debug!("{} seccomp syscall {:?}@{:x} restart from {:x} because it is already patched, rax: {:x}", tid, syscall, rip, resume_from, regs.rax);
+        report_syscall_site(&mut task, rip_before_syscall, Some(syscall), None);
         skip_seccomp_syscall(&mut task, new_regs).unwrap();
-        synchronize_from(&mut task, rip_before_syscall);
+        synchronize_from(&mut task, resume_from);
         return Ok(task);
     }
 
-    while !task.syscall_patch_lockset.borrow_mut().try_read_lock(tid, rip) {
+    // a `syscall` at a site recorded as patched: its code was rewritten
+    // (i.e.: after `mprotect`), the record is stale.
+    if task.is_patched_syscall(rip) {
+        debug!("{} seccomp syscall {:?}@{:x} was patched, but code is rewritten", tid, syscall, rip);
+        task.address_space.borrow_mut().remove_patched(rip);
+    }
+
+    while !task.address_space.borrow_mut().patch_lock().try_read_lock(tid, rip) {
         std::thread::sleep(std::time::Duration::from_micros(1000));
     }
 
     let mut patched = false;
-    if !(task.ldpreload_address.is_none() || hook.is_none()) {
-        match patch_syscall_with(&mut task, hook.unwrap(), syscall, rip) {
-            Err(_) => patched = false,
+    // `exit_group` is never patched, so that the tracer always sees it
+    // (for `systrace_on_exit`), it is called only once anyway.
+    let res = if task.ldpreload_address.is_none() {
+        Err(patch_error(Failure::NotLoaded, format!("{} not loaded", consts::LIBTRAMPOLINE_SO)))
+    } else if get_systrace_options().disable_patching {
+        Err(patch_error(Failure::Never, "patching is disabled"))
+    } else if syscall == SYS_exit_group {
+        Err(patch_error(Failure::Never, "exit_group is never patched"))
+    } else {
+        let res = match hook {
+            Some(hook) => patch_syscall_with(&mut task, hook, syscall, rip),
+            // no hook matches, relocate instructions around the syscall.
+            None => patch_syscall_relocated(&mut task, syscall, rip),
+        };
+        match &res {
+            Err(e) => trace!("{} seccomp syscall {:?}@{:x} not patched: {}", tid, syscall, rip, e),
             Ok(_) => patched = true,
         }
+        res
+    };
+    let status = match (res, hook) {
+        (Ok(_), Some(hook)) => patch_report::Status::Hook(hook.name.clone()),
+        (Ok(_), None) => patch_report::Status::Relocated,
+        (Err(e), _) => patch_report::Status::Failed(patch_report::failure_of(&e), e.to_string()),
+    };
+    report_syscall_site(&mut task, rip_before_syscall, Some(syscall), Some(status));
+
+    let mut routed = false;
+    if !patched && get_systrace_options().route_ptraced_syscalls {
+        match route_syscall_to_trampoline(&mut task, regs) {
+            Err(e) => trace!("{} seccomp syscall {:?}@{:x} not routed: {}", tid, syscall, rip, e),
+            Ok(_) => routed = true,
+        }
     }
 
     let state = get_systrace_state();
-    if !patched {
+    if routed {
+        // `nr_syscalls` is updated by the tool, as if it was patched.
+        state.nr_syscalls_ptraced.fetch_add(1, Ordering::SeqCst);
+        state.nr_syscalls_routed.fetch_add(1, Ordering::SeqCst);
+    } else if !patched {
         state.nr_syscalls.fetch_add(1, Ordering::SeqCst);
         state.nr_syscalls_ptraced.fetch_add(1, Ordering::SeqCst);
     } else {
@@ -966,6 +1908,182 @@ fn do_ptrace_seccomp(mut task: TracedTask) -> Result<TracedTask> {
     Ok(task)
 }
 
+// a notification from the trampoline (see `SYSTRACE_SYSCALL_NOTIFY`), of
+// a syscall done (by a patched site) as `(nr, arg0, arg1, arg2, arg3,
+// retval)`: handled as if the syscall was traced, at its syscall exit stop,
+// except `exit_group`, which is notified before it is done. a `nr` of -1
+// only tells libtrampoline is loaded, which is checked for already.
+fn do_trampoline_notify(task: &mut TracedTask, regs: libc::user_regs_struct) -> Result<()> {
+    let mut new_regs = regs;
+    new_regs.rax = 0;
+    skip_seccomp_syscall(task, new_regs)?;
+    let mut done = regs;
+    done.orig_rax = regs.rdi;
+    done.rdi = regs.rsi;
+    done.rsi = regs.rdx;
+    done.rdx = regs.r10;
+    done.r10 = regs.r8;
+    done.rax = regs.r9;
+    trace!("{} notified of syscall {:x}({:x}, {:x}, {:x}, {:x}) = {:x}",
+           task.gettid(), done.orig_rax, done.rdi, done.rsi, done.rdx, done.r10, done.rax);
+    if done.orig_rax == SYS_exit_group as u64 {
+        if !task.exit_notified
+            && task.loaded_tools.iter().any(|t| t.hook(tool::ToolHook::Exit).is_some()) {
+            task.exit_notified = true;
+            notify_tools(task, tool::ToolHook::Exit, &[done.rdi as i32 as i64]);
+        }
+    } else if done.orig_rax != -1i64 as u64 {
+        track_address_space_change(task, done);
+        if get_systrace_options().patches_mapped_text() {
+            patch_text_mapped_by_syscall(task, done);
+        }
+    }
+    Ok(())
+}
+
+// libtrampoline is found loaded: tools are to be notified of exec, or code
+// patched ahead of time, see `init_libtrampoline`.
+fn libtrampoline_init_pending(task: &TracedTask) -> bool {
+    task.loaded_tools.iter().any(|t| t.hook(tool::ToolHook::Exec).is_some())
+        || get_systrace_options().patches_mapped_text()
+        || get_systrace_options().patches_vdso_syscalls()
+}
+
+// notify tools of exec, and patch code ahead of time, once libtrampoline
+// is found loaded. the task must be stopped, but not in a seccomp stop,
+// returns whether code is patched.
+fn init_libtrampoline(task: &mut TracedTask) -> bool {
+    if task.loaded_tools.iter().any(|t| t.hook(tool::ToolHook::Exec).is_some()) {
+        notify_tools(task, tool::ToolHook::Exec, &[]);
+    }
+    let patches_mapped_text = get_systrace_options().patches_mapped_text();
+    if patches_mapped_text {
+        patch_all_mapped_text(task);
+    }
+    let routes_vdso = get_systrace_options().patches_vdso_syscalls();
+    if routes_vdso {
+        patch_vdso_syscall_sites(task);
+    }
+    patches_mapped_text || routes_vdso
+}
+
+// a call into the vsyscall page (see `vsyscall`), at its seccomp stop: it
+// is routed to the tools through the trampoline once libtrampoline is
+// loaded, like a ptraced syscall (`route_syscall_to_trampoline`), it is
+// emulated by the kernel otherwise.
+fn do_vsyscall(task: &mut TracedTask, regs: libc::user_regs_struct, syscall: SyscallNo) -> Result<()> {
+    let tid = task.gettid();
+    let ldpreload_address = task.ldpreload_address.or_else(|| libtrampoline_load_address(tid));
+    let entry = ldpreload_address.and_then(|_| ptraced_trampoline_address(tid));
+    let message = match entry {
+        Some(entry) => {
+            let caller_regs = vsyscall::skip_vsyscall_to_caller(task, regs)?;
+            // libtrampoline is found loaded, not in a seccomp stop anymore.
+            let mut patched = false;
+            if task.ldpreload_address.is_none() {
+                task.ldpreload_address = ldpreload_address;
+                if let Err(e) = install_tool_chain(task) {
+                    warn!("{} failed to install tool chain: {}", tid, e);
+                }
+                patched = init_libtrampoline(task);
+            }
+            let mut new_regs = caller_regs;
+            new_regs.rax = regs.orig_rax;
+            new_regs.orig_rax = -1i64 as u64;
+            // as if the trampoline was called by the caller, `ret $0x80`
+            // pops the return address pushed by the vsyscall call as well.
+            new_regs.rsp = regs.rsp - consts::SYSTRACE_RED_ZONE_SIZE;
+            new_regs.rip = entry;
+            task.poke(RemotePtr::new(new_regs.rsp as *mut u64), &caller_regs.rip)?;
+            task.setregs(new_regs)?;
+            if patched {
+                synchronize_from(task, entry);
+            }
+            debug!("{} vsyscall {:?}@{:x} routed to the trampoline, returns to {:x}", tid, syscall, regs.rip, caller_regs.rip);
+            "routed to the trampoline"
+        }
+        None => {
+            // no syscall exit stop to wait for.
+            task.state = TaskState::Running;
+            "emulated by the kernel, libtrampoline not loaded"
+        }
+    };
+    report_syscall_site(task, regs.rip, Some(syscall), Some(patch_report::Status::Failed(Failure::Vsyscall, message.to_string())));
+
+    let state = get_systrace_state();
+    state.nr_syscalls_ptraced.fetch_add(1, Ordering::SeqCst);
+    if entry.is_some() {
+        // `nr_syscalls` is updated by the tool.
+        state.nr_syscalls_routed.fetch_add(1, Ordering::SeqCst);
+    } else {
+        state.nr_syscalls.fetch_add(1, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+// call tool `hook` at a seccomp stop: the pending syscall is skipped, and
+// restarted from the `syscall` instruction once all tools returned.
+fn notify_tools_and_restart(task: &mut TracedTask, regs: libc::user_regs_struct, hook: tool::ToolHook, args: &[i64]) -> Result<()> {
+    skip_seccomp_syscall(task, regs)?;
+    notify_tools(task, hook, args);
+    restart_skipped_syscall(task, regs)
+}
+
+// restart a syscall skipped at a seccomp stop (with `regs`) from the
+// `syscall` instruction, which could have been patched meanwhile.
+fn restart_skipped_syscall(task: &mut TracedTask, regs: libc::user_regs_struct) -> Result<()> {
+    let mut new_regs = regs;
+    new_regs.rax = regs.orig_rax;
+    new_regs.orig_rax = -1i64 as u64;
+    new_regs.rip = regs.rip - consts::SYSCALL_INSN_SIZE as u64;
+    task.seccomp_hook_size = None;
+    task.setregs(new_regs)
+}
+
+/// route a ptraced (unpatched) syscall into the trampoline, so that the
+/// tool's `captured_syscall` sees it exactly once, just like a patched
+/// syscall. The pending syscall is skipped, then a call frame returning
+/// to the instruction after `syscall` is pushed below the red zone, and
+/// the task resumes from `_syscall_hook_trampoline_ptraced`.
+fn route_syscall_to_trampoline(task: &mut TracedTask, regs: libc::user_regs_struct) -> Result<()> {
+    let tid = task.gettid();
+    let rip = regs.rip;
+    let page_start = consts::SYSTRACE_PRIVATE_PAGE_OFFSET;
+    let page_end = page_start + consts::SYSTRACE_PRIVATE_PAGE_SIZE;
+    // syscalls from our private page are either untraced, or explicitly
+    // asked to be traced (`traced_syscall`) by the tool itself, routing
+    // them back to the tool would recurse.
+    if rip >= page_start && rip < page_end {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("syscall from private page @{:x}", rip),
+        ));
+    }
+    let entry = ptraced_trampoline_address(tid).ok_or(Error::new(
+        ErrorKind::Other,
+        format!("{} not loaded", consts::LIBTRAMPOLINE_SO),
+    ))?;
+
+    // the syscall could have been skipped already by a failed patch attempt.
+    if task.task_state_is_seccomp() {
+        skip_seccomp_syscall(task, regs)?;
+    }
+
+    let mut new_regs = regs;
+    new_regs.rax = regs.orig_rax;
+    // not in a syscall anymore, prevent syscall restart.
+    new_regs.orig_rax = -1i64 as u64;
+    new_regs.rsp = regs.rsp - consts::SYSTRACE_RED_ZONE_SIZE - std::mem::size_of::<u64>() as u64;
+    new_regs.rip = entry;
+    task.poke(RemotePtr::new(new_regs.rsp as *mut u64), &rip)?;
+    task.setregs(new_regs)?;
+
+    // there won't be a syscall exit stop for the routed syscall.
+    task.seccomp_hook_size = None;
+    task.address_space.borrow_mut().patch_lock().try_read_unlock(tid, rip);
+    Ok(())
+}
+
 fn from_nix_error(err: nix::Error) -> Error {
     Error::new(ErrorKind::Other, err)
 }
@@ -999,6 +2117,20 @@ fn systool_set_log_level(task: &TracedTask) {
     }
 }
 
+// write `--tool-arg` options, so that tools can read them without
+// parsing the environment.
+fn systool_set_tool_args(task: &TracedTask) {
+    let bytes = get_systrace_options().tool_args_bytes();
+    if bytes.is_empty() || bytes.len() > consts::SYSTRACE_TOOL_ARGS_MAX_SIZE {
+        return;
+    }
+    let args_ptr = RemotePtr::new(consts::SYSTRACE_TOOL_ARGS_ADDR as *mut u8);
+    let size_ptr = RemotePtr::new(consts::SYSTRACE_LOCAL_TOOL_ARGS_SIZE as *mut u64);
+    let _ = task
+        .poke_bytes(args_ptr, &bytes)
+        .and_then(|_| task.poke(size_ptr, &(bytes.len() as u64)));
+}
+
 fn tracee_preinit(task: &mut TracedTask) -> nix::Result<()> {
     let tid = task.gettid();
     let mut regs = ptrace::getregs(tid)?;
@@ -1011,7 +2143,8 @@ fn tracee_preinit(task: &mut TracedTask) -> nix::Result<()> {
     regs.rdi = page_addr;
     regs.rsi = page_size;
     regs.rdx = (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as u64;
-    regs.r10 = (libc::MAP_PRIVATE | libc::MAP_FIXED | libc::MAP_ANONYMOUS) as u64;
+    // never clobber a mapping of an attached process, `EEXIST`.
+    regs.r10 = (libc::MAP_PRIVATE | libc::MAP_FIXED_NOREPLACE | libc::MAP_ANONYMOUS) as u64;
     regs.r8 = -1 as i64 as u64;
     regs.r9 = 0 as u64;
 
@@ -1043,6 +2176,7 @@ fn tracee_preinit(task: &mut TracedTask) -> nix::Result<()> {
     assert_eq!(ret, page_addr);
 
     systool_set_log_level(task);
+    systool_set_tool_args(task);
 
     remote::gen_syscall_sequences_at(tid, page_addr)?;
 
@@ -1079,15 +2213,370 @@ fn do_ptrace_exec(task: &mut TracedTask) -> nix::Result<()> {
                           (libc::PROT_READ | libc::PROT_WRITE) as i64,
                           (libc::MAP_SHARED | libc::MAP_FIXED) as i64,
                           consts::SYSTRACE_GLOBAL_STATE_FD as i64,
-                          0).unwrap();
-    assert_eq!(_at, consts::SYSTRACE_GLOBAL_STATE_ADDR as i64);
-    let _ = unistd::close(consts::SYSTRACE_GLOBAL_STATE_FD);
-    ptrace::write(tid, consts::SYSTRACE_LOCAL_SYSTRACE_GLOBAL_STATE as ptrace::AddressType, _at as *mut _)?;
+                          0);
+    match _at {
+        Ok(at) => {
+            assert_eq!(at, consts::SYSTRACE_GLOBAL_STATE_ADDR as i64);
+            let _ = unistd::close(consts::SYSTRACE_GLOBAL_STATE_FD);
+            ptrace::write(tid, consts::SYSTRACE_LOCAL_SYSTRACE_GLOBAL_STATE as ptrace::AddressType, at as *mut _)?;
+        }
+        // `SYSTRACE_GLOBAL_STATE_FD` is not inherited by (children of)
+        // processes attached by `--attach`.
+        Err(_) => {
+            let scratch = task.untraced_syscall(SYS_mmap, 0, 0x1000,
+                                                (libc::PROT_READ | libc::PROT_WRITE) as i64,
+                                                (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as i64,
+                                                -1, 0).unwrap();
+            map_global_state_from(task, scratch as u64).unwrap();
+            let _ = task.untraced_syscall(SYS_munmap, scratch, 0x1000, 0, 0, 0, 0);
+        }
+    }
     let state = get_systrace_state();
     state.nr_process_spawns.fetch_add(1, Ordering::SeqCst);
     Ok(())
 }
 
+// map the tracer's global state (`SYSTRACE_GLOBAL_STATE_FD`) into a task
+// which was not forked by the tracer, through `/proc/<tracer>/fd`. an
+// anonymous page is mapped instead if the file cannot be opened. fails
+// with `EEXIST` if `SYSTRACE_GLOBAL_STATE_ADDR` is mapped already.
+fn map_global_state_from(task: &mut TracedTask, scratch: u64) -> Result<()> {
+    let path = format!("/proc/{}/fd/{}\0", unistd::getpid(), consts::SYSTRACE_GLOBAL_STATE_FD);
+    task.poke_bytes(RemotePtr::new(scratch as *mut u8), path.as_bytes())?;
+    let prot = (libc::PROT_READ | libc::PROT_WRITE) as i64;
+    let at = match task.untraced_syscall(SYS_openat, libc::AT_FDCWD as i64, scratch as i64, libc::O_RDWR as i64, 0, 0, 0) {
+        Ok(fd) => {
+            let at = task.untraced_syscall(SYS_mmap,
+                                           consts::SYSTRACE_GLOBAL_STATE_ADDR as i64,
+                                           consts::SYSTRACE_GLOBAL_STATE_SIZE as i64,
+                                           prot,
+                                           (libc::MAP_SHARED | libc::MAP_FIXED_NOREPLACE) as i64,
+                                           fd,
+                                           0);
+            let _ = task.untraced_syscall(SYS_close, fd, 0, 0, 0, 0, 0);
+            at?
+        }
+        Err(e) => {
+            warn!("{} cannot open {}: {}, statistics not shared", task.gettid(), path.trim_end_matches('\0'), e);
+            task.untraced_syscall(SYS_mmap,
+                                  consts::SYSTRACE_GLOBAL_STATE_ADDR as i64,
+                                  consts::SYSTRACE_GLOBAL_STATE_SIZE as i64,
+                                  prot,
+                                  (libc::MAP_PRIVATE | libc::MAP_FIXED_NOREPLACE | libc::MAP_ANONYMOUS) as i64,
+                                  -1,
+                                  0)?
+        }
+    };
+    assert_eq!(at, consts::SYSTRACE_GLOBAL_STATE_ADDR as i64);
+    task.poke(RemotePtr::new(consts::SYSTRACE_LOCAL_SYSTRACE_GLOBAL_STATE as *mut u64), &(at as u64))
+}
+
+// `dlopen(path, RTLD_NOW | RTLD_GLOBAL)` in tracee's context, `scratch` is used to
+// pass the path.
+fn remote_dlopen(task: &mut TracedTask, dlopen: u64, scratch: u64, path: &PathBuf) -> Result<()> {
+    let mut bytes = path.to_str().unwrap_or("").as_bytes().to_vec();
+    bytes.push(0);
+    task.poke_bytes(RemotePtr::new(scratch as *mut u8), &bytes)?;
+    // `RTLD_GLOBAL`, so that tools can bind to libtrampoline, as with `LD_PRELOAD`.
+    let handle = remote_call_at(task, dlopen, &[scratch as i64, (libc::RTLD_NOW | libc::RTLD_GLOBAL) as i64])?;
+    if handle == 0 {
+        Err(Error::new(ErrorKind::Other, format!("dlopen {:?} failed", path)))
+    } else {
+        Ok(())
+    }
+}
+
+// install seccomp filter `prog` for all threads of the task.
+fn remote_install_seccomp(task: &mut TracedTask, scratch: u64, prog: &[libc::sock_filter]) -> Result<()> {
+    // struct sock_fprog, followed by the instructions.
+    let insns = scratch + 16;
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(&(prog.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&insns.to_le_bytes());
+    for insn in prog {
+        bytes.extend_from_slice(&insn.code.to_le_bytes());
+        bytes.push(insn.jt);
+        bytes.push(insn.jf);
+        bytes.extend_from_slice(&insn.k.to_le_bytes());
+    }
+    task.poke_bytes(RemotePtr::new(scratch as *mut u8), &bytes)?;
+    task.untraced_syscall(SYS_prctl, libc::PR_SET_NO_NEW_PRIVS as i64, 1, 0, 0, 0, 0)?;
+    let res = task.untraced_syscall(SYS_seccomp,
+                                    libc::SECCOMP_SET_MODE_FILTER as i64,
+                                    libc::SECCOMP_FILTER_FLAG_TSYNC as i64,
+                                    scratch as i64,
+                                    0, 0, 0)?;
+    if res != 0 {
+        return Err(Error::new(ErrorKind::Other, format!("seccomp: cannot synchronize thread {}", res)));
+    }
+    Ok(())
+}
+
+// `EEXIST` mapping systrace's page `addr` into an attached process.
+fn mapped_already(task: &TracedTask, addr: u64, e: Error) -> Error {
+    if e.raw_os_error() == Some(libc::EEXIST) {
+        Error::new(
+            ErrorKind::AlreadyExists,
+            format!("process {} has a mapping at {:x}, which systrace needs", task.getpid(), addr),
+        )
+    } else {
+        e
+    }
+}
+
+/// prepare a task attached by `PTRACE_SEIZE` (`--attach`), as if it was
+/// launched by the tracer: inject the private page, load libtrampoline and
+/// `tools` with `dlopen` (at runtime address `dlopen`), then install the
+/// seccomp filter `prog` for all threads.
+/// - the task must be in `PTRACE_EVENT_STOP`, other threads of the process
+///   should be stopped as well.
+pub fn attach_preinit(task: &mut TracedTask, dlopen: u64, libs: &[PathBuf], prog: &[libc::sock_filter]) -> Result<()> {
+    let tid = task.gettid();
+    let regs = task.getregs()?;
+    let rip = regs.rip;
+    let saved = ptrace::read(tid, rip as ptrace::AddressType).map_err(from_nix_error)?;
+
+    // `tracee_preinit` expects to be stopped right after the first `int3`
+    // of `int3; syscall; int3`.
+    let bp_syscall_bp: i64 = 0xcc050fcc;
+    ptrace::write(tid, rip as ptrace::AddressType,
+                  ((saved & !(0xffffffff as i64)) | bp_syscall_bp) as *mut libc::c_void)
+        .map_err(from_nix_error)?;
+    let mut new_regs = regs;
+    new_regs.rip = rip + 1;
+    new_regs.orig_rax = -1i64 as u64;
+    task.setregs(new_regs)?;
+    let res = tracee_preinit(task).map_err(|e| match e {
+        nix::Error::Sys(errno) => Error::from_raw_os_error(errno as i32),
+        e => from_nix_error(e),
+    });
+    ptrace::write(tid, rip as ptrace::AddressType, saved as *mut libc::c_void).map_err(from_nix_error)?;
+    if let Err(e) = res {
+        task.setregs(regs)?;
+        return Err(mapped_already(task, consts::SYSTRACE_PRIVATE_PAGE_OFFSET, e));
+    }
+    task.injected_mmap_page = Some(consts::SYSTRACE_PRIVATE_PAGE_OFFSET);
+
+    let scratch_size = 0x10000i64;
+    let scratch = task.untraced_syscall(SYS_mmap, 0, scratch_size,
+                                        (libc::PROT_READ | libc::PROT_WRITE) as i64,
+                                        (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as i64,
+                                        -1, 0)? as u64;
+    let res = map_global_state_from(task, scratch)
+        .map_err(|e| mapped_already(task, consts::SYSTRACE_GLOBAL_STATE_ADDR, e))
+        .and_then(|_| libs.iter().try_for_each(|lib| remote_dlopen(task, dlopen, scratch, lib)))
+        .and_then(|_| {
+            task.ldpreload_address = libtrampoline_load_address(tid);
+            install_tool_chain(task)
+        })
+        .and_then(|_| remote_install_seccomp(task, scratch, prog));
+    let _ = task.untraced_syscall(SYS_munmap, scratch as i64, scratch_size, 0, 0, 0, 0);
+    // back to the interrupted state, an interrupted syscall is restarted.
+    task.setregs(regs)?;
+    if let Err(e) = res {
+        // the private page cannot unmap itself.
+        let _ = remote_syscall_in_place(task, SYS_munmap, &[consts::SYSTRACE_PRIVATE_PAGE_OFFSET as i64,
+                                                            consts::SYSTRACE_PRIVATE_PAGE_SIZE as i64,
+                                                            0, 0, 0, 0]);
+        task.injected_mmap_page = None;
+        return Err(e);
+    }
+    update_memory_map(task);
+    if get_systrace_options().patches_vdso_syscalls() {
+        patch_vdso_syscall_sites(task);
+    }
+    get_systrace_state().nr_process_spawns.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+/// write `bytes` to (possibly read-only) code at `addr` with `ptrace::write`,
+/// unlike `poke_bytes`, errors (i.e.: the task is gone) are returned.
+pub fn poke_code(task: &TracedTask, addr: u64, bytes: &[u8]) -> Result<()> {
+    for (k, chunk) in bytes.chunks(std::mem::size_of::<u64>()).enumerate() {
+        let at = (addr + (k * std::mem::size_of::<u64>()) as u64) as ptrace::AddressType;
+        let mut word = ptrace::read(task.tid, at).map_err(from_nix_error)?.to_le_bytes();
+        word[..chunk.len()].copy_from_slice(chunk);
+        ptrace::write(task.tid, at, i64::from_le_bytes(word) as *mut libc::c_void).map_err(from_nix_error)?;
+    }
+    Ok(())
+}
+
+// do syscall `nr` at current `rip` (by writing `syscall; int3` there),
+// instead of the private page. unlike `untraced_syscall`, it can be
+// used to unmap the private page itself, but it is trapped by seccomp.
+fn remote_syscall_in_place(task: &mut TracedTask, nr: SyscallNo, args: &[i64; 6]) -> Result<i64> {
+    let tid = task.gettid();
+    let oldregs = task.getregs()?;
+    let saved = ptrace::read(tid, oldregs.rip as ptrace::AddressType).map_err(from_nix_error)?;
+    let syscall_bp: i64 = 0xcc050f;
+    ptrace::write(tid, oldregs.rip as ptrace::AddressType,
+                  ((saved & !0xffffff) | syscall_bp) as *mut libc::c_void)
+        .map_err(from_nix_error)?;
+    let mut regs = oldregs;
+    regs.orig_rax = nr as u64;
+    regs.rax = nr as u64;
+    regs.rdi = args[0] as u64;
+    regs.rsi = args[1] as u64;
+    regs.rdx = args[2] as u64;
+    regs.r10 = args[3] as u64;
+    regs.r8 = args[4] as u64;
+    regs.r9 = args[5] as u64;
+    task.setregs(regs)?;
+    task.resume(None)?;
+    loop {
+        match wait::waitpid(tid, None).map_err(from_nix_error)? {
+            WaitStatus::Stopped(_, signal::SIGTRAP) => break,
+            WaitStatus::PtraceEvent(_, _, 7) => task.resume(None)?,
+            WaitStatus::Stopped(_, sig) => {
+                task.signal_to_deliver = Some(sig);
+                task.resume(None)?;
+            }
+            otherwise => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("{} syscall {:?} returned unknown status {:?}", tid, nr, otherwise),
+                ));
+            }
+        }
+    }
+    let newregs = task.getregs()?;
+    ptrace::write(tid, oldregs.rip as ptrace::AddressType, saved as *mut libc::c_void)
+        .map_err(from_nix_error)?;
+    task.setregs(oldregs)?;
+    if newregs.rax > (-4096i64) as u64 {
+        Err(Error::from_raw_os_error(-(newregs.rax as i64) as i32))
+    } else {
+        Ok(newregs.rax as i64)
+    }
+}
+
+// a thread is (still) in a patched syscall if it is running the private
+// page, the stub pages, libtrampoline, a tool, the vdso or the middle of a
+// patched site, or if a return address into the stub pages, libtrampoline
+// or a tool is found near the top of its stack: the extended jump is a
+// `callq`, and so are the hooks.
+fn in_patched_syscall(task: &TracedTask, maps: &[ProcMapsEntry], stubs: &[SyscallStubPage]) -> bool {
+    let ours: Vec<(u64, u64)> = maps
+        .iter()
+        .filter(|e| match e.filename() {
+            Some(file) => file.file_name() == Some(std::ffi::OsStr::new(consts::LIBTRAMPOLINE_SO))
+                || task.loaded_tools.iter().any(|tool| &tool.path == file),
+            None => false,
+        })
+        .map(|e| (e.base(), e.end()))
+        .chain(stubs.iter().map(|page| (page.address, page.address + page.size as u64)))
+        .collect();
+    let in_ours = |addr: u64| ours.iter().any(|&(base, end)| addr >= base && addr < end);
+    let regs = match task.getregs() {
+        Ok(regs) => regs,
+        Err(_) => return true,
+    };
+    let private_page = consts::SYSTRACE_PRIVATE_PAGE_OFFSET
+        ..consts::SYSTRACE_PRIVATE_PAGE_OFFSET + consts::SYSTRACE_PRIVATE_PAGE_SIZE;
+    let in_vdso = maps
+        .iter()
+        .any(|e| e.filename() == Some(&PathBuf::from("[vdso]")) && regs.rip >= e.base() && regs.rip < e.end());
+    let in_site = task
+        .address_space
+        .borrow()
+        .patched_sites()
+        .any(|site| regs.rip > site.address && regs.rip < site.address + site.original_bytes.len() as u64);
+    if private_page.contains(&regs.rip) || in_ours(regs.rip) || in_vdso || in_site {
+        return true;
+    }
+    let stack_end = maps
+        .iter()
+        .find(|e| regs.rsp >= e.base() && regs.rsp < e.end())
+        .map(|e| e.end())
+        .unwrap_or(regs.rsp);
+    let size = std::cmp::min(stack_end - regs.rsp, 0x10000) as usize;
+    match task.peek_bytes(RemotePtr::new(regs.rsp as *mut u8), size) {
+        Ok(stack) => stack.chunks_exact(std::mem::size_of::<u64>()).any(|word| {
+            let mut raw = [0u8; 8];
+            raw.copy_from_slice(word);
+            in_ours(u64::from_le_bytes(raw))
+        }),
+        Err(_) => true,
+    }
+}
+
+/// threads of `threads` (all threads of a process, stopped) which are still
+/// in a patched syscall, hence the process cannot be restored yet, see
+/// `detach_restore`.
+pub fn threads_in_patched_syscalls(threads: &[TracedTask]) -> Result<Vec<Pid>> {
+    let task = match threads.first() {
+        Some(task) if task.injected_mmap_page.is_some() => task,
+        _ => return Ok(Vec::new()),
+    };
+    let maps = decode_proc_maps(task.getpid())?;
+    let stubs: Vec<SyscallStubPage> = task.address_space.borrow().stub_pages().cloned().collect();
+    Ok(threads
+        .iter()
+        .filter(|task| in_patched_syscall(task, &maps, &stubs))
+        .map(|task| task.gettid())
+        .collect())
+}
+
+/// undo what was done to process of `threads` (all threads of the process,
+/// stopped, but not in a seccomp stop) before detaching: the original bytes
+/// of patched syscall sites and vdso are restored, then the stub pages, the
+/// global state and the private page are unmapped. nothing is undone if
+/// some thread is still in a patched syscall: patched syscalls then keep
+/// going through the trampoline (and tools), untraced.
+/// - libtrampoline and tools stay loaded.
+/// - the seccomp filter cannot be removed.
+pub fn detach_restore(threads: &mut [TracedTask]) -> Result<()> {
+    let pid = match threads.first() {
+        Some(task) => task.getpid(),
+        None => return Ok(()),
+    };
+    let busy = threads_in_patched_syscalls(threads)?;
+    if !busy.is_empty() {
+        warn!("{} threads {:?} are in patched syscalls, patches are left in place", pid, busy);
+        return Ok(());
+    }
+    let patched: Vec<PatchedSyscall> = threads[0].address_space.borrow().patched_sites().cloned().collect();
+    for site in &patched {
+        // i.e.: unmapped since.
+        if let Err(e) = poke_code(&threads[0], site.address, &site.original_bytes) {
+            warn!("{} cannot restore patched syscall site {:x}: {}", pid, site.address, e);
+        }
+    }
+    threads[0].address_space.borrow_mut().clear_patched();
+    if threads[0].injected_mmap_page.is_none() {
+        return Ok(());
+    }
+    vdso::vdso_restore(&threads[0])?;
+    debug!("{} restored {} patched syscall sites", pid, patched.len());
+
+    let stubs: Vec<SyscallStubPage> = threads[0].address_space.borrow().stub_pages().cloned().collect();
+    // i.e.: not stopped by `PTRACE_EVENT_VFORK`, which cannot be resumed
+    // until the vfork child exits (or execs).
+    let task = match threads
+        .iter_mut()
+        .find(|task| task.state != TaskState::Event(libc::PTRACE_EVENT_VFORK as u64)) {
+        Some(task) => task,
+        None => return Ok(()),
+    };
+    for page in &stubs {
+        task.untraced_syscall(SYS_munmap, page.address as i64, page.size as i64, 0, 0, 0, 0)?;
+    }
+    task.address_space.borrow_mut().clear_stub_pages();
+    task.untraced_syscall(SYS_munmap,
+                          consts::SYSTRACE_GLOBAL_STATE_ADDR as i64,
+                          consts::SYSTRACE_GLOBAL_STATE_SIZE as i64,
+                          0, 0, 0, 0)?;
+    remote_syscall_in_place(task, SYS_munmap, &[
+        consts::SYSTRACE_PRIVATE_PAGE_OFFSET as i64,
+        consts::SYSTRACE_PRIVATE_PAGE_SIZE as i64,
+        0, 0, 0, 0])?;
+    for task in threads.iter_mut() {
+        task.injected_mmap_page = None;
+        task.ldpreload_address = None;
+    }
+    Ok(())
+}
+
 // so here we are, at ptrace seccomp stop, if we simply resume, the kernel would
 // do the syscall, without our patch. we change to syscall number to -1, so that
 // kernel would simply skip the syscall, so that we can jump to our patched syscall
@@ -0,0 +1,655 @@
+//! symbolized backtraces of tracees
+//!
+//! addresses are shown as `library+offset (symbol+offset)`, from the
+//! memory map of the tracee and the ELF symbols of the mapped files.
+//! stacks are unwound with the call frame information (`.eh_frame`) of the
+//! mapped file when it covers the pc, by following frame pointers (`rbp`)
+//! otherwise. CFI rules using DWARF expressions (i.e.: PLT entries) are not
+//! evaluated, frame pointers are followed instead.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use goblin::elf::section_header::SHT_NOBITS;
+use goblin::elf::Elf;
+
+use crate::patch_report;
+use crate::proc::*;
+use crate::symbols;
+
+/// frames are not unwound beyond this depth.
+pub const MAX_FRAMES: usize = 64;
+
+// DWARF register numbers of x86_64, registers above the return address
+// (i.e.: xmm) are not tracked.
+const DW_REG_RBP: u64 = 6;
+const DW_REG_RSP: u64 = 7;
+const DW_REG_RA: u64 = 16;
+const DW_NREGS: usize = DW_REG_RA as usize + 1;
+
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0a;
+const DW_EH_PE_SDATA4: u8 = 0x0b;
+const DW_EH_PE_SDATA8: u8 = 0x0c;
+const DW_EH_PE_PCREL: u8 = 0x10;
+
+/// how a frame is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// the innermost frame, from the registers.
+    Registers,
+    /// from the CFI (`.eh_frame`) of the callee.
+    Cfi,
+    /// by following `rbp`.
+    FramePointer,
+}
+
+/// a frame: its pc (a return address, except for the innermost frame),
+/// and its stack pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub pc: u64,
+    pub sp: u64,
+    pub method: Method,
+}
+
+fn truncated() -> Error {
+    Error::new(ErrorKind::Other, "truncated .eh_frame")
+}
+
+// little endian reader of `.eh_frame`, `at` is the offset in the section.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, size: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.at..self.at.saturating_add(size))
+            .ok_or_else(truncated)?;
+        self.at += size;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let mut buf = [0u8; 2];
+        buf.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn uleb(&mut self) -> Result<u64> {
+        let mut res = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                res |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(res);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64> {
+        let mut res = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                res |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    res |= -1i64 << shift;
+                }
+                return Ok(res);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Result<&'a [u8]> {
+        let len = self.bytes[self.at..]
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(truncated)?;
+        let s = self.bytes(len)?;
+        self.at += 1;
+        Ok(s)
+    }
+
+    // a pointer with `encoding`, `vaddr` is the link time address of
+    // `.eh_frame`. indirect pointers are not dereferenced.
+    fn pointer(&mut self, encoding: u8, vaddr: u64) -> Result<u64> {
+        let pc = vaddr.wrapping_add(self.at as u64);
+        let value = match encoding & 0x0f {
+            DW_EH_PE_ABSPTR | DW_EH_PE_UDATA8 | DW_EH_PE_SDATA8 => self.u64()?,
+            DW_EH_PE_ULEB128 => self.uleb()?,
+            DW_EH_PE_UDATA2 => u64::from(self.u16()?),
+            DW_EH_PE_UDATA4 => u64::from(self.u32()?),
+            DW_EH_PE_SLEB128 => self.sleb()? as u64,
+            DW_EH_PE_SDATA2 => self.u16()? as i16 as u64,
+            DW_EH_PE_SDATA4 => self.u32()? as i32 as u64,
+            _ => {
+                let msg = format!("unsupported pointer encoding {:#x}", encoding);
+                return Err(Error::new(ErrorKind::Other, msg));
+            }
+        };
+        match encoding & 0x70 {
+            0 => Ok(value),
+            DW_EH_PE_PCREL => Ok(pc.wrapping_add(value)),
+            _ => {
+                let msg = format!("unsupported pointer encoding {:#x}", encoding);
+                Err(Error::new(ErrorKind::Other, msg))
+            }
+        }
+    }
+}
+
+// common information entry, instructions are a range of `.eh_frame`.
+struct Cie {
+    code_align: u64,
+    data_align: i64,
+    ra_register: u64,
+    fde_encoding: u8,
+    augmented: bool,
+    instructions: (usize, usize),
+}
+
+// frame description entry of the link time addresses `start..end`.
+struct Fde {
+    start: u64,
+    end: u64,
+    cie: usize,
+    instructions: (usize, usize),
+}
+
+// `.eh_frame` of an ELF file, FDEs are sorted by address.
+struct EhFrame {
+    data: Vec<u8>,
+    cies: Vec<Cie>,
+    fdes: Vec<Fde>,
+}
+
+// `.eh_frame` of a file (if any), and the stamp of the file when read.
+type CachedEhFrame = (symbols::FileStamp, Option<Arc<EhFrame>>);
+
+lazy_static! {
+    static ref EH_FRAMES: Mutex<HashMap<PathBuf, CachedEhFrame>> = Mutex::new(HashMap::new());
+}
+
+fn parse_cie(r: &mut Reader, vaddr: u64) -> Result<Cie> {
+    let version = r.u8()?;
+    let augmentation = r.cstr()?;
+    if augmentation.windows(2).any(|w| w == b"eh") {
+        r.u64()?;
+    }
+    let code_align = r.uleb()?;
+    let data_align = r.sleb()?;
+    let ra_register = if version == 1 { u64::from(r.u8()?) } else { r.uleb()? };
+    let mut fde_encoding = DW_EH_PE_ABSPTR;
+    let augmented = augmentation.first() == Some(&b'z');
+    if augmented {
+        let size = r.uleb()? as usize;
+        let end = r.at.saturating_add(size);
+        for c in &augmentation[1..] {
+            match c {
+                b'R' => fde_encoding = r.u8()?,
+                b'P' => {
+                    let encoding = r.u8()?;
+                    r.pointer(encoding, vaddr)?;
+                }
+                b'L' => {
+                    r.u8()?;
+                }
+                b'S' | b'B' => (),
+                _ => break,
+            }
+        }
+        r.at = end;
+    }
+    Ok(Cie {
+        code_align,
+        data_align,
+        ra_register,
+        fde_encoding,
+        augmented,
+        instructions: (r.at, r.bytes.len()),
+    })
+}
+
+// parse `data`, the `.eh_frame` section at link time address `vaddr`.
+fn parse_eh_frame(data: Vec<u8>, vaddr: u64) -> Result<EhFrame> {
+    let mut cies: Vec<Cie> = Vec::new();
+    let mut cie_offsets: HashMap<usize, usize> = HashMap::new();
+    let mut fdes: Vec<Fde> = Vec::new();
+    let mut at = 0;
+    while at + 4 <= data.len() {
+        let mut r = Reader { bytes: &data, at };
+        let mut length = u64::from(r.u32()?);
+        if length == 0 {
+            break;
+        }
+        if length == 0xffff_ffff {
+            length = r.u64()?;
+        }
+        let end = (length as usize)
+            .checked_add(r.at)
+            .filter(|end| *end <= data.len())
+            .ok_or_else(truncated)?;
+        let mut r = Reader { bytes: &data[..end], at: r.at };
+        let id_at = r.at;
+        let id = r.u32()? as usize;
+        if id == 0 {
+            cie_offsets.insert(at, cies.len());
+            cies.push(parse_cie(&mut r, vaddr)?);
+        } else {
+            let cie_at = id_at.checked_sub(id).ok_or_else(truncated)?;
+            let cie = match cie_offsets.get(&cie_at) {
+                Some(k) => *k,
+                None => {
+                    let msg = format!("FDE @{:#x} has no CIE @{:#x}", at, cie_at);
+                    return Err(Error::new(ErrorKind::Other, msg));
+                }
+            };
+            let start = r.pointer(cies[cie].fde_encoding, vaddr)?;
+            let size = r.pointer(cies[cie].fde_encoding & 0x0f, 0)?;
+            if cies[cie].augmented {
+                let size = r.uleb()? as usize;
+                r.bytes(size)?;
+            }
+            if start != 0 {
+                fdes.push(Fde {
+                    start,
+                    end: start.wrapping_add(size),
+                    cie,
+                    instructions: (r.at, end),
+                });
+            }
+        }
+        at = end;
+    }
+    fdes.sort_by_key(|fde| fde.start);
+    Ok(EhFrame { data, cies, fdes })
+}
+
+fn read_eh_frame(path: &Path) -> Result<Option<EhFrame>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let elf = Elf::parse(&bytes).map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let section = elf.section_headers.iter().find(|sh| {
+        sh.sh_type != SHT_NOBITS && elf.shdr_strtab.get(sh.sh_name).and_then(|s| s.ok()) == Some(".eh_frame")
+    });
+    match section.and_then(|sh| bytes.get(sh.file_range()).map(|data| (sh.sh_addr, data))) {
+        Some((vaddr, data)) => parse_eh_frame(data.to_vec(), vaddr).map(Some),
+        None => Ok(None),
+    }
+}
+
+// `.eh_frame` of `path`, files without one (or with a bad one) are cached
+// as `None`, until the file changes.
+fn eh_frame(path: &Path) -> Option<Arc<EhFrame>> {
+    let mut cache = EH_FRAMES.lock().unwrap();
+    let stamp = symbols::file_stamp(path).ok()?;
+    if let Some((cached, eh_frame)) = cache.get(path) {
+        if *cached == stamp {
+            return eh_frame.clone();
+        }
+    }
+    let eh_frame = read_eh_frame(path).ok().flatten().map(Arc::new);
+    cache.insert(path.to_path_buf(), (stamp, eh_frame.clone()));
+    eh_frame
+}
+
+// how a register is recovered from the CFA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    Undefined,
+    SameValue,
+    Offset(i64),
+    ValOffset(i64),
+    Register(u64),
+    Unsupported,
+}
+
+// how the CFA is computed, `None` if by an expression.
+type CfaRule = Option<(u64, i64)>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Row {
+    cfa: CfaRule,
+    rules: [Rule; DW_NREGS],
+}
+
+impl Row {
+    fn set(&mut self, reg: u64, rule: Rule) {
+        if let Some(r) = self.rules.get_mut(reg as usize) {
+            *r = rule;
+        }
+    }
+}
+
+// run the CFA `program` of `eh_frame` from `loc`, until the row of
+// `target` is reached. `initial` is the row set by the CIE.
+fn execute(
+    eh_frame: &EhFrame,
+    cie: &Cie,
+    program: (usize, usize),
+    row: &mut Row,
+    initial: &Row,
+    mut loc: u64,
+    target: u64,
+) -> Result<()> {
+    let mut r = Reader { bytes: &eh_frame.data[..program.1], at: program.0 };
+    let mut stack: Vec<Row> = Vec::new();
+    let factored = |r: &mut Reader| -> Result<i64> { Ok((r.uleb()? as i64).wrapping_mul(cie.data_align)) };
+    let factored_sf = |r: &mut Reader| -> Result<i64> { Ok(r.sleb()?.wrapping_mul(cie.data_align)) };
+    let skip_block = |r: &mut Reader| -> Result<()> {
+        let size = r.uleb()? as usize;
+        r.bytes(size).map(|_| ())
+    };
+    while r.at < r.bytes.len() {
+        let op = r.u8()?;
+        let delta = match (op >> 6, u64::from(op & 0x3f)) {
+            (1, delta) => Some(delta),
+            (2, reg) => {
+                let offset = factored(&mut r)?;
+                row.set(reg, Rule::Offset(offset));
+                None
+            }
+            (3, reg) => {
+                row.set(reg, initial.rules.get(reg as usize).copied().unwrap_or(Rule::SameValue));
+                None
+            }
+            _ => match op {
+                0x00 => None,
+                0x02 => Some(u64::from(r.u8()?)),
+                0x03 => Some(u64::from(r.u16()?)),
+                0x04 => Some(u64::from(r.u32()?)),
+                0x05 => {
+                    let reg = r.uleb()?;
+                    let offset = factored(&mut r)?;
+                    row.set(reg, Rule::Offset(offset));
+                    None
+                }
+                0x06 => {
+                    let reg = r.uleb()?;
+                    row.set(reg, initial.rules.get(reg as usize).copied().unwrap_or(Rule::SameValue));
+                    None
+                }
+                0x07 => {
+                    let reg = r.uleb()?;
+                    row.set(reg, Rule::Undefined);
+                    None
+                }
+                0x08 => {
+                    let reg = r.uleb()?;
+                    row.set(reg, Rule::SameValue);
+                    None
+                }
+                0x09 => {
+                    let reg = r.uleb()?;
+                    let other = r.uleb()?;
+                    row.set(reg, Rule::Register(other));
+                    None
+                }
+                0x0a => {
+                    stack.push(row.clone());
+                    None
+                }
+                0x0b => {
+                    *row = stack.pop().ok_or_else(|| Error::new(ErrorKind::Other, "unbalanced restore_state"))?;
+                    None
+                }
+                0x0c => {
+                    let reg = r.uleb()?;
+                    let offset = r.uleb()? as i64;
+                    row.cfa = Some((reg, offset));
+                    None
+                }
+                0x0d => {
+                    let reg = r.uleb()?;
+                    row.cfa = row.cfa.map(|(_, offset)| (reg, offset));
+                    None
+                }
+                0x0e => {
+                    let offset = r.uleb()? as i64;
+                    row.cfa = row.cfa.map(|(reg, _)| (reg, offset));
+                    None
+                }
+                0x0f => {
+                    skip_block(&mut r)?;
+                    row.cfa = None;
+                    None
+                }
+                0x10 | 0x16 => {
+                    let reg = r.uleb()?;
+                    skip_block(&mut r)?;
+                    row.set(reg, Rule::Unsupported);
+                    None
+                }
+                0x11 => {
+                    let reg = r.uleb()?;
+                    let offset = factored_sf(&mut r)?;
+                    row.set(reg, Rule::Offset(offset));
+                    None
+                }
+                0x12 => {
+                    let reg = r.uleb()?;
+                    let offset = factored_sf(&mut r)?;
+                    row.cfa = Some((reg, offset));
+                    None
+                }
+                0x13 => {
+                    let offset = factored_sf(&mut r)?;
+                    row.cfa = row.cfa.map(|(reg, _)| (reg, offset));
+                    None
+                }
+                0x14 => {
+                    let reg = r.uleb()?;
+                    let offset = factored(&mut r)?;
+                    row.set(reg, Rule::ValOffset(offset));
+                    None
+                }
+                0x15 => {
+                    let reg = r.uleb()?;
+                    let offset = factored_sf(&mut r)?;
+                    row.set(reg, Rule::ValOffset(offset));
+                    None
+                }
+                // DW_CFA_GNU_args_size
+                0x2e => {
+                    r.uleb()?;
+                    None
+                }
+                // DW_CFA_GNU_negative_offset_extended
+                0x2f => {
+                    let reg = r.uleb()?;
+                    let offset = factored(&mut r)?;
+                    row.set(reg, Rule::Offset(offset.wrapping_neg()));
+                    None
+                }
+                _ => {
+                    let msg = format!("unsupported CFA instruction {:#x}", op);
+                    return Err(Error::new(ErrorKind::Other, msg));
+                }
+            },
+        };
+        if let Some(delta) = delta {
+            loc = loc.wrapping_add(delta.wrapping_mul(cie.code_align));
+            if loc > target {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// the CFI row of the link time address `vaddr` in `eh_frame`.
+fn cfi_row(eh_frame: &EhFrame, vaddr: u64) -> Option<Row> {
+    let k = eh_frame.fdes.partition_point(|fde| fde.start <= vaddr);
+    let fde = eh_frame.fdes.get(k.checked_sub(1)?).filter(|fde| vaddr < fde.end)?;
+    let cie = &eh_frame.cies[fde.cie];
+    if cie.ra_register != DW_REG_RA {
+        return None;
+    }
+    let mut row = Row { cfa: None, rules: [Rule::SameValue; DW_NREGS] };
+    let initial = row.clone();
+    execute(eh_frame, cie, cie.instructions, &mut row, &initial, fde.start, u64::MAX).ok()?;
+    let initial = row.clone();
+    execute(eh_frame, cie, fde.instructions, &mut row, &initial, fde.start, vaddr).ok()?;
+    Some(row)
+}
+
+// registers needed to unwind.
+#[derive(Debug, Clone, Copy)]
+struct Regs {
+    pc: u64,
+    sp: u64,
+    fp: u64,
+}
+
+// the caller of `regs` by the CFI `row`, with a pc of 0 if `row` says
+// there is no caller (i.e.: `_start`).
+fn step_cfi<F>(row: &Row, regs: &Regs, peek: &F) -> Option<Regs>
+where
+    F: Fn(u64) -> Option<u64>,
+{
+    let cfa = match row.cfa? {
+        (DW_REG_RSP, offset) => regs.sp.wrapping_add(offset as u64),
+        (DW_REG_RBP, offset) => regs.fp.wrapping_add(offset as u64),
+        _ => return None,
+    };
+    let recover = |rule: Rule, value: u64| match rule {
+        Rule::SameValue => Some(value),
+        Rule::Offset(offset) => peek(cfa.wrapping_add(offset as u64)),
+        Rule::ValOffset(offset) => Some(cfa.wrapping_add(offset as u64)),
+        Rule::Register(DW_REG_RSP) => Some(regs.sp),
+        Rule::Register(DW_REG_RBP) => Some(regs.fp),
+        _ => None,
+    };
+    let pc = match row.rules[DW_REG_RA as usize] {
+        Rule::Undefined => 0,
+        rule => recover(rule, regs.pc)?,
+    };
+    let fp = recover(row.rules[DW_REG_RBP as usize], regs.fp)?;
+    Some(Regs { pc, sp: cfa, fp })
+}
+
+// the caller of `regs` by following `rbp`.
+fn step_frame_pointer<F>(regs: &Regs, peek: &F) -> Option<Regs>
+where
+    F: Fn(u64) -> Option<u64>,
+{
+    if regs.fp == 0 || regs.fp & 7 != 0 || regs.fp < regs.sp {
+        return None;
+    }
+    let fp = peek(regs.fp)?;
+    let pc = peek(regs.fp.wrapping_add(8))?;
+    Some(Regs { pc, sp: regs.fp.wrapping_add(16), fp })
+}
+
+fn is_executable(maps: &[ProcMapsEntry], addr: u64) -> bool {
+    maps.iter()
+        .any(|e| addr >= e.base() && addr < e.end() && e.prot() & libc::PROT_EXEC != 0)
+}
+
+/// unwind the stack of a task given its `rip`, `rsp` and `rbp`, the memory
+/// map `maps` of its process, and `peek` to read a word of its memory.
+/// unwinding stops at the outermost frame, when a frame cannot be found,
+/// or after `MAX_FRAMES` frames.
+pub fn unwind<F>(maps: &[ProcMapsEntry], rip: u64, rsp: u64, rbp: u64, peek: F) -> Vec<Frame>
+where
+    F: Fn(u64) -> Option<u64>,
+{
+    let mut regs = Regs { pc: rip, sp: rsp, fp: rbp };
+    let mut frames = vec![Frame { pc: rip, sp: rsp, method: Method::Registers }];
+    while frames.len() < MAX_FRAMES {
+        // a return address could be right after the last instruction of
+        // its function (i.e.: a call to a noreturn function).
+        let pc = if frames.len() == 1 { regs.pc } else { regs.pc.wrapping_sub(1) };
+        let cfi = symbols::load_bias(maps, pc)
+            .filter(|(file, _)| file.is_absolute())
+            .and_then(|(file, bias)| cfi_row(&*eh_frame(&file)?, pc.wrapping_sub(bias)))
+            .and_then(|row| step_cfi(&row, &regs, &peek));
+        let (next, method) = match cfi {
+            Some(next) => (next, Method::Cfi),
+            None => match step_frame_pointer(&regs, &peek) {
+                Some(next) => (next, Method::FramePointer),
+                None => break,
+            },
+        };
+        if next.pc == 0 || next.sp <= regs.sp || !is_executable(maps, next.pc) {
+            break;
+        }
+        frames.push(Frame { pc: next.pc, sp: next.sp, method });
+        regs = next;
+    }
+    frames
+}
+
+/// `addr` as `library+offset (symbol+offset)`, given the memory map `maps`
+/// of its process, or as is if it is not in a mapped file.
+pub fn symbolize(maps: &[ProcMapsEntry], addr: u64) -> String {
+    let ((file, offset), symbol) = patch_report::site_of(maps, addr);
+    let location = if file == "[anon]" { format!("{:#x}", addr) } else { format!("{}+{:#x}", file, offset) };
+    match symbol {
+        Some(symbol) => format!("{} ({})", location, symbol),
+        None => location,
+    }
+}
+
+#[inline(never)]
+fn unwind_self() -> Vec<Frame> {
+    let (rip, rsp, rbp): (u64, u64, u64);
+    unsafe {
+        std::arch::asm!("lea {}, [rip]", "mov {}, rsp", "mov {}, rbp", out(reg) rip, out(reg) rsp, out(reg) rbp);
+    }
+    let maps = decode_proc_maps(nix::unistd::getpid()).unwrap();
+    unwind(&maps, rip, rsp, rbp, |addr| {
+        if addr & 7 == 0 && addr >= rsp && addr < rsp + 0x10_0000 {
+            Some(unsafe { *(addr as *const u64) })
+        } else {
+            None
+        }
+    })
+}
+
+#[test]
+fn can_unwind_with_eh_frame() {
+    let maps = decode_proc_maps(nix::unistd::getpid()).unwrap();
+    let frames = unwind_self();
+    assert!(frames.len() > 2);
+    assert!(symbolize(&maps, frames[0].pc).contains("unwind_self"));
+    assert_eq!(frames[1].method, Method::Cfi);
+    assert!(symbolize(&maps, frames[1].pc).contains("can_unwind_with_eh_frame"));
+    assert!(frames.windows(2).all(|w| w[0].sp < w[1].sp));
+}
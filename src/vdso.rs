@@ -4,15 +4,43 @@ use std::path::PathBuf;
 use std::collections::HashMap;
 use std::vec::Vec;
 use nix::unistd;
-use nix::unistd::Pid;
 use goblin::elf::Elf;
-use log::{debug};
+use log::{debug, warn};
 
 use crate::proc::*;
 use crate::task::Task;
 use crate::remote::*;
-use crate::traced_task::TracedTask;
+use crate::traced_task::{poke_code, TracedTask};
 use crate::nr::*;
+use crate::options::*;
+
+/// how vdso functions of tracees are handled (`--vdso`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VdsoMode {
+    /// vdso is left alone, its functions are not seen by the tracer nor
+    /// the tool.
+    Keep,
+    /// vdso functions are rewritten into real syscalls, trapped (hence
+    /// patched) like any other syscall.
+    #[default]
+    Syscall,
+    /// vdso functions are rewritten into syscalls which are patched to
+    /// call the trampoline as soon as it is loaded, the tool sees them
+    /// without a seccomp stop.
+    Trampoline,
+}
+
+impl std::str::FromStr for VdsoMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(VdsoMode::Keep),
+            "syscall" => Ok(VdsoMode::Syscall),
+            "trampoline" => Ok(VdsoMode::Trampoline),
+            _ => Err(Error::new(ErrorKind::Other, format!("unknown vdso mode {}, expected keep, syscall or trampoline", s))),
+        }
+    }
+}
 
 /*
  * byte code for the new psudo vdso functions
@@ -57,34 +85,82 @@ const __vdso_gettimeofday: &[u8] = &[
     0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00,     // nopl 0x0(%rax, %rax, 1)
     0x00 ];
 
-const VDSO_SYMBOLS: &[&str] = &[
-    "__vdso_time",
-    "__vdso_clock_gettime",
-    "__vdso_getcpu",
-    "__vdso_gettimeofday" ];
+#[allow(non_upper_case_globals)]
+const __vdso_clock_getres: &[u8] = &[
+    0xb8, 0xe5, 0x00, 0x00, 0x00,                 // mov SYS_clock_getres, %eax
+    0x0f, 0x05,                                   // syscall
+    0xc3,                                         // retq
+    0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00,     // nopl 0x0(%rax, %rax, 1)
+    0x00 ];
+
+/// offset of the `syscall` in functions generated by `gen_trampoline_entry`.
+pub const VDSO_SYSCALL_OFFSET: usize = 5;
+
+/// a vdso function doing syscall `nr`, followed by `nop; nop; nop`, which
+/// is the pattern of the syscall hook for vdso functions (see
+/// `trampoline/syscall_hooks.def`).
+fn gen_trampoline_entry(nr: SyscallNo) -> Vec<u8> {
+    let mut res: Vec<u8> = vec![0xb8];                  // mov $nr, %eax
+    res.extend_from_slice(&(nr as u32).to_le_bytes());
+    res.extend_from_slice(&[
+        0x0f, 0x05,                                     // syscall
+        0x90, 0x90, 0x90,                               // nop; nop; nop
+        0xc3,                                           // retq
+        0x0f, 0x1f, 0x44, 0x00, 0x00 ]);                // nopl 0x0(%rax, %rax, 1)
+    debug_assert_eq!(res.len(), 16);
+    res
+}
+
+// (symbol, syscall, code with `VdsoMode::Syscall`).
+const VDSO_FUNCTIONS: &[(&str, SyscallNo, &[u8])] = &[
+    ("__vdso_time", SYS_time, __vdso_time),
+    ("__vdso_clock_gettime", SYS_clock_gettime, __vdso_clock_gettime),
+    ("__vdso_getcpu", SYS_getcpu, __vdso_getcpu),
+    ("__vdso_gettimeofday", SYS_gettimeofday, __vdso_gettimeofday),
+    ("__vdso_clock_getres", SYS_clock_getres, __vdso_clock_getres) ];
+
+/// a vdso function to be rewritten, at `offset` of the vdso, with `room`
+/// bytes available for the new code.
+#[derive(Debug, Clone)]
+struct VdsoPatch {
+    offset: u64,
+    room: usize,
+    syscall: SyscallNo,
+    emulated: &'static [u8],
+}
+
+impl VdsoPatch {
+    fn code(&self, mode: VdsoMode) -> Option<Vec<u8>> {
+        match mode {
+            VdsoMode::Keep => None,
+            VdsoMode::Syscall => Some(self.emulated.to_vec()),
+            VdsoMode::Trampoline => Some(gen_trampoline_entry(self.syscall)),
+        }
+    }
+}
 
 lazy_static! {
-    static ref VDSO_PATCH_INFO: HashMap<String, (u64, usize, &'static [u8])> = {
+    static ref VDSO_PATCH_INFO: HashMap<String, VdsoPatch> = {
         let info = vdso_get_symbols_info();
-        let mut res: HashMap<String, (u64, usize, &'static [u8])> = HashMap::new();
-        let funcs = &[ __vdso_time,
-                       __vdso_clock_gettime,
-                       __vdso_getcpu,
-                       __vdso_gettimeofday];
-        VDSO_SYMBOLS.iter().zip(funcs).for_each(|(k,v)| {
-            let name = String::from(*k);
-            if let Some(&(base, size)) = info.get(&name) {
-                assert!(v.len() <= size);
-                res.insert(String::from(*k), (base, size, v));
+        let mut res: HashMap<String, VdsoPatch> = HashMap::new();
+        VDSO_FUNCTIONS.iter().for_each(|&(name, syscall, emulated)| {
+            if let Some(&(offset, room)) = info.get(name) {
+                res.insert(String::from(name), VdsoPatch { offset, room, syscall, emulated });
             }
         });
         res
     };
 }
 
-// get vdso symbols offset/size from current process
+// vdso functions are 16 bytes aligned.
+const VDSO_FUNCTION_ALIGNMENT: u64 = 16;
+
+// get vdso symbols offset/room from current process
 // assuming vdso binary is the same for all processes
-// so that we don't have to decode vdso for each process
+// so that we don't have to decode vdso for each process.
+// the room of a function is its size, including the padding up to the
+// next (aligned) function, some kernels have functions smaller than the
+// code they are rewritten to, i.e.: `jmp` to a hidden function.
 fn vdso_get_symbols_info() -> HashMap<String, (u64, usize)> {
     let mut res: HashMap<String, (u64, usize)> = HashMap::new();
     decode_proc_maps(unistd::getpid())
@@ -100,14 +176,27 @@ fn vdso_get_symbols_info() -> HashMap<String, (u64, usize)> {
                     vdso.size())
             };
             Elf::parse(slice).map(|elf| {
-                let strtab = elf.dynstrtab;
+                let strtab = &elf.dynstrtab;
+                let mut starts: Vec<u64> = elf.dynsyms
+                    .iter()
+                    .filter(|sym| sym.is_function())
+                    .map(|sym| sym.st_value)
+                    .collect();
+                starts.sort_unstable();
+                let names: Vec<&str> = VDSO_FUNCTIONS.iter().map(|f| f.0).collect();
                 elf.dynsyms.iter().for_each(|sym| {
                     let sym_name = &strtab[sym.st_name];
-                    if VDSO_SYMBOLS.contains(&&sym_name) {
+                    if names.contains(&sym_name) {
                         debug_assert!(sym.is_function());
-                        res.insert(
-                            String::from(sym_name),
-                            (sym.st_value, sym.st_size as usize));
+                        let start = sym.st_value;
+                        let aligned = (start + sym.st_size + VDSO_FUNCTION_ALIGNMENT - 1) & !(VDSO_FUNCTION_ALIGNMENT - 1);
+                        let section_end = elf.section_headers
+                            .iter()
+                            .find(|sh| sh.vm_range().contains(&(start as usize)))
+                            .map_or(aligned, |sh| sh.sh_addr + sh.sh_size);
+                        let next = starts.iter().cloned().find(|&at| at > start).unwrap_or(aligned);
+                        let end = aligned.min(section_end).min(next).max(start + sym.st_size);
+                        res.insert(String::from(sym_name), (start, (end - start) as usize));
                     }
                 });
             }).ok()
@@ -115,18 +204,6 @@ fn vdso_get_symbols_info() -> HashMap<String, (u64, usize)> {
     res
 }
 
-fn patch_vdso(pid: Pid) -> Result<()> {
-    decode_proc_maps(pid)?
-        .iter()
-        .filter(|e| e.filename() == Some(&PathBuf::from("[vdso]")))
-        .cloned()
-        .next()
-        .map(|_vdso| {
-            
-        });
-    Ok(())
-}
-
 #[test]
 fn can_find_vdso() {
     let vdso = decode_proc_maps(unistd::getpid());
@@ -144,11 +221,18 @@ fn vdso_patch_info_is_valid() {
     let info = &VDSO_PATCH_INFO;
     info.iter().for_each(|i| println!("info: {:x?}", i));
     assert!(info.len() > 0);
+    assert!(info.values().all(|patch| patch.room as u64 >= VDSO_FUNCTION_ALIGNMENT));
+    let code = gen_trampoline_entry(SYS_clock_getres);
+    assert_eq!(&code[VDSO_SYSCALL_OFFSET..VDSO_SYSCALL_OFFSET + 5], &[0x0f, 0x05, 0x90, 0x90, 0x90]);
 }
 
-/// patch vdso when enabled
+/// patch vdso according to `--vdso`.
 /// @task must be in stopped state
 pub fn vdso_patch(task: &mut TracedTask) -> Result<()> {
+    let mode = get_systrace_options().vdso;
+    if mode == VdsoMode::Keep {
+        return Ok(());
+    }
     decode_proc_maps(
         task.getpid())?
         .iter()
@@ -162,11 +246,19 @@ pub fn vdso_patch(task: &mut TracedTask) -> Result<()> {
                 vdso.size() as i64,
                 (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as i64,
                 0, 0, 0).unwrap();
-            for (name, (offset, size, bytes)) in VDSO_PATCH_INFO.iter() {
-                let start = vdso.base() + offset;
-                assert!(bytes.len() <= *size);
+            for (name, patch) in VDSO_PATCH_INFO.iter() {
+                let start = vdso.base() + patch.offset;
+                let bytes = match patch.code(mode) {
+                    Some(bytes) => bytes,
+                    None => continue,
+                };
+                if bytes.len() > patch.room {
+                    warn!("{} cannot patch {}@{:x}, only {} bytes for {} bytes of code",
+                          task.getpid(), name, start, patch.room, bytes.len());
+                    continue;
+                }
                 let rptr = RemotePtr::new(start as *mut u8);
-                task.poke_bytes(rptr, bytes).unwrap();
+                task.poke_bytes(rptr, &bytes).unwrap();
                 debug!("{} patched {}@{:x}", task.getpid(), name, start);
             }
             task.untraced_syscall(
@@ -178,3 +270,56 @@ pub fn vdso_patch(task: &mut TracedTask) -> Result<()> {
         });
     Ok(())
 }
+
+/// addresses of the `syscall` of vdso functions rewritten by `vdso_patch`
+/// with `VdsoMode::Trampoline`, to be patched.
+pub fn vdso_syscall_sites(task: &TracedTask) -> Result<Vec<u64>> {
+    let vdso = decode_proc_maps(task.getpid())?
+        .into_iter()
+        .find(|e| e.filename() == Some(&PathBuf::from("[vdso]")));
+    let vdso = match vdso {
+        Some(vdso) => vdso,
+        None => return Ok(Vec::new()),
+    };
+    let mut res: Vec<u64> = Vec::new();
+    for patch in VDSO_PATCH_INFO.values() {
+        let code = gen_trampoline_entry(patch.syscall);
+        if code.len() > patch.room {
+            continue;
+        }
+        let start = vdso.base() + patch.offset;
+        // not rewritten, or patched already.
+        match task.peek_bytes(RemotePtr::new(start as *mut u8), code.len()) {
+            Ok(ref bytes) if bytes == &code => res.push(start + VDSO_SYSCALL_OFFSET as u64),
+            _otherwise => (),
+        }
+    }
+    res.sort_unstable();
+    Ok(res)
+}
+
+/// restore vdso functions patched by `vdso_patch`, the original code is
+/// read from tracer's own vdso (see `vdso_get_symbols_info`).
+/// @task must be in stopped state
+pub fn vdso_restore(task: &TracedTask) -> Result<()> {
+    let find_vdso = |pid| -> Result<Option<ProcMapsEntry>> {
+        Ok(decode_proc_maps(pid)?
+           .iter()
+           .find(|e| e.filename() == Some(&PathBuf::from("[vdso]")))
+           .cloned())
+    };
+    if get_systrace_options().vdso == VdsoMode::Keep {
+        return Ok(());
+    }
+    if let (Some(ours), Some(vdso)) = (find_vdso(unistd::getpid())?, find_vdso(task.getpid())?) {
+        for (name, patch) in VDSO_PATCH_INFO.iter() {
+            let original = unsafe {
+                std::slice::from_raw_parts((ours.base() + patch.offset) as *const u8, patch.room)
+            };
+            // NB: ptrace does not require the vdso to be writable.
+            poke_code(task, vdso.base() + patch.offset, original)?;
+            debug!("{} restored {}@{:x}", task.getpid(), name, vdso.base() + patch.offset);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! legacy vsyscall page
+//!
+//! old (i.e.: static) binaries call `gettimeofday`, `time` and `getcpu` at
+//! fixed addresses of the vsyscall page (`0xffffffffff600000`). the page
+//! has no code (`vsyscall=xonly`, or `emulate`): calls fault, and are
+//! emulated by the kernel, which runs the seccomp filter with `rip` at the
+//! entry called, then returns to the caller. these calls do not go
+//! through the vdso nor a `syscall` instruction, hence cannot be patched,
+//! and there is no syscall exit stop. with `vsyscall=none`, calls get a
+//! `SIGSEGV` instead, as they would without the tracer.
+//!
+//! at the seccomp stop, the call can be skipped (the kernel still returns
+//! to the caller), or let run. to route it to the tools, the task must be
+//! stopped at the caller (`rip` cannot be changed at the seccomp stop), a
+//! hardware breakpoint is used, which only stops the task itself.
+
+use std::io::{Error, ErrorKind, Result};
+
+use nix::sys::wait::{self, WaitStatus};
+use nix::sys::signal;
+use nix::unistd::Pid;
+
+use crate::nr::*;
+use crate::remote::*;
+use crate::task::{Task, TaskState};
+use crate::traced_task::TracedTask;
+
+pub const VSYSCALL_ADDR: u64 = 0xffff_ffff_ff60_0000;
+pub const VSYSCALL_SIZE: u64 = 0x1000;
+// entries are 1KB apart.
+const VSYSCALL_ENTRY_SIZE: u64 = 0x400;
+const VSYSCALL_ENTRIES: [SyscallNo; 3] = [SYS_gettimeofday, SYS_time, SYS_getcpu];
+
+// `DR7` enabling `DR0` as a (local) breakpoint on execution.
+const DR7_L0: u64 = 1;
+// resume flag, set by the page fault: it would mask the breakpoint.
+const EFLAGS_RF: u64 = 1 << 16;
+
+/// `addr` is in the vsyscall page.
+pub fn is_vsyscall(addr: u64) -> bool {
+    (VSYSCALL_ADDR..VSYSCALL_ADDR + VSYSCALL_SIZE).contains(&addr)
+}
+
+/// the syscall emulated by the vsyscall entry @rip, if any.
+pub fn vsyscall_at(rip: u64) -> Option<SyscallNo> {
+    if !is_vsyscall(rip) || rip & (VSYSCALL_ENTRY_SIZE - 1) != 0 {
+        return None;
+    }
+    VSYSCALL_ENTRIES
+        .get(((rip - VSYSCALL_ADDR) / VSYSCALL_ENTRY_SIZE) as usize)
+        .copied()
+}
+
+/// skip the vsyscall pending at a seccomp stop (with `regs`), the kernel
+/// returns `retval` to the caller.
+pub fn skip_vsyscall(task: &mut TracedTask, regs: libc::user_regs_struct, retval: i64) -> Result<()> {
+    let mut new_regs = regs;
+    new_regs.orig_rax = -1i64 as u64;
+    new_regs.rax = retval as u64;
+    task.setregs(new_regs)
+}
+
+fn set_debugreg(tid: Pid, k: usize, value: u64) -> Result<()> {
+    let offset = std::mem::offset_of!(libc::user, u_debugreg) + k * std::mem::size_of::<u64>();
+    let ret = unsafe { libc::ptrace(libc::PTRACE_POKEUSER, tid.as_raw(), offset, value) };
+    if ret < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// skip the vsyscall pending at a seccomp stop (with `regs`), and stop the
+/// task once the kernel returned to the caller, before the caller runs.
+/// signals delivered meanwhile are passed to the task, returns the
+/// registers at the caller.
+pub fn skip_vsyscall_to_caller(task: &mut TracedTask, regs: libc::user_regs_struct) -> Result<libc::user_regs_struct> {
+    let tid = task.gettid();
+    let caller: u64 = task.peek(RemotePtr::new(regs.rsp as *mut u64))?;
+    set_debugreg(tid, 0, caller)?;
+    set_debugreg(tid, 7, DR7_L0)?;
+    let mut new_regs = regs;
+    new_regs.eflags &= !EFLAGS_RF;
+    skip_vsyscall(task, new_regs, regs.rax as i64)?;
+    let mut sig = None;
+    let res = loop {
+        if let Err(e) = task.resume(sig) {
+            break Err(e);
+        }
+        match wait::waitpid(Some(tid), None) {
+            Ok(WaitStatus::Stopped(tid1, signal::SIGTRAP)) if tid1 == tid => break task.getregs(),
+            Ok(WaitStatus::Stopped(tid1, sig1)) if tid1 == tid => sig = Some(sig1),
+            unexpected => {
+                break Err(Error::new(
+                    ErrorKind::Other,
+                    format!("waitpid({}): unexpected status {:?}, vsyscall @{:x}", tid, unexpected, regs.rip),
+                ))
+            }
+        }
+    };
+    let _ = set_debugreg(tid, 7, 0);
+    let new_regs = res?;
+    task.state = TaskState::Stopped(signal::SIGTRAP);
+    if new_regs.rip != caller {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("vsyscall @{:x} returned to {:x}, expected {:x}", regs.rip, new_regs.rip, caller),
+        ));
+    }
+    Ok(new_regs)
+}
+
+#[test]
+fn vsyscall_entries() {
+    assert_eq!(vsyscall_at(VSYSCALL_ADDR), Some(SYS_gettimeofday));
+    assert_eq!(vsyscall_at(VSYSCALL_ADDR + 0x400), Some(SYS_time));
+    assert_eq!(vsyscall_at(VSYSCALL_ADDR + 0x800), Some(SYS_getcpu));
+    assert_eq!(vsyscall_at(VSYSCALL_ADDR + 0xc00), None);
+    assert_eq!(vsyscall_at(VSYSCALL_ADDR + 0x10), None);
+    assert!(is_vsyscall(VSYSCALL_ADDR + 0xfff) && !is_vsyscall(VSYSCALL_ADDR - 1));
+}
@@ -0,0 +1,625 @@
+//! x86-64 instruction length decoder and relocator
+//!
+//! only what is needed to move instructions around a `syscall` is decoded:
+//! the length, RIP-relative memory operands and relative branches. legacy,
+//! REX and VEX prefixes are supported, EVEX and 3DNow! are not.
+
+use std::io::{Error, ErrorKind, Result};
+
+/// kind of a decoded instruction, as far as control flow is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsnKind {
+    /// falls through to the next instruction
+    Normal,
+    /// `syscall`
+    Syscall,
+    /// `ret`, `jmp`, `ud2`.. never falls through
+    Terminator,
+    /// `jcc rel`, `call rel`, falls through (eventually)
+    Branch,
+    /// `jmp rel`, never falls through
+    Jump,
+    /// `loop`, `jrcxz`.. cannot be relocated
+    Unsupported,
+}
+
+/// a decoded instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Insn {
+    pub len: usize,
+    pub kind: InsnKind,
+    // offset of the opcode (after prefixes)
+    opcode_offset: usize,
+    /// offset of the `disp32` of a RIP-relative memory operand
+    pub rip_disp: Option<usize>,
+    /// offset and size of the relative branch operand
+    pub branch_rel: Option<(usize, usize)>,
+}
+
+impl Insn {
+    /// branch target of a relative branch at `address`.
+    pub fn branch_target(&self, bytes: &[u8], address: u64) -> Option<u64> {
+        let (offset, size) = self.branch_rel?;
+        let rel = read_signed(&bytes[offset..offset + size]);
+        Some((address as i64 + self.len as i64 + rel) as u64)
+    }
+}
+
+fn read_signed(bytes: &[u8]) -> i64 {
+    match bytes.len() {
+        1 => bytes[0] as i8 as i64,
+        2 => i16::from_le_bytes([bytes[0], bytes[1]]) as i64,
+        _ => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64,
+    }
+}
+
+// opcode maps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Map {
+    OneByte,
+    TwoByte,
+    ThreeByte38,
+    ThreeByte3a,
+}
+
+// immediate size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Imm {
+    None,
+    Byte,
+    Word,
+    // 16 or 32 bits
+    Z,
+    // 16, 32 or 64 bits (`mov r, imm`)
+    V,
+    // `enter`: iw + ib
+    Enter,
+    // `mov al, moffs`: address size
+    Moffs,
+    // `test r/m, imm` is the only /0 and /1 of `f6` (ib) and `f7` (iz)
+    TestB,
+    TestZ,
+}
+
+// (modrm, immediate) of primary opcodes, `None` for invalid ones or prefixes.
+fn primary(op: u8) -> Option<(bool, Imm)> {
+    let res = match op {
+        0x00..=0x3f => match op & 7 {
+            0..=3 => (true, Imm::None),
+            4 => (false, Imm::Byte),
+            5 => (false, Imm::Z),
+            _ => return None,
+        },
+        0x50..=0x5f => (false, Imm::None),
+        0x63 => (true, Imm::None),
+        0x68 => (false, Imm::Z),
+        0x69 => (true, Imm::Z),
+        0x6a => (false, Imm::Byte),
+        0x6b => (true, Imm::Byte),
+        0x6c..=0x6f => (false, Imm::None),
+        0x70..=0x7f => (false, Imm::Byte),
+        0x80 | 0x83 => (true, Imm::Byte),
+        0x81 => (true, Imm::Z),
+        0x84..=0x8f => (true, Imm::None),
+        0x90..=0x99 | 0x9b..=0x9f => (false, Imm::None),
+        0xa0..=0xa3 => (false, Imm::Moffs),
+        0xa4..=0xa7 | 0xaa..=0xaf => (false, Imm::None),
+        0xa8 => (false, Imm::Byte),
+        0xa9 => (false, Imm::Z),
+        0xb0..=0xb7 => (false, Imm::Byte),
+        0xb8..=0xbf => (false, Imm::V),
+        0xc0 | 0xc1 | 0xc6 => (true, Imm::Byte),
+        0xc2 | 0xca => (false, Imm::Word),
+        0xc3 | 0xc9 | 0xcb | 0xcc | 0xcf => (false, Imm::None),
+        0xc7 => (true, Imm::Z),
+        0xc8 => (false, Imm::Enter),
+        0xcd => (false, Imm::Byte),
+        0xd0..=0xd3 | 0xd8..=0xdf => (true, Imm::None),
+        0xd7 => (false, Imm::None),
+        0xe0..=0xe7 => (false, Imm::Byte),
+        0xe8 | 0xe9 => (false, Imm::Z),
+        0xeb => (false, Imm::Byte),
+        0xec..=0xef | 0xf1 | 0xf4 | 0xf5 | 0xf8..=0xfd => (false, Imm::None),
+        0xf6 => (true, Imm::TestB),
+        0xf7 => (true, Imm::TestZ),
+        0xfe | 0xff => (true, Imm::None),
+        _ => return None,
+    };
+    Some(res)
+}
+
+// (modrm, immediate) of `0f xx` opcodes.
+fn map0f(op: u8) -> Option<(bool, Imm)> {
+    let res = match op {
+        0x00..=0x03 | 0x0d | 0x10..=0x1f | 0x20..=0x23 | 0x28..=0x2f => (true, Imm::None),
+        0x05..=0x09 | 0x0b | 0x30..=0x37 | 0x77 | 0xa0..=0xa2 | 0xa8..=0xaa | 0xc8..=0xcf => {
+            (false, Imm::None)
+        }
+        0x40..=0x6f | 0x74..=0x76 | 0x78 | 0x79 | 0x7c..=0x7f | 0x90..=0x9f => (true, Imm::None),
+        0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 => (true, Imm::Byte),
+        0x80..=0x8f => (false, Imm::Z),
+        0xa3 | 0xa5 | 0xab | 0xad..=0xb9 | 0xbb..=0xc1 | 0xc3 | 0xc7 | 0xd0..=0xff => {
+            (true, Imm::None)
+        }
+        _ => return None,
+    };
+    Some(res)
+}
+
+/// decode the instruction at the beginning of `bytes`, `None` if it is
+/// invalid, unsupported or truncated.
+pub fn decode(bytes: &[u8]) -> Option<Insn> {
+    let mut k = 0;
+    let mut opsize16 = false;
+    let mut addrsize32 = false;
+    // legacy prefixes
+    while k < bytes.len() && k < 14 {
+        match bytes[k] {
+            0x66 => opsize16 = true,
+            0x67 => addrsize32 = true,
+            0xf0 | 0xf2 | 0xf3 | 0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 => (),
+            _ => break,
+        }
+        k += 1;
+    }
+    let mut rex_w = false;
+    if k < bytes.len() && bytes[k] & 0xf0 == 0x40 {
+        rex_w = bytes[k] & 8 != 0;
+        k += 1;
+    }
+    let mut map = Map::OneByte;
+    let mut vex = false;
+    match *bytes.get(k)? {
+        0x0f => {
+            k += 1;
+            map = match *bytes.get(k)? {
+                0x38 => {
+                    k += 1;
+                    Map::ThreeByte38
+                }
+                0x3a => {
+                    k += 1;
+                    Map::ThreeByte3a
+                }
+                _ => Map::TwoByte,
+            };
+        }
+        0xc5 => {
+            vex = true;
+            k += 2;
+            map = Map::TwoByte;
+        }
+        0xc4 => {
+            vex = true;
+            map = match bytes.get(k + 1)? & 0x1f {
+                1 => Map::TwoByte,
+                2 => Map::ThreeByte38,
+                3 => Map::ThreeByte3a,
+                _ => return None,
+            };
+            rex_w = *bytes.get(k + 2)? & 0x80 != 0;
+            k += 3;
+        }
+        // EVEX
+        0x62 => return None,
+        _ => (),
+    }
+    let opcode_offset = k;
+    let op = *bytes.get(k)?;
+    k += 1;
+    let (has_modrm, imm) = match map {
+        Map::OneByte => primary(op)?,
+        Map::TwoByte if vex => match op {
+            0x77 => (false, Imm::None),
+            0x70..=0x73 | 0xc2 | 0xc4..=0xc6 => (true, Imm::Byte),
+            _ => (true, Imm::None),
+        },
+        Map::TwoByte => map0f(op)?,
+        Map::ThreeByte38 => (true, Imm::None),
+        Map::ThreeByte3a => (true, Imm::Byte),
+    };
+
+    let mut rip_disp = None;
+    let mut modrm_reg = 0;
+    if has_modrm {
+        let modrm = *bytes.get(k)?;
+        k += 1;
+        let (md, reg, rm) = (modrm >> 6, (modrm >> 3) & 7, modrm & 7);
+        modrm_reg = reg;
+        if md != 3 {
+            if rm == 4 {
+                let sib = *bytes.get(k)?;
+                k += 1;
+                if md == 0 && sib & 7 == 5 {
+                    k += 4;
+                }
+            } else if md == 0 && rm == 5 {
+                rip_disp = Some(k);
+                k += 4;
+            }
+            match md {
+                1 => k += 1,
+                2 => k += 4,
+                _ => (),
+            }
+        }
+    }
+    let z = if opsize16 { 2 } else { 4 };
+    k += match imm {
+        Imm::None => 0,
+        Imm::Byte => 1,
+        Imm::Word => 2,
+        Imm::Z => z,
+        Imm::V => if rex_w { 8 } else { z },
+        Imm::Enter => 3,
+        Imm::Moffs => if addrsize32 { 4 } else { 8 },
+        Imm::TestB => if modrm_reg < 2 { 1 } else { 0 },
+        Imm::TestZ => if modrm_reg < 2 { z } else { 0 },
+    };
+    if k > bytes.len() {
+        return None;
+    }
+
+    let mut branch_rel = None;
+    let kind = match (map, op) {
+        (Map::OneByte, 0x70..=0x7f) => {
+            branch_rel = Some((k - 1, 1));
+            InsnKind::Branch
+        }
+        (Map::OneByte, 0xe8) => {
+            branch_rel = Some((k - 4, 4));
+            InsnKind::Branch
+        }
+        (Map::OneByte, 0xe9) => {
+            branch_rel = Some((k - 4, 4));
+            InsnKind::Jump
+        }
+        (Map::OneByte, 0xeb) => {
+            branch_rel = Some((k - 1, 1));
+            InsnKind::Jump
+        }
+        (Map::OneByte, 0xe0..=0xe3) => InsnKind::Unsupported,
+        (Map::OneByte, 0xc2) | (Map::OneByte, 0xc3) | (Map::OneByte, 0xca)
+        | (Map::OneByte, 0xcb) | (Map::OneByte, 0xcf) | (Map::OneByte, 0xf4) => InsnKind::Terminator,
+        (Map::OneByte, 0xff) if modrm_reg == 4 || modrm_reg == 5 => InsnKind::Terminator,
+        (Map::TwoByte, 0x80..=0x8f) if !vex => {
+            branch_rel = Some((k - z, z));
+            InsnKind::Branch
+        }
+        (Map::TwoByte, 0x05) if !vex => InsnKind::Syscall,
+        (Map::TwoByte, 0x0b) if !vex => InsnKind::Terminator,
+        _ => InsnKind::Normal,
+    };
+    Some(Insn {
+        len: k,
+        kind,
+        opcode_offset,
+        rip_disp,
+        branch_rel,
+    })
+}
+
+fn out_of_range(what: &str, from: u64, to: u64) -> Error {
+    Error::new(
+        ErrorKind::Other,
+        format!("{} @{:x} cannot be relocated to {:x}: out of range", what, from, to),
+    )
+}
+
+/// re-emit instruction `bytes` originally at `from`, to address `to`:
+/// RIP-relative operands are fixed up, short branches become near branches,
+/// hence the result can be longer than the original.
+pub fn relocate(bytes: &[u8], from: u64, to: u64) -> Result<Vec<u8>> {
+    let insn = decode(bytes).ok_or_else(|| {
+        Error::new(ErrorKind::Other, format!("cannot decode {:02x?} @{:x}", bytes, from))
+    })?;
+    let bytes = &bytes[..insn.len];
+    if insn.kind == InsnKind::Unsupported {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("{:02x?} @{:x} cannot be relocated", bytes, from),
+        ));
+    }
+    if let Some(offset) = insn.rip_disp {
+        let disp = read_signed(&bytes[offset..offset + 4]);
+        let new_disp = disp + from as i64 - to as i64;
+        if new_disp != new_disp as i32 as i64 {
+            return Err(out_of_range("rip-relative operand", from, to));
+        }
+        let mut res = bytes.to_vec();
+        res[offset..offset + 4].copy_from_slice(&(new_disp as i32).to_le_bytes());
+        return Ok(res);
+    }
+    let target = match insn.branch_target(bytes, from) {
+        Some(target) => target,
+        None => return Ok(bytes.to_vec()),
+    };
+    // prefixes are kept as is, i.e.: `bnd jmp`
+    let mut res = bytes[..insn.opcode_offset].to_vec();
+    let op = bytes[insn.opcode_offset];
+    match op {
+        0x70..=0x7f => res.extend_from_slice(&[0x0f, 0x80 + (op - 0x70)]),
+        0xeb => res.push(0xe9),
+        _ => res.extend_from_slice(&bytes[insn.opcode_offset..insn.branch_rel.unwrap().0]),
+    }
+    let rel = target as i64 - (to as i64 + res.len() as i64 + 4);
+    if rel != rel as i32 as i64 {
+        return Err(out_of_range("branch", from, to));
+    }
+    res.extend_from_slice(&(rel as i32).to_le_bytes());
+    Ok(res)
+}
+
+/// size of the jump patched over a syscall site: `jmp rel32`.
+pub const SITE_JUMP_SIZE: usize = 5;
+
+/// bytes decoded backwards from a syscall site.
+pub const RELOCATE_LOOKBEHIND: usize = 32;
+
+/// instructions around a `syscall` covering `SITE_JUMP_SIZE` bytes, which
+/// are to be moved out of the way (see `relocate`), so that the site can
+/// be patched with a jump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyscallSite {
+    /// address of the first covered instruction
+    pub address: u64,
+    /// covered bytes, including the `syscall`
+    pub len: usize,
+    /// covered instructions before the `syscall`, with their address
+    pub before: Vec<(u64, Vec<u8>)>,
+    /// covered instructions after the `syscall`, with their address
+    pub after: Vec<(u64, Vec<u8>)>,
+}
+
+// instructions decoded from `code[start..]` (at `base + start`), until
+// `end`, or `None` if the last instruction does not end at `end`.
+fn decode_until(code: &[u8], base: u64, start: usize, end: usize) -> Option<Vec<(u64, Insn)>> {
+    let mut res = Vec::new();
+    let mut k = start;
+    while k < end {
+        let insn = decode(&code[k..])?;
+        res.push((base + k as u64, insn.clone()));
+        k += insn.len;
+    }
+    if k == end {
+        Some(res)
+    } else {
+        None
+    }
+}
+
+/// find instructions to be relocated for the `syscall` at `syscall`, given
+/// (readable) `code` mapped at `base`. instructions following the
+/// `syscall` are preferred, because they are decoded from a known
+/// instruction boundary, instructions before the `syscall` are decoded
+/// from as far back as possible (up to `RELOCATE_LOOKBEHIND` bytes).
+/// either way, none of the instructions can branch into the covered range.
+pub fn cover_syscall_site(code: &[u8], base: u64, syscall: u64) -> Option<SyscallSite> {
+    if syscall < base || syscall + 2 > base + code.len() as u64 {
+        return None;
+    }
+    let at = (syscall - base) as usize;
+    if decode(&code[at..])?.kind != InsnKind::Syscall {
+        return None;
+    }
+    let site = cover_after(code, base, at).or_else(|| cover_before(code, base, at))?;
+    let covered = site.address + 1..site.address + site.len as u64;
+    let branches_in = site.before.iter().chain(site.after.iter()).any(|(address, bytes)| {
+        decode(bytes)
+            .and_then(|insn| insn.branch_target(bytes, *address))
+            .is_some_and(|target| covered.contains(&target))
+    });
+    if branches_in {
+        None
+    } else {
+        Some(site)
+    }
+}
+
+fn cover_after(code: &[u8], base: u64, at: usize) -> Option<SyscallSite> {
+    let mut after = Vec::new();
+    let mut k = at + 2;
+    while k - at < SITE_JUMP_SIZE {
+        let insn = decode(&code[k..])?;
+        match insn.kind {
+            InsnKind::Syscall | InsnKind::Unsupported => return None,
+            // anything after is not necessarily code.
+            InsnKind::Terminator | InsnKind::Jump if k + insn.len - at < SITE_JUMP_SIZE => return None,
+            _ => (),
+        }
+        after.push((base + k as u64, code[k..k + insn.len].to_vec()));
+        k += insn.len;
+    }
+    Some(SyscallSite {
+        address: base + at as u64,
+        len: k - at,
+        before: Vec::new(),
+        after,
+    })
+}
+
+fn cover_before(code: &[u8], base: u64, at: usize) -> Option<SyscallSite> {
+    // the earliest decoding which lands on `at`, the longer it is, the
+    // likelier it is in sync with actual instruction boundaries.
+    let insns = (at.saturating_sub(RELOCATE_LOOKBEHIND)..at)
+        .find_map(|start| decode_until(code, base, start, at))?;
+    let first = insns
+        .iter()
+        .rposition(|(address, _)| at + 2 - (address - base) as usize >= SITE_JUMP_SIZE)?;
+    let covered = &insns[first..];
+    if covered.iter().any(|(_, insn)| insn.kind != InsnKind::Normal) {
+        return None;
+    }
+    let start = (covered[0].0 - base) as usize;
+    let before = covered
+        .iter()
+        .map(|(address, insn)| {
+            let k = (address - base) as usize;
+            (*address, code[k..k + insn.len].to_vec())
+        })
+        .collect();
+    Some(SyscallSite {
+        address: base + start as u64,
+        len: at + 2 - start,
+        before,
+        after: Vec::new(),
+    })
+}
+
+/// addresses of (relative) branches in `code` mapped at `base`, which
+/// land strictly inside `start..end`, `code` is decoded linearly, hence
+/// it must be a whole function. returns `None` if `code` cannot be decoded.
+/// NB: indirect branches are not known.
+pub fn branches_into(code: &[u8], base: u64, start: u64, end: u64) -> Option<Vec<u64>> {
+    let mut res = Vec::new();
+    let mut k = 0;
+    while k < code.len() {
+        let insn = decode(&code[k..])?;
+        let address = base + k as u64;
+        if let Some(target) = insn.branch_target(&code[k..], address) {
+            if target > start && target < end {
+                res.push(address);
+            }
+        }
+        k += insn.len;
+    }
+    Some(res)
+}
+
+/// offsets of `syscall` instructions in `code`, which is decoded linearly
+/// like `branches_into`. returns `None` if `code` cannot be decoded.
+pub fn syscall_sites(code: &[u8]) -> Option<Vec<usize>> {
+    let mut res = Vec::new();
+    let mut k = 0;
+    while k < code.len() {
+        let insn = decode(&code[k..])?;
+        if insn.kind == InsnKind::Syscall {
+            res.push(k);
+        }
+        k += insn.len;
+    }
+    Some(res)
+}
+
+/// bytes to patch `site` with a jump to `target`, the remaining bytes
+/// are `int3`: nothing should jump there.
+pub fn gen_site_jump(site: &SyscallSite, target: u64) -> Option<Vec<u8>> {
+    let rel = target as i64 - (site.address as i64 + SITE_JUMP_SIZE as i64);
+    if rel != rel as i32 as i64 {
+        return None;
+    }
+    let mut res = vec![0xe9];
+    res.extend_from_slice(&(rel as i32).to_le_bytes());
+    res.resize(site.len, 0xcc);
+    Some(res)
+}
+
+#[test]
+fn x86_decode_sanity_check() {
+    let cases: &[(&[u8], usize, InsnKind)] = &[
+        (&[0x0f, 0x05], 2, InsnKind::Syscall),
+        (&[0xc3], 1, InsnKind::Terminator),
+        (&[0x48, 0x89, 0xf8], 3, InsnKind::Normal),                     // mov %rdi,%rax
+        (&[0xb8, 0x27, 0x00, 0x00, 0x00], 5, InsnKind::Normal),         // mov $0x27,%eax
+        (&[0x48, 0xb8, 1, 2, 3, 4, 5, 6, 7, 8], 10, InsnKind::Normal),  // movabs
+        (&[0x48, 0x3d, 0x01, 0xf0, 0xff, 0xff], 6, InsnKind::Normal),   // cmp $-4095,%rax
+        (&[0x48, 0x8b, 0x3c, 0x24], 4, InsnKind::Normal),               // mov (%rsp),%rdi
+        (&[0x48, 0x8b, 0x05, 0, 0, 0, 0], 7, InsnKind::Normal),         // mov 0(%rip),%rax
+        (&[0x64, 0x89, 0x04, 0x25, 0, 0, 0, 0], 8, InsnKind::Normal),   // mov %eax,%fs:0
+        (&[0xf7, 0xd8], 2, InsnKind::Normal),                           // neg %eax
+        (&[0xf7, 0xc7, 1, 0, 0, 0], 6, InsnKind::Normal),               // test $1,%edi
+        (&[0x0f, 0x1f, 0x84, 0, 0, 0, 0, 0], 8, InsnKind::Normal),      // nopl
+        (&[0x66, 0x0f, 0x1f, 0x44, 0, 0], 6, InsnKind::Normal),         // nopw
+        (&[0x73, 0x10], 2, InsnKind::Branch),                           // jae
+        (&[0x0f, 0x87, 0, 1, 0, 0], 6, InsnKind::Branch),               // ja
+        (&[0xe9, 0, 0, 0, 0], 5, InsnKind::Jump),
+        (&[0xff, 0xe0], 2, InsnKind::Terminator),                       // jmp *%rax
+        (&[0xe3, 0x02], 2, InsnKind::Unsupported),                      // jrcxz
+        (&[0xc5, 0xf8, 0x77], 3, InsnKind::Normal),                     // vzeroupper
+        (&[0xc4, 0xe2, 0x79, 0x18, 0x05, 0, 0, 0, 0], 9, InsnKind::Normal), // vbroadcastss
+    ];
+    for (bytes, len, kind) in cases {
+        let insn = decode(bytes).unwrap_or_else(|| panic!("cannot decode {:02x?}", bytes));
+        assert_eq!((insn.len, insn.kind), (*len, *kind), "{:02x?}", bytes);
+    }
+    assert_eq!(decode(&[0x48, 0x8b]), None);
+    assert_eq!(decode(&[0x62, 0xf1, 0x7c, 0x48, 0x10, 0x00]), None);
+}
+
+#[test]
+fn x86_decode_truncated() {
+    // every proper prefix of these must be rejected, not read past the end.
+    let cases: &[&[u8]] = &[
+        &[0xc5, 0xf8, 0x77],                                 // vzeroupper
+        &[0xc4, 0xe2, 0x79, 0x18, 0x05, 0, 0, 0, 0],         // vbroadcastss
+        &[0x62, 0xf1, 0x7c, 0x48, 0x10, 0x00],               // evex vmovups
+        &[0x48, 0x8b, 0x3c, 0x24],                           // modrm + sib
+        &[0x48, 0x8b, 0x05, 0, 0, 0, 0],                     // modrm + disp32
+        &[0x0f, 0x1f, 0x84, 0, 0, 0, 0, 0],                  // modrm + sib + disp32
+    ];
+    for bytes in cases {
+        for n in 0..bytes.len() {
+            assert_eq!(decode(&bytes[..n]), None, "{:02x?}", &bytes[..n]);
+        }
+    }
+}
+
+#[test]
+fn x86_relocate_sanity_check() {
+    // mov 0x10(%rip),%rax @0x1000 => target 0x1017
+    let bytes = [0x48, 0x8b, 0x05, 0x10, 0, 0, 0];
+    assert_eq!(decode(&bytes).unwrap().rip_disp, Some(3));
+    let moved = relocate(&bytes, 0x1000, 0x2000).unwrap();
+    assert_eq!(moved, vec![0x48, 0x8b, 0x05, 0x10, 0xf0, 0xff, 0xff]);
+    // jae +0x10 @0x1000 => target 0x1012
+    let bytes = [0x73, 0x10];
+    let moved = relocate(&bytes, 0x1000, 0x2000).unwrap();
+    assert_eq!(moved, vec![0x0f, 0x83, 0x0c, 0xf0, 0xff, 0xff]);
+    let moved_insn = decode(&moved).unwrap();
+    assert_eq!(moved_insn.branch_target(&moved, 0x2000), Some(0x1012));
+    // too far away
+    assert!(relocate(&bytes, 0x1000, 0x1_0000_2000).is_err());
+    // jrcxz
+    let bytes = [0xe3, 0x02];
+    assert!(relocate(&bytes, 0x1000, 0x2000).is_err());
+}
+
+#[test]
+fn x86_cover_syscall_site_sanity_check() {
+    // mov $0x27,%eax; syscall; ret
+    let code = [0xb8, 0x27, 0, 0, 0, 0x0f, 0x05, 0xc3];
+    let site = cover_syscall_site(&code, 0x1000, 0x1005).unwrap();
+    assert_eq!((site.address, site.len), (0x1000, 7));
+    assert_eq!(site.before, vec![(0x1000, vec![0xb8, 0x27, 0, 0, 0])]);
+    assert!(site.after.is_empty());
+    // syscall; mov %rax,%rdi; test %rax,%rax
+    let code = [0x0f, 0x05, 0x48, 0x89, 0xc7, 0x48, 0x85, 0xc0];
+    let site = cover_syscall_site(&code, 0x1000, 0x1000).unwrap();
+    assert_eq!((site.address, site.len), (0x1000, 5));
+    assert_eq!(site.after.len(), 1);
+    assert_eq!(
+        gen_site_jump(&site, 0x2000).unwrap(),
+        vec![0xe9, 0xfb, 0x0f, 0, 0]
+    );
+    // syscall; je into the covered range; nop
+    let code = [0x0f, 0x05, 0x74, 0xfd, 0x90];
+    assert_eq!(cover_syscall_site(&code, 0x1000, 0x1000), None);
+}
+
+#[test]
+fn x86_branches_into_sanity_check() {
+    // mov $0xe6,%eax; syscall; neg %eax; ret; jmp into the syscall
+    let code = [0xb8, 0xe6, 0, 0, 0, 0x0f, 0x05, 0xf7, 0xd8, 0xc3, 0xeb, 0xfb];
+    assert_eq!(branches_into(&code, 0x1000, 0x1005, 0x100a), Some(vec![0x100a]));
+    // the window start is not inside
+    assert_eq!(branches_into(&code, 0x1000, 0x1000, 0x1005), Some(vec![]));
+    assert_eq!(branches_into(&code[..11], 0x1000, 0x1000, 0x1005), None);
+}
+
+#[test]
+fn x86_syscall_sites_sanity_check() {
+    // mov $0x50f00,%eax; syscall; cmp $-0x1000,%rax; ret
+    let code = [0xb8, 0x00, 0x0f, 0x05, 0, 0x0f, 0x05, 0x48, 0x3d, 0x00, 0xf0, 0xff, 0xff, 0xc3];
+    assert_eq!(syscall_sites(&code), Some(vec![5]));
+    assert_eq!(syscall_sites(&code[..6]), None);
+}
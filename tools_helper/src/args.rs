@@ -0,0 +1,62 @@
+//! options passed to tools by `systrace --tool-arg key=value`
+//!
+//! the tracer writes options into the private page (see
+//! `SYSTRACE_TOOL_ARGS_ADDR`) before the tracee runs; this module only
+//! borrows from there, hence it is safe to call from `captured_syscall`:
+//! no allocation, no syscall.
+//!
+//! NB: options are global, tools in the same chain can use prefixed keys
+//! (i.e.: `fault.errno=5`) to avoid clashes.
+
+use core::slice;
+use core::str;
+
+use crate::consts;
+
+/// iterator over `(key, value)` options, in command line order.
+pub struct ToolArgs {
+    rest: &'static [u8],
+}
+
+impl Iterator for ToolArgs {
+    type Item = (&'static str, &'static str);
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.rest.is_empty() {
+            let end = self.rest.iter().position(|&c| c == 0).unwrap_or(self.rest.len());
+            let entry = &self.rest[..end];
+            self.rest = &self.rest[core::cmp::min(end + 1, self.rest.len())..];
+            if let Ok(s) = str::from_utf8(entry) {
+                match s.find('=') {
+                    Some(i) => return Some((&s[..i], &s[i + 1..])),
+                    None => return Some((s, "")),
+                }
+            }
+        }
+        None
+    }
+}
+
+/// all options passed by `--tool-arg`.
+pub fn tool_args() -> ToolArgs {
+    let size_ptr = consts::SYSTRACE_LOCAL_TOOL_ARGS_SIZE as *const u64;
+    let size = unsafe { core::ptr::read_volatile(size_ptr) } as usize;
+    let size = core::cmp::min(size, consts::SYSTRACE_TOOL_ARGS_MAX_SIZE);
+    let rest = unsafe { slice::from_raw_parts(consts::SYSTRACE_TOOL_ARGS_ADDR as *const u8, size) };
+    ToolArgs { rest }
+}
+
+/// value of option `key`, the last one wins if `key` is given more than once.
+pub fn get_tool_arg(key: &str) -> Option<&'static str> {
+    tool_args().filter(|(k, _)| *k == key).map(|(_, v)| v).last()
+}
+
+#[test]
+fn tool_args_can_parse() {
+    let args = ToolArgs { rest: b"fault.errno=5\0verbose\0\xff=1\0k=a=b\0k=c" };
+    assert_eq!(
+        args.collect::<Vec<_>>(),
+        vec![("fault.errno", "5"), ("verbose", ""), ("k", "a=b"), ("k", "c")]
+    );
+    assert_eq!(ToolArgs { rest: b"" }.next(), None);
+    assert_eq!(ToolArgs { rest: b"k=v\0" }.collect::<Vec<_>>(), vec![("k", "v")]);
+}
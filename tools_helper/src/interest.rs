@@ -0,0 +1,51 @@
+//! declare which syscalls a tool is interested in
+//!
+//! systrace reads the `SYSTRACE_INTEREST` bitmap exported by each tool,
+//! syscalls no tool is interested in are allowed by the seccomp filter:
+//! they are neither patched nor ptraced, hence never reach
+//! `captured_syscall`. tools not exporting `SYSTRACE_INTEREST` are
+//! interested in all syscalls.
+//!
+//! ```
+//! use syscalls::SyscallNo::{SYS_open, SYS_openat};
+//! use tools_helper::systrace_interest;
+//!
+//! systrace_interest!(SYS_open, SYS_openat);
+//! ```
+
+use crate::consts;
+
+/// syscall interest bitmap of `nrs`.
+pub const fn syscall_interest(nrs: &[i32]) -> [u64; consts::SYSTRACE_INTEREST_WORDS] {
+    let mut res = [0u64; consts::SYSTRACE_INTEREST_WORDS];
+    let mut k = 0;
+    while k < nrs.len() {
+        let nr = nrs[k] as usize;
+        res[nr / 64] |= 1u64 << (nr % 64);
+        k += 1;
+    }
+    res
+}
+
+/// export `SYSTRACE_INTEREST` for the given syscalls.
+#[macro_export]
+macro_rules! systrace_interest {
+    ($($nr:expr),* $(,)?) => {
+        #[no_mangle]
+        #[used]
+        pub static SYSTRACE_INTEREST: [u64; $crate::consts::SYSTRACE_INTEREST_WORDS] =
+            $crate::interest::syscall_interest(&[$($nr as i32),*]);
+    };
+}
+
+#[test]
+fn syscall_interest_sanity_check() {
+    let nrs = [0, 2, 63, 64, 257, 511];
+    let bitmap = syscall_interest(&nrs);
+    for nr in 0..consts::SYSTRACE_INTEREST_WORDS * 64 {
+        let is_set = bitmap[nr / 64] & (1u64 << (nr % 64)) != 0;
+        assert_eq!(is_set, nrs.contains(&(nr as i32)), "syscall {}", nr);
+    }
+    assert_eq!(bitmap[0], 1 | 1 << 2 | 1 << 63);
+    assert_eq!(syscall_interest(&[]), [0u64; consts::SYSTRACE_INTEREST_WORDS]);
+}
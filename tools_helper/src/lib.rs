@@ -8,6 +8,11 @@ pub mod spinlock;
 pub mod consts;
 pub mod state;
 pub mod counter;
+pub mod tool;
+pub mod args;
+pub mod interest;
 
 pub use counter::note_syscall;
 pub use counter::NoteInfo;
+pub use tool::{Action, SyscallCtx, Tool};
+pub use args::{get_tool_arg, tool_args};
@@ -0,0 +1,296 @@
+//! typed interface for writing systrace tools
+//!
+//! a tool implements `Tool`, then calls `systrace_tool!` once, which
+//! generates everything libsystrace-trampoline.so expects from a tool:
+//! the `captured_syscall_enter`/`captured_syscall`/`captured_syscall_exit`
+//! C ABI entry points, the `systrace_on_*` lifecycle callbacks (called by
+//! the tracer),
+//! plus a constructor (`logger::init` then `Tool::init`) and a destructor
+//! (`Tool::fini`). Callbacks are wrapped with `catch_unwind`, a panicking
+//! callback falls back to the default behavior instead of unwinding into
+//! the trampoline.
+//!
+//! NB: callbacks run in the same context as `captured_syscall`, the same
+//! restrictions as the logger apply: avoid allocations and use
+//! `untraced_syscall` for any syscall.
+
+use core::panic::AssertUnwindSafe;
+use std::panic;
+
+use syscalls::*;
+
+/// a syscall captured by the trampoline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallCtx {
+    pub no: i32,
+    pub args: [i64; 6],
+}
+
+impl SyscallCtx {
+    pub fn new(no: i32, args: [i64; 6]) -> Self {
+        SyscallCtx { no, args }
+    }
+    pub fn syscall_no(&self) -> SyscallNo {
+        SyscallNo::from(self.no)
+    }
+    /// run the syscall, untraced by seccomp.
+    pub fn untraced_syscall(&self) -> i64 {
+        let a = &self.args;
+        unsafe { untraced_syscall(self.no, a[0], a[1], a[2], a[3], a[4], a[5]) }
+    }
+    /// run the syscall on behalf of the program, untraced by seccomp, but
+    /// subject to the policy rules.
+    pub fn forwarded_syscall(&self) -> i64 {
+        let a = &self.args;
+        unsafe { forwarded_syscall(self.no, a[0], a[1], a[2], a[3], a[4], a[5]) }
+    }
+}
+
+/// `struct syscall_info` from `scinfo.h`.
+#[repr(C)]
+pub struct SyscallInfo {
+    pub no: u64,
+    pub args: [u64; 6],
+}
+
+/// what to do with a captured syscall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// run the syscall as is.
+    Continue,
+    /// run a different syscall (or the same one with different arguments).
+    Rewrite(SyscallCtx),
+    /// skip the syscall, the tracee sees the given value as its result.
+    Return(i64),
+}
+
+pub trait Tool: Sync {
+    /// called once when the tool is loaded, after the logger is initialized.
+    fn init(&self) {}
+    /// called when a syscall is captured, before it runs.
+    fn on_syscall(&self, _ctx: &SyscallCtx) -> Action {
+        Action::Continue
+    }
+    /// called after the syscall returned `ret`, with the original
+    /// arguments; returns the value seen by the tracee.
+    fn on_syscall_exit(&self, _ctx: &SyscallCtx, ret: i64) -> i64 {
+        ret
+    }
+    /// called once when the tool is unloaded (i.e.: on `exit`).
+    fn fini(&self) {}
+    /// called in a new thread, before it runs.
+    fn on_thread_start(&self) {}
+    /// called in a forked child process, before it runs.
+    fn on_fork_child(&self, _parent: i32) {}
+    /// called after `execve`, once the tool is loaded. NB: this could
+    /// be called before `init`.
+    fn on_exec(&self) {}
+    /// called before the process exits (`exit_group`), the log is
+    /// flushed afterwards.
+    fn on_exit(&self, _status: i32) {}
+}
+
+#[doc(hidden)]
+pub fn __tool_init<T: Tool>(tool: &T) {
+    let _ = crate::logger::init();
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| tool.init()));
+}
+
+#[doc(hidden)]
+pub fn __tool_fini<T: Tool>(tool: &T) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| tool.fini()));
+}
+
+#[doc(hidden)]
+pub fn __tool_on_thread_start<T: Tool>(tool: &T) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| tool.on_thread_start()));
+}
+
+#[doc(hidden)]
+pub fn __tool_on_fork_child<T: Tool>(tool: &T, parent: i32) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| tool.on_fork_child(parent)));
+}
+
+#[doc(hidden)]
+pub fn __tool_on_exec<T: Tool>(tool: &T) {
+    let _ = crate::logger::init();
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| tool.on_exec()));
+}
+
+#[doc(hidden)]
+pub fn __tool_on_exit<T: Tool>(tool: &T, status: i32) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| tool.on_exit(status)));
+    log::logger().flush();
+}
+
+#[doc(hidden)]
+pub fn __tool_captured_syscall<T: Tool>(tool: &T, no: i32, args: [i64; 6]) -> i64 {
+    let ctx = SyscallCtx::new(no, args);
+    let action =
+        panic::catch_unwind(AssertUnwindSafe(|| tool.on_syscall(&ctx))).unwrap_or(Action::Continue);
+    match action {
+        Action::Continue => ctx.forwarded_syscall(),
+        Action::Rewrite(new_ctx) => new_ctx.forwarded_syscall(),
+        Action::Return(ret) => ret,
+    }
+}
+
+// chained entry point: returns 0 to pass the syscall to the next tool,
+// non-zero to short-circuit it with `*retval` as the result.
+#[doc(hidden)]
+pub fn __tool_captured_syscall_enter<T: Tool>(tool: &T, info: *mut SyscallInfo, retval: *mut i64) -> i32 {
+    let info = unsafe { &mut *info };
+    let mut args = [0i64; 6];
+    for (arg, raw) in args.iter_mut().zip(info.args.iter()) {
+        *arg = *raw as i64;
+    }
+    let ctx = SyscallCtx::new(info.no as i32, args);
+    let action =
+        panic::catch_unwind(AssertUnwindSafe(|| tool.on_syscall(&ctx))).unwrap_or(Action::Continue);
+    match action {
+        Action::Continue => 0,
+        Action::Rewrite(new_ctx) => {
+            info.no = new_ctx.no as u64;
+            for (raw, arg) in info.args.iter_mut().zip(new_ctx.args.iter()) {
+                *raw = *arg as u64;
+            }
+            0
+        }
+        Action::Return(ret) => {
+            unsafe { *retval = ret };
+            1
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn __tool_captured_syscall_exit<T: Tool>(tool: &T, no: i32, args: *const i64, ret: i64) -> i64 {
+    let mut raw = [0i64; 6];
+    if !args.is_null() {
+        unsafe { core::ptr::copy_nonoverlapping(args, raw.as_mut_ptr(), raw.len()) };
+    }
+    let ctx = SyscallCtx::new(no, raw);
+    panic::catch_unwind(AssertUnwindSafe(|| tool.on_syscall_exit(&ctx, ret))).unwrap_or(ret)
+}
+
+/// generate C ABI entry points for a `Tool`.
+///
+/// `systrace_tool!(MyTool)` for a unit struct, or
+/// `systrace_tool!(MyTool, MyTool::new())` with a const initializer.
+#[macro_export]
+macro_rules! systrace_tool {
+    ($tool:ident) => {
+        $crate::systrace_tool!($tool, $tool);
+    };
+    ($ty:ty, $init:expr) => {
+        static __SYSTRACE_TOOL: $ty = $init;
+
+        #[link_section = ".init_array"]
+        #[used]
+        static __SYSTRACE_TOOL_CTOR: extern "C" fn() = {
+            extern "C" fn __systrace_tool_ctor() {
+                $crate::tool::__tool_init(&__SYSTRACE_TOOL);
+            }
+            __systrace_tool_ctor
+        };
+
+        #[link_section = ".fini_array"]
+        #[used]
+        static __SYSTRACE_TOOL_DTOR: extern "C" fn() = {
+            extern "C" fn __systrace_tool_dtor() {
+                $crate::tool::__tool_fini(&__SYSTRACE_TOOL);
+            }
+            __systrace_tool_dtor
+        };
+
+        #[no_mangle]
+        pub extern "C" fn systrace_on_thread_start() {
+            $crate::tool::__tool_on_thread_start(&__SYSTRACE_TOOL);
+        }
+
+        #[no_mangle]
+        pub extern "C" fn systrace_on_fork_child(parent: i32) {
+            $crate::tool::__tool_on_fork_child(&__SYSTRACE_TOOL, parent);
+        }
+
+        #[no_mangle]
+        pub extern "C" fn systrace_on_exec() {
+            $crate::tool::__tool_on_exec(&__SYSTRACE_TOOL);
+        }
+
+        #[no_mangle]
+        pub extern "C" fn systrace_on_exit(status: i32) {
+            $crate::tool::__tool_on_exit(&__SYSTRACE_TOOL, status);
+        }
+
+        #[no_mangle]
+        pub extern "C" fn captured_syscall(
+            no: i32,
+            a0: i64,
+            a1: i64,
+            a2: i64,
+            a3: i64,
+            a4: i64,
+            a5: i64,
+        ) -> i64 {
+            $crate::tool::__tool_captured_syscall(&__SYSTRACE_TOOL, no, [a0, a1, a2, a3, a4, a5])
+        }
+
+        #[no_mangle]
+        pub extern "C" fn captured_syscall_enter(
+            info: *mut $crate::tool::SyscallInfo,
+            retval: *mut i64,
+        ) -> i32 {
+            $crate::tool::__tool_captured_syscall_enter(&__SYSTRACE_TOOL, info, retval)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn captured_syscall_exit(no: i32, args: *const i64, ret: i64) -> i64 {
+            $crate::tool::__tool_captured_syscall_exit(&__SYSTRACE_TOOL, no, args, ret)
+        }
+    };
+}
+
+#[cfg(test)]
+struct TestTool;
+
+#[cfg(test)]
+impl Tool for TestTool {
+    fn on_syscall(&self, ctx: &SyscallCtx) -> Action {
+        match ctx.syscall_no() {
+            SyscallNo::SYS_getpid => Action::Return(42),
+            SyscallNo::SYS_open => {
+                Action::Rewrite(SyscallCtx::new(SyscallNo::SYS_openat as i32, [-100, ctx.args[0], ctx.args[1], 0, 0, 0]))
+            }
+            SyscallNo::SYS_ptrace => panic!("panicking tool"),
+            _ => Action::Continue,
+        }
+    }
+    fn on_syscall_exit(&self, _ctx: &SyscallCtx, ret: i64) -> i64 {
+        ret + 1
+    }
+}
+
+#[test]
+fn tool_captured_syscall_enter() {
+    let enter = |no: SyscallNo, args: [u64; 6]| {
+        let mut info = SyscallInfo { no: no as u64, args };
+        let mut retval = -1i64;
+        let res = __tool_captured_syscall_enter(&TestTool, &mut info, &mut retval);
+        (res, info.no, info.args, retval)
+    };
+    let args = [1, 2, 3, 4, 5, 6];
+    // short-circuits the chain with the returned value.
+    assert_eq!(enter(SyscallNo::SYS_getpid, args), (1, SyscallNo::SYS_getpid as u64, args, 42));
+    // passed to the next tool, as rewritten.
+    assert_eq!(
+        enter(SyscallNo::SYS_open, args),
+        (0, SyscallNo::SYS_openat as u64, [-100i64 as u64, 1, 2, 0, 0, 0], -1)
+    );
+    assert_eq!(enter(SyscallNo::SYS_read, args), (0, SyscallNo::SYS_read as u64, args, -1));
+    // a panicking callback falls back to `Action::Continue`.
+    assert_eq!(enter(SyscallNo::SYS_ptrace, args), (0, SyscallNo::SYS_ptrace as u64, args, -1));
+    let raw = [1i64, 2, 3, 4, 5, 6];
+    assert_eq!(__tool_captured_syscall_exit(&TestTool, SyscallNo::SYS_read as i32, raw.as_ptr(), 7), 8);
+    assert_eq!(__tool_captured_syscall_exit(&TestTool, SyscallNo::SYS_read as i32, core::ptr::null(), 7), 8);
+}
@@ -1,6 +1,7 @@
 #include <sys/types.h>
 #include <sys/user.h>
 #include <sys/syscall.h>
+#include <sys/mman.h>
 #include <stdio.h>
 #include <stdlib.h>
 #include <errno.h>
@@ -29,6 +30,17 @@ long untraced_syscall(int syscallno, long a0, long a1, long a2,
                       SYSCALL_UNTRACED, 0, 0);
 }
 
+/**
+ * run a syscall of the program (i.e.: a captured syscall a tool lets
+ * through), untraced, but still subject to the seccomp policy rules
+ * (`--seccomp-rule`, `--policy`), unlike syscalls tools run on their own.
+ */
+long forwarded_syscall(int syscallno, long a0, long a1, long a2,
+		       long a3, long a4, long a5) {
+  return _raw_syscall(syscallno, a0, a1, a2, a3, a4, a5,
+                      SYSCALL_FORWARDED, 0, 0);
+}
+
 /**
  * dispatch a captured syscall.
  * the default behavior is to allow syscall going through
@@ -36,12 +48,12 @@ long untraced_syscall(int syscallno, long a0, long a1, long a2,
  * *NOTE*: `captured_syscall` is defined as a weak symbol, as a result
  * others could provide alternative implementations actually doing 
  * something rather than allow the syscall silently going through.
- * OTOH, `untraced_syscall` is guaranteed to going through (no filtered
- * by seccomp).
+ * OTOH, `forwarded_syscall` is guaranteed to going through (no filtered
+ * by seccomp, but by the policy rules).
  */
 __attribute__((weak)) long _captured_syscall(int syscallno, long arg0, long arg1, long arg2,
 		       long arg3, long arg4, long arg5){
-  return untraced_syscall(syscallno, arg0, arg1, arg2, arg3, arg4, arg5);
+  return forwarded_syscall(syscallno, arg0, arg1, arg2, arg3, arg4, arg5);
 }
 
 typedef long (*syscall_pfn)(int, long, long, long, long, long, long);
@@ -50,106 +62,115 @@ typedef long (*syscall_pfn)(int, long, long, long, long, long, long);
 long captured_syscall(int syscallno, long arg0, long arg1, long arg2,
 		      long arg3, long arg4, long arg5);
 
+/**
+ * optional syscall exit callback.
+ * called after `captured_syscall` returned (hence after the real syscall
+ * when `captured_syscall` is not overridden), with the syscall arguments
+ * and the result; the value returned is what the tracee will see.
+ * *NOTE*: this is a weak undefined symbol, it is NULL unless a tool
+ * provides an implementation.
+ */
+extern __attribute__((weak))
+long captured_syscall_exit(int syscallno, const long* args, long retval);
+
+/**
+ * dispatch a captured syscall through the tool chain (`--tool`, in order).
+ * a tool's `captured_syscall_enter` returns 0 to pass the (possibly
+ * modified) syscall to the next tool, or non-zero to short-circuit with
+ * `*retval`; a tool only providing `captured_syscall` always
+ * short-circuits. if no tool short-circuits, the syscall is forwarded.
+ * `captured_syscall_exit` is then called in reverse order, by every tool
+ * which has seen the syscall.
+ */
+static long syscall_hook_chained(const struct syscall_info* syscall,
+				 const struct tool_chain_entry* chain,
+				 unsigned long n)
+{
+  struct syscall_info info = *syscall;
+  unsigned long seen = 0;
+  int done = 0;
+  long ret = 0;
+
+  while (seen < n && !done) {
+    const struct tool_chain_entry* tool = &chain[seen++];
+    if (tool->enter) {
+      done = tool->enter(&info, &ret);
+    } else if (tool->syscall) {
+      ret = tool->syscall(info.no, info.args[0], info.args[1], info.args[2],
+			  info.args[3], info.args[4], info.args[5]);
+      done = 1;
+    }
+  }
+  if (!done) {
+    ret = forwarded_syscall(info.no, info.args[0], info.args[1], info.args[2],
+			    info.args[3], info.args[4], info.args[5]);
+  }
+  while (seen-- > 0) {
+    if (chain[seen].exit) {
+      ret = chain[seen].exit(info.no, (const long*)info.args, ret);
+    }
+  }
+  return ret;
+}
+
+/**
+ * the tracer is notified of syscalls it must see once done, which the
+ * trampoline runs untraced: changes of the address space (so that patch
+ * records are invalidated), and text mapped (patched when mapped, see
+ * `patches_mapped_text` in `src/options.rs`). syscalls run by tools
+ * on their own are not notified.
+ */
+static int notifies_tracer(const struct syscall_info* syscall)
+{
+  switch (syscall->no) {
+  case SYS_munmap:
+  case SYS_mremap:
+  case SYS_mprotect:
+    return 1;
+  case SYS_mmap:
+    return (syscall->args[2] & PROT_EXEC) || (syscall->args[3] & MAP_FIXED);
+  default:
+    return 0;
+  }
+}
+
+static void notify_tracer(const struct syscall_info* syscall, long ret)
+{
+  traced_syscall(SYSCALL_NOTIFY, syscall->no, syscall->args[0], syscall->args[1],
+                 syscall->args[2], syscall->args[3], ret);
+}
+
+static long syscall_hook_unnotified(const struct syscall_info* syscall)
+{
+    unsigned long n = *(const unsigned long*)TLS_TOOL_CHAIN_SIZE;
+    if (n > 0) {
+      return syscall_hook_chained(syscall, (const struct tool_chain_entry*)PRELOAD_TOOL_CHAIN_ADDR, n);
+    }
+    /* no tool chain installed by the tracer, use the global symbols */
+    long ret = captured_syscall(syscall->no, syscall->args[0], syscall->args[1], syscall->args[2], syscall->args[3], syscall->args[4], syscall->args[5]);
+    if (captured_syscall_exit) {
+      ret = captured_syscall_exit(syscall->no, (const long*)syscall->args, ret);
+    }
+    return ret;
+}
+
 __attribute__((visibility("hidden"))) long syscall_hook(const struct syscall_info* syscall)
 {
-    return captured_syscall(syscall->no, syscall->args[0], syscall->args[1], syscall->args[2], syscall->args[3], syscall->args[4], syscall->args[5]);
+    /* never returns, tools' `systrace_on_exit` are called first */
+    if (syscall->no == SYS_exit_group) {
+      notify_tracer(syscall, 0);
+    }
+    long ret = syscall_hook_unnotified(syscall);
+    if ((unsigned long)ret < -4096UL && notifies_tracer(syscall)) {
+      notify_tracer(syscall, ret);
+    }
+    return ret;
 }
 
 extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline(void);
-extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline_48_3d_01_f0_ff_ff(void);
-extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline_48_3d_00_f0_ff_ff(void);
-extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline_48_8b_3c_24(void);
-extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline_5a_5e_c3(void);
-extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline_89_c2_f7_da(void);
-extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline_90_90_90(void);
-extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline_ba_01_00_00_00(void);
-extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline_89_c1_31_d2(void);
-extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline_c3_nop(void);
-extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline_85_c0_0f_94_c2(void);
-
-static struct syscall_patch_hook syscall_patch_hooks[] = {
-    /* Many glibc syscall wrappers (e.g. read) have 'syscall' followed
-     * by
-     * cmp $-4095,%rax (in glibc-2.18-16.fc20.x86_64) */
-    { 0,
-      6,
-      { 0x48, 0x3d, 0x01, 0xf0, 0xff, 0xff },
-      (uintptr_t)_syscall_hook_trampoline_48_3d_01_f0_ff_ff },
-    /* Many glibc syscall wrappers (e.g. __libc_recv) have 'syscall'
-     * followed by
-     * cmp $-4096,%rax (in glibc-2.18-16.fc20.x86_64) */
-    { 0,
-      6,
-      { 0x48, 0x3d, 0x00, 0xf0, 0xff, 0xff },
-      (uintptr_t)_syscall_hook_trampoline_48_3d_00_f0_ff_ff },
-    /* Many glibc syscall wrappers (e.g. read) have 'syscall' followed
-     * by
-     * mov (%rsp),%rdi (in glibc-2.18-16.fc20.x86_64) */
-    { 0,
-      4,
-      { 0x48, 0x8b, 0x3c, 0x24 },
-      (uintptr_t)_syscall_hook_trampoline_48_8b_3c_24 },
-    /* __lll_unlock_wake has 'syscall' followed by
-     * pop %rdx; pop %rsi; ret */
-    { 1,
-      3,
-      { 0x5a, 0x5e, 0xc3 },
-      (uintptr_t)_syscall_hook_trampoline_5a_5e_c3 },
-    /* posix_fadvise64 has 'syscall' followed by
-     * mov %eax,%edx; neg %edx (in glibc-2.22-11.fc23.x86_64) */
-    { 1,
-      4,
-      { 0x89, 0xc2, 0xf7, 0xda },
-      (uintptr_t)_syscall_hook_trampoline_89_c2_f7_da },
-    /* Our VDSO vsyscall patches have 'syscall' followed by "nop; nop;
-       nop" */
-    { 1,
-      3,
-      { 0x90, 0x90, 0x90 },
-      (uintptr_t)_syscall_hook_trampoline_90_90_90 },
-    /* glibc-2.22-17.fc23.x86_64 has 'syscall' followed by 'mov $1,%rdx'
-     * in
-     * pthread_barrier_wait.
-     */
-    { 0,
-      5,
-      { 0xba, 0x01, 0x00, 0x00, 0x00 },
-      (uintptr_t)_syscall_hook_trampoline_ba_01_00_00_00 },
-    /* pthread_sigmask has 'syscall' followed by 'mov %eax,%ecx; xor
-       %edx,%edx' */
-    { 1,
-      4,
-      { 0x89, 0xc1, 0x31, 0xd2 },
-      (uintptr_t)_syscall_hook_trampoline_89_c1_31_d2 },
-    /* getpid has 'syscall' followed by 'retq; nopl 0x0(%rax,%rax,1) */
-    { 1,
-      9,
-      { 0xc3, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00 },
-      (uintptr_t)_syscall_hook_trampoline_c3_nop },
-    /* liblsan internal_close has 'syscall' followed by 'retq; nopl 0x0(%rax,%rax,1) */
-    { 1,
-      6,
-      { 0xc3, 0x0f, 0x1f, 0x44, 0x00, 0x00 },
-      (uintptr_t)_syscall_hook_trampoline_c3_nop },
-    /* liblsan internal_open has 'syscall' followed by 'retq; nopl (%rax) */
-    { 1,
-      4,
-      { 0xc3, 0x0f, 0x1f, 0x00 },
-      (uintptr_t)_syscall_hook_trampoline_c3_nop },
-    /* liblsan internal_dup2 has 'syscall' followed by 'retq; xchg %ax,%ax */
-    { 1,
-      3,
-      { 0xc3, 0x66, 0x90 },
-      (uintptr_t)_syscall_hook_trampoline_c3_nop },
-    { 1,
-      5,
-      { 0x85, 0xc0, 0x0f, 0x94, 0xc2 },
-      (uintptr_t)_syscall_hook_trampoline_85_c0_0f_94_c2 },
-    { 1,
-      7,
-      { 0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00 },
-      (uintptr_t)_syscall_hook_trampoline_90_90_90 },
-  };
+extern __attribute__((visibility("hidden"))) void _syscall_hook_trampoline_ptraced(void);
+/* `syscall_patch_hooks[]`, generated by build.rs from syscall_hooks.def */
+#include "syscall_hooks.h"
 
 __attribute__((constructor, visibility("hidden"))) void __preload_init(void)
 {
@@ -157,4 +178,8 @@ __attribute__((constructor, visibility("hidden"))) void __preload_init(void)
   tls[0] = sizeof(syscall_patch_hooks) / sizeof(syscall_patch_hooks[0]);
   tls[1] = (unsigned long)syscall_patch_hooks;
   tls[4] = (unsigned long)_syscall_hook_trampoline;
+  tls[9] = (unsigned long)_syscall_hook_trampoline_ptraced;
+  /* libtrampoline is loaded, the tracer may not have seen any syscall
+   * since (i.e.: with `default allow`) */
+  traced_syscall(SYSCALL_NOTIFY, -1, 0, 0, 0, 0, 0);
 }
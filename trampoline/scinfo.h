@@ -16,15 +16,31 @@
 #define TLS_SYSCALL_PATCH_SIZE (PRELOAD_THREAD_LOCALS_ADDR + 0 * sizeof(long))
 #define TLS_SYSCALL_PATCH_ADDR (PRELOAD_THREAD_LOCALS_ADDR + 1 * sizeof(long))
 #define TLS_SYSCALL_HOOK_ADDR  (PRELOAD_THREAD_LOCALS_ADDR + 2 * sizeof(long))
+#define TLS_TOOL_CHAIN_SIZE    (PRELOAD_THREAD_LOCALS_ADDR + 10 * sizeof(long))
+
+#define PRELOAD_TOOL_CHAIN_ADDR 0x70002000UL
 
 #define SYSCALL_UNTRACED (void*)(PRELOAD_PAGE_ADDR+0)
 #define SYSCALL_TRACED   (void*)(PRELOAD_PAGE_ADDR+4)
+#define SYSCALL_FORWARDED (void*)(PRELOAD_PAGE_ADDR+0x38)
+
+/* not a syscall, notifies the tracer of a syscall done by the trampoline,
+ * see `SYSTRACE_SYSCALL_NOTIFY` in `src/consts.rs` */
+#define SYSCALL_NOTIFY 0x1000
 
 struct syscall_info {
   unsigned long no;
   unsigned long args[6];
 };
 
+/* one entry per tool, written by the tracer, see `src/tool.rs` */
+struct tool_chain_entry {
+  int (*enter)(struct syscall_info* syscall, long* retval);
+  long (*syscall)(int syscallno, long arg0, long arg1, long arg2,
+                  long arg3, long arg4, long arg5);
+  long (*exit)(int syscallno, const long* args, long retval);
+};
+
 struct syscall_patch_hook {
   uint8_t is_multi_instruction;
   uint8_t next_instruction_length;